@@ -0,0 +1,78 @@
+//! Benchmarks the decode cache and the block backend against decoding every instruction as it is
+//! executed. Every bundled ROM is run for a fixed number of frames at a high clock rate, once with
+//! each decoder, and the machine states are compared to make sure they all executed the same
+//! program.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use chip8_emu::Cpu;
+use chip8_emu::blocks::Backend;
+
+/// The clock rate of the benchmark runs, high enough that the frame overhead is negligible.
+const CLOCK_RATE_HZ: u32 = 600_000;
+const FRAMES: u32 = 600;
+/// Every run is repeated, and the fastest is reported, to reduce noise.
+const REPEATS: u32 = 3;
+
+/// Runs `rom` for `FRAMES` frames with the given decoder, and returns the fastest run time and
+/// the final state hash.
+fn run(rom: &[u8], decode_cache: bool, backend: Backend) -> (Duration, u64) {
+    let mut best = Duration::MAX;
+    let mut state_hash = 0;
+    for _ in 0..REPEATS {
+        let mut cpu = Cpu::with_rom(rom).expect("Bundled ROMs can be loaded");
+        cpu.set_decode_cache(decode_cache);
+        cpu.set_backend(backend);
+        cpu.set_clock_rate(CLOCK_RATE_HZ);
+        cpu.set_rng_seed(0);
+
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            cpu.run_frame();
+        }
+        best = std::cmp::min(best, start.elapsed());
+        state_hash = cpu.state_hash();
+    }
+
+    (best, state_hash)
+}
+
+fn main() {
+    let roms_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("static/roms");
+    let mut rom_paths: Vec<_> = fs::read_dir(&roms_dir).expect("Failed to read the ROMs directory")
+        .map(|entry| entry.expect("Failed to read the ROMs directory").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "rom"))
+        .collect();
+    rom_paths.sort();
+
+    println!("{:<12} {:>12} {:>12} {:>8} {:>12} {:>8}", "ROM", "decode", "cached", "speedup",
+        "blocks", "speedup");
+    let mut total_uncached = Duration::ZERO;
+    let mut total_cached = Duration::ZERO;
+    let mut total_blocks = Duration::ZERO;
+    for rom_path in rom_paths {
+        let rom = fs::read(&rom_path).expect("Failed to read ROM");
+        let (uncached, uncached_hash) = run(&rom, false, Backend::Interpreter);
+        let (cached, cached_hash) = run(&rom, true, Backend::Interpreter);
+        let (blocks, blocks_hash) = run(&rom, true, Backend::Blocks);
+        assert_eq!(uncached_hash, cached_hash, "The decoders diverged on {}", rom_path.display());
+        assert_eq!(uncached_hash, blocks_hash, "The decoders diverged on {}", rom_path.display());
+
+        print_times(&rom_path.file_stem().unwrap().to_string_lossy(), uncached, cached, blocks);
+        total_uncached += uncached;
+        total_cached += cached;
+        total_blocks += blocks;
+    }
+
+    print_times("total", total_uncached, total_cached, total_blocks);
+}
+
+/// Prints the run times of each decoder, and their speedup over decoding every instruction.
+fn print_times(name: &str, uncached: Duration, cached: Duration, blocks: Duration) {
+    println!("{:<12} {:>10.2}ms {:>10.2}ms {:>7.2}x {:>10.2}ms {:>7.2}x", name,
+        uncached.as_secs_f64() * 1000.0, cached.as_secs_f64() * 1000.0,
+        uncached.as_secs_f64() / cached.as_secs_f64(), blocks.as_secs_f64() * 1000.0,
+        uncached.as_secs_f64() / blocks.as_secs_f64());
+}
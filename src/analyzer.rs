@@ -0,0 +1,553 @@
+//! Heuristic detection of the platform and quirk profile of ROMs that aren't in the ROM database.
+//!
+//! The ROM is scanned statically: the code reachable from the entry point is traced, and the
+//! instructions whose meaning depends on a quirk are checked for how the ROM relies on them. The
+//! ROM can also be run for a short trial, which finds code the static scan can't reach (e.g.
+//! behind `JP V0, addr`) and sprites drawn across the screen edge. Hires CHIP-8 programs are
+//! detected by their first instruction.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::instruction::Instruction;
+use crate::load::{validate_rom, LoadOptions};
+use crate::metadata::Platform;
+use crate::quirks::Quirks;
+use crate::timing;
+use crate::{Cpu, MEM_RESERVED, MEM_SIZE, SCREEN_WIDTH};
+
+/// The number of frames of the trial run that is recommended for `analyze_rom`: 5 seconds, which
+/// is usually enough to get past the title screen.
+pub const DEFAULT_TRIAL_FRAMES: u32 = 300;
+
+/// The number of instructions after a load/store instruction that are checked for a use of I.
+const MEM_ACC_LOOKAHEAD: usize = 16;
+
+/// The maximal number of addresses listed in a finding.
+const MAX_LISTED_ADDRESSES: usize = 3;
+
+/// The result of analyzing a ROM: the platform it was most likely written for, and the quirk
+/// profile that is recommended for running it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RomAnalysis {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub load_options: LoadOptions,
+    /// How likely the recommended quirk profile is to run the ROM correctly, in the range [0, 1].
+    pub confidence: f32,
+    /// The evidence the recommendation is based on, in human-readable form.
+    pub findings: Vec<String>,
+}
+
+/// Analyze `rom`, running it for `trial_frames` frames in addition to scanning it. No trial run is
+/// made if `trial_frames` is zero.
+pub fn analyze_rom(rom: &[u8], trial_frames: u32) -> RomAnalysis {
+    let load_options = LoadOptions::detect(rom);
+    let mut evidence = Evidence::default();
+    scan_rom(rom, &load_options, &mut evidence);
+
+    let platform = evidence.platform();
+    let mut quirks = evidence.recommended_quirks(platform);
+
+    let mut trial_findings = Vec::new();
+    if load_options.hires {
+        trial_findings.push(format!("The ROM starts with `{:04X}`, like hires CHIP-8 programs",
+            crate::load::HIRES_START_INSTRUCTION));
+    }
+    let mut trial_fault = false;
+    if let Err(err) = validate_rom(rom, &load_options) {
+        trial_findings.push(err.to_string());
+        trial_fault = true;
+    } else if trial_frames > 0 {
+        let trial = trial_run(rom, quirks, &load_options, trial_frames, &mut evidence);
+        if let Some((frame, address, reason)) = trial.fault {
+            trial_findings.push(format!("The trial run stopped at frame {} at {}: {}", frame,
+                format_address(address), reason));
+            trial_fault = true;
+        }
+        if !trial.clipped_sprites.is_empty() {
+            trial_findings.push(format!("Sprites drawn across the screen edge at {}, which are \
+                clipped here but wrap around on some interpreters",
+                format_addresses(&trial.clipped_sprites)));
+        }
+
+        // The trial run may have found more evidence for the quirks
+        quirks = evidence.recommended_quirks(platform);
+    }
+
+    let mut confidence = evidence.shift.confidence(quirks.original_shift)
+        * evidence.mem_acc.confidence(quirks.original_mem_acc);
+    if !evidence.unknown_opcodes.is_empty() {
+        confidence *= 0.5;
+    }
+    if trial_fault {
+        confidence *= 0.5;
+    }
+    // SCHIP jumps to `xnn + Vx`, which this emulator doesn't do
+    if platform != Platform::Chip8 && !evidence.indirect_jumps.is_empty() {
+        confidence *= 0.75;
+    }
+
+    let mut findings = evidence.findings();
+    findings.extend(trial_findings);
+    RomAnalysis {
+        platform,
+        quirks,
+        load_options,
+        confidence,
+        findings,
+    }
+}
+
+#[wasm_bindgen]
+/// Analyze `rom` like `analyze_rom`, and return the analysis in JSON format.
+pub fn analyze_rom_json(rom: &[u8], trial_frames: u32) -> String {
+    serde_json::to_string(&analyze_rom(rom, trial_frames)).expect("Failed to serialize analysis")
+}
+
+/// The sites that rely on either behaviour of a quirk, by address. A site is `true` if it relies
+/// on the original behaviour.
+#[derive(Default)]
+struct QuirkSites {
+    sites: BTreeMap<usize, bool>,
+}
+
+impl QuirkSites {
+    /// Record the site at `address`, unless it was already recorded.
+    fn add(&mut self, address: usize, original: bool) {
+        self.sites.entry(address).or_insert(original);
+    }
+
+    /// Returns the addresses of the sites relying on the `original` behaviour.
+    fn addresses(&self, original: bool) -> Vec<usize> {
+        self.sites.iter().filter(|&(_, &site_original)| site_original == original)
+            .map(|(&address, _)| address).collect()
+    }
+
+    /// Returns the behaviour most sites rely on, if there is any majority.
+    fn majority(&self) -> Option<bool> {
+        let num_original = self.addresses(true).len();
+        let num_modern = self.sites.len() - num_original;
+        match num_original.cmp(&num_modern) {
+            std::cmp::Ordering::Greater => Some(true),
+            std::cmp::Ordering::Less => Some(false),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    /// Returns how likely it is that `original` is the right behaviour. If no site relies on the
+    /// quirk, either behaviour is right.
+    fn confidence(&self, original: bool) -> f32 {
+        if self.sites.is_empty() {
+            return 1.0;
+        }
+
+        let num_agreeing = self.addresses(original).len();
+        // Laplace smoothing, so a single site isn't taken as certain
+        (num_agreeing + 1) as f32 / (self.sites.len() + 2) as f32
+    }
+}
+
+/// Everything the scan and the trial run found in the ROM.
+#[derive(Default)]
+struct Evidence {
+    shift: QuirkSites,
+    mem_acc: QuirkSites,
+    schip_opcodes: Vec<usize>,
+    xochip_opcodes: Vec<usize>,
+    unknown_opcodes: Vec<usize>,
+    indirect_jumps: Vec<usize>,
+}
+
+impl Evidence {
+    /// Returns the platform the ROM was most likely written for.
+    fn platform(&self) -> Platform {
+        if !self.xochip_opcodes.is_empty() {
+            Platform::XoChip
+        } else if !self.schip_opcodes.is_empty() {
+            Platform::SuperChip
+        } else {
+            Platform::Chip8
+        }
+    }
+
+    /// Returns the recommended quirk profile for a ROM written for `platform`.
+    fn recommended_quirks(&self, platform: Platform) -> Quirks {
+        match platform {
+            Platform::Chip8 => {
+                let original_shift = self.shift.majority().unwrap_or(false);
+                let original_mem_acc = self.mem_acc.majority().unwrap_or(false);
+                Quirks {
+                    original_shift,
+                    original_mem_acc,
+                    display_wait: true,
+                    ..Quirks::default()
+                }
+            }
+            // The quirks of these platforms are fixed, the sites only affect the confidence
+            Platform::SuperChip => Quirks::default(),
+            Platform::XoChip => Quirks {
+                original_mem_acc: true,
+                ..Quirks::default()
+            },
+        }
+    }
+
+    /// Describe the evidence.
+    fn findings(&self) -> Vec<String> {
+        let mut findings = Vec::new();
+        let mut describe = |addresses: Vec<usize>, description: &str| {
+            if !addresses.is_empty() {
+                findings.push(format!("{} at {}", description, format_addresses(&addresses)));
+            }
+        };
+
+        describe(self.xochip_opcodes.clone(), "XO-CHIP instructions");
+        describe(self.schip_opcodes.clone(), "SCHIP instructions");
+        describe(self.unknown_opcodes.clone(), "Unsupported instructions");
+        describe(self.shift.addresses(true), "Shifts of Vy into Vx (original shift)");
+        describe(self.shift.addresses(false), "Shifts that ignore Vy (modern shift)");
+        describe(self.mem_acc.addresses(true),
+            "Load/store instructions followed by another that relies on I being incremented \
+            (original load/store)");
+        describe(self.mem_acc.addresses(false),
+            "Load/store instructions followed by a use of I that relies on it being unchanged \
+            (modern load/store)");
+        describe(self.indirect_jumps.clone(), "Jumps with an offset of V0 (Bnnn)");
+
+        findings
+    }
+}
+
+/// The platform an instruction belongs to, as far as this emulator is concerned.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum InstructionSet {
+    Chip8,
+    SuperChip,
+    XoChip,
+    /// Not an instruction of any platform, or a machine code routine (0nnn).
+    Unknown,
+}
+
+/// Returns the instruction set `instr` belongs to, where `hires` is true for hires CHIP-8
+/// programs. SCHIP and XO-CHIP instructions that the emulator executes as something else (like
+/// `Dxy0` and `5xy2`) still count as evidence of their platform.
+fn instruction_set(instr: u16, hires: bool) -> InstructionSet {
+    let low_nibble = instr & 0x000F;
+    let low_byte = instr & 0x00FF;
+    let extension = match instr >> 12 {
+        0x0 => match instr {
+            0x00C1..=0x00CF | 0x00FB..=0x00FF => Some(InstructionSet::SuperChip),
+            0x00D0..=0x00DF => Some(InstructionSet::XoChip),
+            _ => None,
+        },
+        0x5 if low_nibble == 0x2 || low_nibble == 0x3 => Some(InstructionSet::XoChip),
+        0xD if low_nibble == 0 => Some(InstructionSet::SuperChip),
+        0xF => match low_byte {
+            0x30 | 0x75 | 0x85 => Some(InstructionSet::SuperChip),
+            0x00 if instr == 0xF000 => Some(InstructionSet::XoChip),
+            0x02 if instr == 0xF002 => Some(InstructionSet::XoChip),
+            0x01 | 0x3A => Some(InstructionSet::XoChip),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match (extension, Instruction::decode(instr, hires)) {
+        (Some(extension), _) => extension,
+        (None, Some(_)) => InstructionSet::Chip8,
+        (None, None) => InstructionSet::Unknown,
+    }
+}
+
+/// Returns the instruction at `address` in memory, if it is within the ROM loaded at
+/// `load_address`.
+pub(crate) fn rom_instruction(rom: &[u8], load_address: usize, address: usize) -> Option<u16> {
+    let offset = address.checked_sub(load_address)?;
+    rom.get(offset..offset + 2).map(|bytes| ((bytes[0] as u16) << 8) | (bytes[1] as u16))
+}
+
+/// Returns the shift site of `instruction` if it is a shift that relies on either behaviour of
+/// the shift quirk: `true` if it relies on Vy being shifted. When x and y are the same register
+/// both behaviours are the same, and `8x06` is how modern ROMs usually write a shift of Vx.
+pub(crate) fn shift_site(instruction: &Instruction) -> Option<bool> {
+    match *instruction {
+        Instruction::Shr { x, y } | Instruction::Shl { x, y } if x != y => Some(y != 0),
+        _ => None,
+    }
+}
+
+/// Trace the code reachable from the entry point, and record the evidence found in it.
+fn scan_rom(rom: &[u8], load_options: &LoadOptions, evidence: &mut Evidence) {
+    let load_address = load_options.load_address;
+    let mut visited = vec![false; MEM_SIZE];
+    let mut worklist = vec![load_options.entry_point];
+    while let Some(address) = worklist.pop() {
+        if address >= MEM_SIZE || visited[address] {
+            continue;
+        }
+        let instr = match rom_instruction(rom, load_address, address) {
+            Some(instr) => instr,
+            None => continue,
+        };
+        visited[address] = true;
+
+        match instruction_set(instr, load_options.hires) {
+            InstructionSet::Chip8 => {}
+            InstructionSet::SuperChip => evidence.schip_opcodes.push(address),
+            InstructionSet::XoChip => evidence.xochip_opcodes.push(address),
+            InstructionSet::Unknown => {
+                // This is probably data, so the trace ends here
+                evidence.unknown_opcodes.push(address);
+                continue;
+            }
+        }
+
+        let instruction = match Instruction::decode(instr, load_options.hires) {
+            Some(instruction) => instruction,
+            None => {
+                match instr {
+                    // SCHIP's `EXIT` ends the program
+                    0x00FD => {}
+                    // `LD I, long addr` is followed by its 16-bit address
+                    0xF000 => worklist.push(address + 4),
+                    _ => worklist.push(address + 2),
+                }
+                continue;
+            }
+        };
+
+        if let Some(original) = shift_site(&instruction) {
+            evidence.shift.add(address, original);
+        }
+        if matches!(instruction, Instruction::Store { .. } | Instruction::Load { .. }) {
+            if let Some(original) = scan_mem_acc_site(rom, load_address, address) {
+                evidence.mem_acc.add(address, original);
+            }
+        }
+
+        match instruction {
+            Instruction::Ret => {}
+            Instruction::Jp { addr } => worklist.push(addr),
+            Instruction::Call { addr } => {
+                worklist.push(addr);
+                worklist.push(address + 2);
+            }
+            Instruction::JpV0 { .. } => evidence.indirect_jumps.push(address),
+            _ if instruction.is_skip() => {
+                worklist.push(address + 2);
+                worklist.push(address + 4);
+            }
+            _ => worklist.push(address + 2),
+        }
+    }
+
+    evidence.schip_opcodes.sort_unstable();
+    evidence.xochip_opcodes.sort_unstable();
+    evidence.unknown_opcodes.sort_unstable();
+    evidence.indirect_jumps.sort_unstable();
+}
+
+/// Check how the code after the load/store instruction at `address` uses I. Returns `true` if the
+/// next use relies on I being incremented (another load/store), `false` if it relies on I being
+/// unchanged (drawing, BCD or adding to I), or nothing if I is set again or the use is unknown.
+pub(crate) fn scan_mem_acc_site(rom: &[u8], load_address: usize, address: usize)
+    -> Option<bool> {
+    let mut address = address + 2;
+    for _ in 0..MEM_ACC_LOOKAHEAD {
+        let instr = rom_instruction(rom, load_address, address)?;
+        // The hires screen clear doesn't use I, so the screen mode doesn't matter here. Unknown
+        // instructions may set I (like SCHIP's `LD HF, Vx`).
+        let instruction = Instruction::decode(instr, false)?;
+        match instruction {
+            Instruction::Jp { addr } => {
+                address = addr;
+                continue;
+            }
+            Instruction::Store { .. } | Instruction::Load { .. } => return Some(true),
+            Instruction::Drw { .. } | Instruction::AddI { .. } | Instruction::LdBcd { .. } => {
+                return Some(false);
+            }
+            // Setting I, or control flow we don't follow
+            Instruction::Cls | Instruction::Ret | Instruction::Call { .. } | Instruction::LdI { .. }
+            | Instruction::JpV0 { .. } | Instruction::LdFont { .. } => return None,
+            _ if instruction.is_skip() => return None,
+            _ => {}
+        }
+        address += 2;
+    }
+
+    None
+}
+
+/// The result of a trial run.
+struct TrialRun {
+    /// The frame, the address and the reason the run stopped early, if it did.
+    fault: Option<(u32, usize, &'static str)>,
+    /// The addresses of the draw instructions that drew sprites across the screen edge.
+    clipped_sprites: Vec<usize>,
+}
+
+/// Run `rom` with `quirks` and `load_options` for up to `frames` frames, and record the evidence
+/// found while running it. A key is pressed whenever the ROM waits for one. The run stops before
+/// any instruction the emulator can't execute.
+fn trial_run(rom: &[u8], quirks: Quirks, load_options: &LoadOptions, frames: u32,
+    evidence: &mut Evidence) -> TrialRun {
+    let mut cpu = Cpu::with_rom_and_quirks_at(rom, quirks, load_options)
+        .expect("The ROM was validated before the trial run");
+    cpu.set_rng_seed(0);
+
+    let instructions_per_frame = timing::DEFAULT_CLOCK_RATE_HZ / timing::FRAME_RATE_HZ;
+    let mut clipped_sprites = Vec::new();
+    // The address of the last load/store instruction, while I wasn't used or set since
+    let mut last_mem_acc = None;
+    let mut next_key = 0;
+    for frame in 0..frames {
+        cpu.tick_clock();
+
+        for _ in 0..instructions_per_frame {
+            if cpu.is_waiting_for_vblank() {
+                break;
+            }
+            if cpu.is_waiting_for_keypress() {
+                cpu.capture_key(next_key);
+                next_key = (next_key + 1) % 16;
+            }
+
+            let address = cpu.pc_register;
+            if address + 1 >= MEM_SIZE {
+                return TrialRun {
+                    fault: Some((frame, address, "The PC is out of memory bounds")),
+                    clipped_sprites,
+                };
+            }
+            let instr = cpu.fetch_instruction();
+            let instruction = match Instruction::decode(instr, cpu.load_options.hires) {
+                Some(instruction) => instruction,
+                None => {
+                    return TrialRun {
+                        fault: Some((frame, address, "Unsupported instruction")),
+                        clipped_sprites,
+                    };
+                }
+            };
+            if let Some(reason) = trial_fault(&cpu, &instruction) {
+                return TrialRun {
+                    fault: Some((frame, address, reason)),
+                    clipped_sprites,
+                };
+            }
+
+            if let Some(original) = shift_site(&instruction) {
+                evidence.shift.add(address, original);
+            }
+            if let Some(mem_acc_address) = last_mem_acc {
+                let site = match instruction {
+                    Instruction::Drw { .. } | Instruction::AddI { .. }
+                    | Instruction::LdBcd { .. } => Some(Some(false)),
+                    Instruction::Store { .. } | Instruction::Load { .. } => Some(Some(true)),
+                    Instruction::LdI { .. } | Instruction::LdFont { .. } => Some(None),
+                    _ => None,
+                };
+                if let Some(site) = site {
+                    if let Some(original) = site {
+                        evidence.mem_acc.add(mem_acc_address, original);
+                    }
+                    last_mem_acc = None;
+                }
+            }
+            if matches!(instruction, Instruction::Store { .. } | Instruction::Load { .. }) {
+                last_mem_acc = Some(address);
+            }
+            if let Instruction::Drw { x, y, nibble } = instruction {
+                if sprite_is_clipped(&cpu, x, y, nibble) && !clipped_sprites.contains(&address) {
+                    clipped_sprites.push(address);
+                }
+            }
+
+            cpu.step();
+        }
+    }
+
+    clipped_sprites.sort_unstable();
+    TrialRun {
+        fault: None,
+        clipped_sprites,
+    }
+}
+
+/// Returns the reason executing `instruction` would fail on `cpu`, if it would.
+fn trial_fault(cpu: &Cpu, instruction: &Instruction) -> Option<&'static str> {
+    let i_register = cpu.i_register;
+    let out_of_bounds = match *instruction {
+        Instruction::Ret if cpu.sp_register == 0 => return Some("Return without a call"),
+        Instruction::Jp { addr } if addr < MEM_RESERVED => {
+            return Some("Jump into the reserved memory area");
+        }
+        Instruction::Call { addr } if addr < MEM_RESERVED => {
+            return Some("Call into the reserved memory area");
+        }
+        Instruction::Call { .. } if cpu.sp_register >= cpu.call_stack.len() => {
+            return Some("Call stack overflow");
+        }
+        Instruction::JpV0 { addr } if addr + (cpu.v_registers[0] as usize) < MEM_RESERVED => {
+            return Some("Jump into the reserved memory area");
+        }
+        Instruction::Drw { nibble, .. } => {
+            if i_register + nibble as usize > MEM_SIZE {
+                return Some("Sprite out of memory bounds");
+            }
+            false
+        }
+        Instruction::AddI { x } => i_register + (cpu.v_registers[x] as usize) >= MEM_SIZE,
+        Instruction::LdBcd { .. } => i_register + 2 >= MEM_SIZE,
+        Instruction::Store { x } | Instruction::Load { x } => i_register + x + 1 >= MEM_SIZE,
+        _ => false,
+    };
+
+    if out_of_bounds {
+        Some("Memory access out of bounds")
+    } else {
+        None
+    }
+}
+
+/// Returns true if drawing the `nibble` rows high sprite at (Vx, Vy) would draw pixels past the
+/// screen edge on `cpu`.
+fn sprite_is_clipped(cpu: &Cpu, x: usize, y: usize, nibble: u8) -> bool {
+    let sprite_x = (cpu.v_registers[x] as usize) % SCREEN_WIDTH;
+    let sprite_y = (cpu.v_registers[y] as usize) % cpu.screen_height;
+    let sprite_height = nibble as usize;
+
+    // The columns of a sprite row that are past the right edge
+    let clipped_columns = if sprite_x + 8 > SCREEN_WIDTH {
+        0xFFu8 >> (SCREEN_WIDTH - sprite_x)
+    } else {
+        0
+    };
+
+    (0..sprite_height).any(|row| {
+        let sprite_row = cpu.memory[(cpu.i_register + row) % MEM_SIZE];
+        sprite_row != 0
+            && (sprite_y + row >= cpu.screen_height || sprite_row & clipped_columns != 0)
+    })
+}
+
+/// Format a memory address like `0x2A4`.
+pub(crate) fn format_address(address: usize) -> String {
+    format!("0x{:03X}", address)
+}
+
+/// Format a list of addresses, shortening it if it is long.
+pub(crate) fn format_addresses(addresses: &[usize]) -> String {
+    let listed: Vec<String> = addresses.iter().take(MAX_LISTED_ADDRESSES)
+        .map(|&address| format_address(address)).collect();
+    let mut text = listed.join(", ");
+    if addresses.len() > MAX_LISTED_ADDRESSES {
+        text += &format!(" and {} more", addresses.len() - MAX_LISTED_ADDRESSES);
+    }
+
+    text
+}
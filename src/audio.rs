@@ -0,0 +1,167 @@
+//! PCM sample generation for the CHIP-8 tone.
+
+use wasm_bindgen::prelude::*;
+
+use crate::timing::FRAME_RATE_HZ;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The shape of the generated tone.
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
+
+/// Generates the tone that is heard while the sound timer is not zero, one frame of samples at a
+/// time.
+pub struct ToneGenerator {
+    sample_rate: u32,
+    waveform: Waveform,
+    frequency_hz: f32,
+    volume: f32,
+    // The time it takes the envelope to go from silence to full volume, and back
+    attack_secs: f32,
+    release_secs: f32,
+
+    // The position in the current waveform period, in the range [0, 1)
+    phase: f32,
+    // The current envelope level, in the range [0, 1]
+    envelope: f32,
+    // The fractional number of samples that didn't fit in the previous frames. This keeps the
+    // number of generated samples exact when the sample rate is not divisible by the frame rate.
+    sample_remainder: f64,
+
+    // Whether the tone was on at the start of the current frame, and the changes to that during
+    // the frame as (position in the frame in the range [0, 1), tone on) pairs.
+    gate_at_frame_start: bool,
+    gate_changes: Vec<(f32, bool)>,
+    // Whether the tone is currently on
+    gate: bool,
+
+    // The samples generated for the last frame
+    samples: Vec<f32>,
+}
+
+impl ToneGenerator {
+    /// Construct a tone generator that renders mono samples at `sample_rate`, with a triangle wave
+    /// at 440HZ.
+    pub fn new(sample_rate: u32) -> Self {
+        assert!(sample_rate > 0, "Sample rate must not be zero");
+
+        ToneGenerator {
+            sample_rate,
+            waveform: Waveform::Triangle,
+            frequency_hz: 440.0,
+            volume: 0.25,
+            attack_secs: 0.005,
+            release_secs: 0.005,
+            phase: 0.0,
+            envelope: 0.0,
+            sample_remainder: 0.0,
+            gate_at_frame_start: false,
+            gate_changes: Vec::new(),
+            gate: false,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Returns the rate at which samples are rendered.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Sets the shape of the tone.
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    /// Sets the pitch of the tone.
+    pub fn set_frequency(&mut self, frequency_hz: f32) {
+        self.frequency_hz = frequency_hz;
+    }
+
+    /// Sets the peak amplitude of the tone, in the range [0, 1].
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    /// Sets the attack and release times of the envelope. Short envelopes prevent the clicks
+    /// caused by starting or stopping the tone in the middle of a period.
+    pub fn set_envelope(&mut self, attack_secs: f32, release_secs: f32) {
+        self.attack_secs = attack_secs.max(0.0);
+        self.release_secs = release_secs.max(0.0);
+    }
+
+    /// Start a new frame, in which the tone starts on if `tone_on` is true.
+    pub fn begin_frame(&mut self, tone_on: bool) {
+        self.gate_at_frame_start = tone_on;
+        self.gate = tone_on;
+        self.gate_changes.clear();
+    }
+
+    /// Turn the tone on or off at `position` in the current frame, in the range [0, 1). Does
+    /// nothing if the tone is already in the requested state.
+    pub fn set_gate(&mut self, position: f32, tone_on: bool) {
+        if tone_on != self.gate {
+            self.gate = tone_on;
+            self.gate_changes.push((position.clamp(0.0, 1.0), tone_on));
+        }
+    }
+
+    /// Render the samples of the current frame.
+    pub fn end_frame(&mut self) {
+        let exact_samples =
+            self.sample_rate as f64 / FRAME_RATE_HZ as f64 + self.sample_remainder;
+        let num_samples = exact_samples as usize;
+        self.sample_remainder = exact_samples - num_samples as f64;
+
+        let attack_step = envelope_step(self.attack_secs, self.sample_rate);
+        let release_step = envelope_step(self.release_secs, self.sample_rate);
+        let phase_step = self.frequency_hz / self.sample_rate as f32;
+
+        self.samples.clear();
+        let mut tone_on = self.gate_at_frame_start;
+        let mut next_change = 0;
+        for sample_idx in 0..num_samples {
+            // Apply all the gate changes that happened before this sample
+            let position = sample_idx as f32 / num_samples as f32;
+            while next_change < self.gate_changes.len()
+                && self.gate_changes[next_change].0 <= position {
+                tone_on = self.gate_changes[next_change].1;
+                next_change += 1;
+            }
+
+            if tone_on {
+                self.envelope = (self.envelope + attack_step).min(1.0);
+            } else {
+                self.envelope = (self.envelope - release_step).max(0.0);
+            }
+
+            let amplitude = match self.waveform {
+                Waveform::Square => if self.phase < 0.5 { 1.0 } else { -1.0 },
+                Waveform::Triangle => 4.0 * (self.phase - 0.5).abs() - 1.0,
+                Waveform::Sawtooth => 2.0 * self.phase - 1.0,
+                Waveform::Sine => (2.0 * std::f32::consts::PI * self.phase).sin(),
+            };
+            self.samples.push(amplitude * self.envelope * self.volume);
+
+            self.phase = (self.phase + phase_step).fract();
+        }
+    }
+
+    /// Returns the samples rendered for the last frame.
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+}
+
+/// Returns the per-sample change of the envelope level for a ramp that takes `secs`.
+fn envelope_step(secs: f32, sample_rate: u32) -> f32 {
+    if secs > 0.0 {
+        1.0 / (secs * sample_rate as f32)
+    } else {
+        1.0
+    }
+}
@@ -0,0 +1,259 @@
+//! Running many instances of the same ROM side by side, e.g. with different seeds and inputs for
+//! search or training.
+//!
+//! Only the per-instance inputs and outputs are kept in a struct-of-arrays layout: a contiguous
+//! array per field, indexed by instance, which can be read or written in bulk. The machine state
+//! itself (registers, PC, I, timers, screen and memory) is not: every machine keeps it in its own
+//! `Cpu`, and a frame is run one machine at a time, so the state of a machine stays in cache while
+//! its frame runs. With the `rayon` feature on native targets, the machines run their frames in
+//! parallel.
+//!
+//! Besides the state at the end of every frame, the edges within the frame are reported for every
+//! machine: where the tone started and stopped, and where the machine started waiting for a key.
+
+#[cfg(all(feature = "rayon", not(target_arch = "wasm32")))]
+use rayon::prelude::*;
+
+use crate::blocks::Backend;
+use crate::load::{LoadError, LoadOptions};
+use crate::quirks::Quirks;
+use crate::{Cpu, HIRES_SCREEN_HEIGHT, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// A change in the outputs of a machine during a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// The sound timer was set, and the tone started playing.
+    ToneStarted,
+    /// The sound timer ran out or was cleared, and the tone stopped playing.
+    ToneStopped,
+    /// A wait for key instruction started waiting for a key press.
+    KeyWaitEntered,
+}
+
+/// An edge, and its position in the frame it happened in, in the range [0, 1].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub position: f32,
+}
+
+/// Records the edges of the outputs of a machine during a frame, by comparing them with their
+/// state at the previous update.
+pub struct EdgeRecorder {
+    tone_on: bool,
+    waiting_for_key: bool,
+    // The edges of the current frame, in the order they happened
+    edges: Vec<Edge>,
+}
+
+impl EdgeRecorder {
+    /// Construct a recorder for a machine which isn't playing the tone or waiting for a key.
+    pub fn new() -> Self {
+        EdgeRecorder {
+            tone_on: false,
+            waiting_for_key: false,
+            edges: Vec::new(),
+        }
+    }
+
+    /// Start a new frame, dropping the edges of the previous one.
+    pub fn begin_frame(&mut self) {
+        self.edges.clear();
+    }
+
+    /// Record the edges between the previous outputs and the outputs at `position` in the current
+    /// frame.
+    pub fn update(&mut self, position: f32, tone_on: bool, waiting_for_key: bool) {
+        if tone_on != self.tone_on {
+            let kind = if tone_on { EdgeKind::ToneStarted } else { EdgeKind::ToneStopped };
+            self.edges.push(Edge { kind, position });
+        }
+        if waiting_for_key && !self.waiting_for_key {
+            self.edges.push(Edge { kind: EdgeKind::KeyWaitEntered, position });
+        }
+
+        self.tone_on = tone_on;
+        self.waiting_for_key = waiting_for_key;
+    }
+
+    /// Returns the edges recorded in the current frame.
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+}
+
+impl Default for EdgeRecorder {
+    fn default() -> Self {
+        EdgeRecorder::new()
+    }
+}
+
+/// A batch of machines running the same ROM, which run their frames together.
+pub struct CpuBatch {
+    cpus: Vec<Cpu>,
+    // The number of pixels in the screen of every machine
+    screen_len: usize,
+
+    // The screens of all machines at the end of the last frame, one after the other
+    screens: Vec<bool>,
+    // Whether the screen of each machine changed during the last frame
+    screen_changed: Vec<bool>,
+    // Whether each machine was playing the tone at the end of the last frame
+    tone_on: Vec<bool>,
+    // Whether each machine was waiting for a key press at the end of the last frame
+    waiting_for_key: Vec<bool>,
+}
+
+impl CpuBatch {
+    /// Create a batch with a machine for each of `rng_seeds`, which runs `rom` with the given quirk
+    /// profile and memory layout, and has its random number generator seeded with the seed.
+    /// Fails if the rom can't be loaded, like `Cpu::with_rom_at`.
+    pub fn new(rom: &[u8], quirks: Quirks, load_options: &LoadOptions, rng_seeds: &[u32])
+        -> Result<CpuBatch, LoadError> {
+        let cpus = rng_seeds.iter().map(|&rng_seed| {
+            let mut cpu = Cpu::with_rom_and_quirks_at(rom, quirks, load_options)?;
+            cpu.set_rng_seed(rng_seed);
+            cpu.edge_recorder = Some(EdgeRecorder::new());
+            Ok(cpu)
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        let screen_height = if load_options.hires { HIRES_SCREEN_HEIGHT } else { SCREEN_HEIGHT };
+        let screen_len = SCREEN_WIDTH * screen_height;
+        let num_instances = cpus.len();
+        Ok(CpuBatch {
+            cpus,
+            screen_len,
+            screens: vec![false; num_instances * screen_len],
+            screen_changed: vec![false; num_instances],
+            tone_on: vec![false; num_instances],
+            waiting_for_key: vec![false; num_instances],
+        })
+    }
+
+    /// Returns the number of machines in the batch.
+    pub fn len(&self) -> usize {
+        self.cpus.len()
+    }
+
+    /// Returns true if the batch has no machines.
+    pub fn is_empty(&self) -> bool {
+        self.cpus.is_empty()
+    }
+
+    /// Sets the number of instructions every machine executes per second.
+    pub fn set_clock_rate(&mut self, clock_rate_hz: u32) {
+        for cpu in &mut self.cpus {
+            cpu.set_clock_rate(clock_rate_hz);
+        }
+    }
+
+    /// Sets how every machine executes instructions.
+    pub fn set_backend(&mut self, backend: Backend) {
+        for cpu in &mut self.cpus {
+            cpu.set_backend(backend);
+        }
+    }
+
+    /// Returns the machine at `index`.
+    pub fn cpu(&self, index: usize) -> &Cpu {
+        &self.cpus[index]
+    }
+
+    /// Returns the machine at `index`, e.g. to configure it differently from the rest of the batch.
+    pub fn cpu_mut(&mut self, index: usize) -> &mut Cpu {
+        &mut self.cpus[index]
+    }
+
+    /// Run a single 60HZ frame on every machine, like `Cpu::run_frame`. `key_states` holds the
+    /// keys each machine holds down during the frame, where bit `k` is set if hex key `k` is
+    /// pressed. The keys that changed since the previous frame are pressed or released at the
+    /// start of the frame.
+    pub fn run_frame(&mut self, key_states: &[u16]) {
+        assert!(key_states.len() == self.cpus.len(), "Key states must be given for every machine");
+
+        let screen_len = self.screen_len;
+
+        #[cfg(all(feature = "rayon", not(target_arch = "wasm32")))]
+        {
+            let outputs = self.screens.par_chunks_mut(screen_len)
+                .zip(&mut self.screen_changed)
+                .zip(&mut self.tone_on)
+                .zip(&mut self.waiting_for_key);
+            self.cpus.par_iter_mut().zip(key_states).zip(outputs)
+                .for_each(|((cpu, &key_state), (((screen, screen_changed), tone_on), waiting))| {
+                    run_instance_frame(cpu, key_state, screen, screen_changed, tone_on, waiting);
+                });
+        }
+        #[cfg(not(all(feature = "rayon", not(target_arch = "wasm32"))))]
+        {
+            let outputs = self.screens.chunks_mut(screen_len)
+                .zip(&mut self.screen_changed)
+                .zip(&mut self.tone_on)
+                .zip(&mut self.waiting_for_key);
+            self.cpus.iter_mut().zip(key_states).zip(outputs)
+                .for_each(|((cpu, &key_state), (((screen, screen_changed), tone_on), waiting))| {
+                    run_instance_frame(cpu, key_state, screen, screen_changed, tone_on, waiting);
+                });
+        }
+    }
+
+    /// Returns the number of pixels in the screen of every machine. The screens are `SCREEN_WIDTH`
+    /// (64) pixels wide.
+    pub fn screen_len(&self) -> usize {
+        self.screen_len
+    }
+
+    /// Returns the screens of all machines at the end of the last frame, one after the other.
+    /// Each screen is `screen_len` pixels, row by row, and a pixel is `true` if it is turned on.
+    pub fn screens(&self) -> &[bool] {
+        &self.screens
+    }
+
+    /// Returns the screen of the machine at `index` at the end of the last frame.
+    pub fn screen(&self, index: usize) -> &[bool] {
+        &self.screens[index * self.screen_len..(index + 1) * self.screen_len]
+    }
+
+    /// Returns whether the screen of each machine changed during the last frame.
+    pub fn screen_changed(&self) -> &[bool] {
+        &self.screen_changed
+    }
+
+    /// Returns whether each machine was playing the tone at the end of the last frame.
+    pub fn tone_on(&self) -> &[bool] {
+        &self.tone_on
+    }
+
+    /// Returns whether each machine was waiting for a key press at the end of the last frame.
+    pub fn waiting_for_key(&self) -> &[bool] {
+        &self.waiting_for_key
+    }
+
+    /// Returns the edges of the outputs of the machine at `index` during the last frame, in the
+    /// order they happened. Unlike the end of frame outputs, these include a tone that started and
+    /// stopped within the frame.
+    pub fn edges(&self, index: usize) -> &[Edge] {
+        self.cpus[index].edge_recorder.as_ref().map_or(&[], |edge_recorder| edge_recorder.edges())
+    }
+}
+
+/// Run a frame on `cpu` with the keys in `key_state` held down, and write its outputs: its screen,
+/// and whether the screen changed, it plays the tone and it waits for a key press.
+fn run_instance_frame(cpu: &mut Cpu, key_state: u16, screen: &mut [bool],
+    screen_changed: &mut bool, tone_on: &mut bool, waiting_for_key: &mut bool) {
+    let requested_key_state = cpu.requested_key_state();
+    for (key, &requested) in requested_key_state.iter().enumerate() {
+        let pressed = key_state & (1 << key) != 0;
+        if pressed != requested {
+            cpu.queue_host_key_event(key as u8, pressed, None)
+                .expect("Hex keys are valid key indices");
+        }
+    }
+
+    cpu.run_frame();
+
+    screen.copy_from_slice(&cpu.screen_buffer);
+    *screen_changed = cpu.handle_screen_dirty_flag();
+    *tone_on = cpu.should_play_tone();
+    *waiting_for_key = cpu.is_waiting_for_keypress();
+}
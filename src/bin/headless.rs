@@ -0,0 +1,618 @@
+//! Headless CHIP-8 runner. Runs a ROM for a fixed number of frames without a display, and
+//! optionally records the session to files. Can also run interactively in a terminal.
+
+use std::io::{self, Write};
+use std::process;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::event::{KeyboardEnhancementFlags, PopKeyboardEnhancementFlags};
+use crossterm::event::PushKeyboardEnhancementFlags;
+use crossterm::{cursor, execute, queue, terminal};
+
+use chip8_emu::Cpu;
+use chip8_emu::analyzer::{analyze_rom, RomAnalysis, DEFAULT_TRIAL_FRAMES};
+use chip8_emu::blocks::Backend;
+use chip8_emu::cartridge::{is_gif, OctoCartridge};
+use chip8_emu::keymap::Keymap;
+use chip8_emu::lint::lint_rom;
+use chip8_emu::load::{LoadOptions, ETI_660_LOAD_ADDRESS};
+use chip8_emu::metadata::{Platform, RomDatabase, RomMetadata};
+use chip8_emu::patch::apply_patch;
+use chip8_emu::quirks::Quirks;
+use chip8_emu::rom_file::decode_rom_file;
+use chip8_emu::terminal::{render_text, TextStyle};
+use chip8_emu::timing::FRAME_RATE_HZ;
+
+/// The number of frames a key stays pressed after it was typed, when the terminal can't report
+/// key releases. Terminals repeat the key presses of held keys, which keeps them pressed.
+const KEY_HOLD_FRAMES: u32 = 10;
+
+const USAGE: &str = "\
+Usage: headless <rom> [options]
+
+Known ROMs are identified in the ROM database, and run with their quirks, speed, key bindings and
+palette, unless they are set by the options. Unknown ROMs are analyzed, and run with the quirks
+detected for them. The ROM can also be an Octo cartridge (a GIF image), which is run with the
+settings of its Octo options. ROMs can also be loaded from zip archives, Intel HEX files and hex
+text dumps.
+
+Options:
+    --frames <n>          Number of 60HZ frames to run (default: 600, or until Esc is
+                          pressed with --terminal)
+    --clock-rate <hz>     Instructions executed per second (default: 600)
+    --vip-timing          Use the COSMAC VIP instruction timing instead of the clock rate
+    --backend <name>      Execute instructions with `interpreter` (default), `blocks` (translated
+                          basic blocks, faster at high clock rates) or `lockstep` (blocks,
+                          checked against the interpreter after every block)
+    --original-shift      Shift Vy instead of Vx in 8xy6 and 8xyE
+    --original-mem-acc    Increment I in Fx55 and Fx65
+    --no-display-wait     Don't wait for a v-blank after Dxyn
+    --fx0a-on-press       Complete Fx0A when a key is pressed instead of when it is released
+    --sample-rate <hz>    Audio sample rate for recordings (default: 44100)
+    --wav <path>          Record the audio of the session to a WAV file
+    --screenshot <path>   Save the screen at the end of the session to a PNG file
+    --gif <path>          Record the screen of the session to an animated GIF file
+    --scale <n>           Integer scale factor of saved images (default: 8)
+    --record-movie <path> Record the key events of the session to a movie file
+    --play-movie <path>   Replay a movie file instead of taking keyboard input, and verify
+                          that it ends in the recorded state. The movie's quirks and timing
+                          are used, and it runs for the movie's frames by default.
+    --terminal            Run interactively in the terminal, with the keyboard mapped to the
+                          hex keypad as 1234/QWER/ASDF/ZXCV. Press Esc to quit.
+    --bind <key>=<hex>    Also bind a keyboard key (e.g. `Left`, `Space` or `J`) to a hex key
+                          in the terminal. Can be repeated.
+    --rom-db <path>       Extend the bundled ROM database with the entries of a JSON file
+    --entry <name>        The ROM to load from a zip archive with several ROMs
+    --patch <path>        Apply an IPS or BPS patch to the ROM before running it
+    --load-address <hex>  Load the ROM at this address (default: 200)
+    --entry-point <hex>   Start executing at this address (default: the load address, or 2C0
+                          with --hires)
+    --eti-660             Load and start the ROM at 600, like the ETI-660
+    --hires               Show a 64x64 screen and execute 0230 as a screen clear, like hires
+                          CHIP-8 interpreters
+    --analyze             Print the platform and quirks detected for the ROM, and exit
+    --lint                Print a report of likely bugs and quirk-sensitive code in the ROM,
+                          and exit (with status 1 if there are errors)
+    --braille             Draw the screen with braille characters instead of half blocks";
+
+/// The options parsed from the command line
+struct Options {
+    rom_path: String,
+    entry_name: Option<String>,
+    patch_path: Option<String>,
+    frames: Option<u32>,
+    clock_rate_hz: Option<u32>,
+    vip_timing: bool,
+    backend: Backend,
+    quirks: Quirks,
+    // True if any quirk was set by the options, overriding the quirks from the ROM database
+    custom_quirks: bool,
+    sample_rate: u32,
+    wav_path: Option<String>,
+    screenshot_path: Option<String>,
+    gif_path: Option<String>,
+    scale: usize,
+    record_movie_path: Option<String>,
+    play_movie_path: Option<String>,
+    terminal: bool,
+    bindings: Vec<(String, u8)>,
+    rom_db_path: Option<String>,
+    // The memory layout set by the options, overriding the ROM database and detection
+    load_options: Option<LoadOptions>,
+    analyze: bool,
+    lint: bool,
+    text_style: TextStyle,
+}
+
+fn main() {
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, USAGE);
+        process::exit(2);
+    });
+
+    let rom = std::fs::read(&options.rom_path).unwrap_or_else(|err| {
+        eprintln!("Failed to read ROM file `{}`: {}", options.rom_path, err);
+        process::exit(1);
+    });
+
+    // Octo cartridges hold the program together with its settings
+    let cartridge = if is_gif(&rom) {
+        Some(OctoCartridge::parse(&rom).unwrap_or_else(|err| {
+            eprintln!("Failed to load cartridge `{}`: {}", options.rom_path, err);
+            process::exit(1);
+        }))
+    } else {
+        None
+    };
+    let rom = match &cartridge {
+        Some(cartridge) => cartridge.rom_bytes().to_vec(),
+        None => decode_rom_file(&rom, options.entry_name.clone()).unwrap_or_else(|err| {
+            eprintln!("Failed to load ROM file `{}`: {}", options.rom_path, err);
+            process::exit(1);
+        }),
+    };
+
+    // A patched ROM is identified by its own bytes, so the patch is applied before the lookup
+    let rom = match &options.patch_path {
+        Some(patch_path) => {
+            let patch = std::fs::read(patch_path).unwrap_or_else(|err| {
+                eprintln!("Failed to read patch file `{}`: {}", patch_path, err);
+                process::exit(1);
+            });
+            apply_patch(&rom, &patch).unwrap_or_else(|err| {
+                eprintln!("Failed to apply patch `{}`: {}", patch_path, err);
+                process::exit(1);
+            })
+        }
+        None => rom,
+    };
+
+    let mut rom_database = RomDatabase::bundled();
+    if let Some(rom_db_path) = &options.rom_db_path {
+        let extended = std::fs::read_to_string(rom_db_path).map_err(|err| err.to_string())
+            .and_then(|json| rom_database.extend_from_json(&json).map_err(|err| err.to_string()));
+        if let Err(err) = extended {
+            eprintln!("Failed to load ROM database `{}`: {}", rom_db_path, err);
+            process::exit(1);
+        }
+    }
+
+    if options.analyze {
+        print_analysis(&analyze_rom(&rom, DEFAULT_TRIAL_FRAMES));
+        return;
+    }
+
+    // The settings of a cartridge were chosen for it, so they are used even for known ROMs
+    let metadata = match &cartridge {
+        Some(cartridge) => Some(cartridge.metadata()),
+        None => {
+            let metadata = rom_database.lookup(&rom);
+            if let Some(metadata) = metadata {
+                print_rom_identity(metadata);
+            }
+            metadata
+        }
+    };
+
+    let load_options = options.load_options
+        .or_else(|| metadata.and_then(|metadata| metadata.load_options))
+        .unwrap_or_else(|| LoadOptions::detect(&rom));
+
+    if options.lint {
+        let report = lint_rom(&rom, &load_options);
+        println!("{}", report);
+        process::exit(if report.has_errors() { 1 } else { 0 });
+    }
+
+    let mut cpu = match &options.play_movie_path {
+        Some(movie_path) => {
+            let movie_text = std::fs::read_to_string(movie_path).unwrap_or_else(|err| {
+                eprintln!("Failed to read movie file `{}`: {}", movie_path, err);
+                process::exit(1);
+            });
+            Cpu::with_movie(&rom, &movie_text).unwrap_or_else(|err| {
+                eprintln!("Failed to load movie `{}`: {}", movie_path, err);
+                process::exit(1);
+            })
+        }
+        None => {
+            let quirks = match metadata {
+                _ if options.custom_quirks => options.quirks,
+                Some(metadata) => metadata.quirks.unwrap_or(options.quirks),
+                None => {
+                    let analysis = analyze_rom(&rom, DEFAULT_TRIAL_FRAMES);
+                    println!("Unknown ROM, using the detected {} quirks (confidence {:.0}%)",
+                        platform_name(analysis.platform), analysis.confidence * 100.0);
+                    analysis.quirks
+                }
+            };
+            if load_options != LoadOptions::standard() {
+                println!("Memory layout: {}", layout_description(&load_options));
+            }
+            let mut cpu = Cpu::with_rom_and_quirks_at(&rom, quirks, &load_options)
+                .unwrap_or_else(|err| {
+                    eprintln!("Failed to load ROM `{}`: {}", options.rom_path, err);
+                    process::exit(1);
+                });
+
+            let rom_speed = metadata.and_then(|metadata| metadata.speed);
+            if let Some(clock_rate_hz) = options.clock_rate_hz.or(rom_speed) {
+                cpu.set_clock_rate(clock_rate_hz);
+            }
+            cpu.set_vip_timing(options.vip_timing);
+            cpu
+        }
+    };
+    cpu.set_backend(options.backend);
+
+    for warning in cpu.load_warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+
+    // The database entries were validated when they were loaded
+    let mut keymap = match metadata {
+        Some(metadata) => metadata.keymap().expect("Invalid keymap in ROM database"),
+        None => Keymap::qwerty(),
+    };
+    for (host_key, hex_key) in &options.bindings {
+        keymap.bind(host_key, *hex_key).expect("Invalid key binding");
+    }
+    cpu.set_keymap(&keymap);
+
+    if let Some(metadata) = metadata {
+        let palette = metadata.palette_colors().expect("Invalid palette in ROM database");
+        for (index, &color) in palette.iter().enumerate() {
+            cpu.set_palette_color(index, color);
+        }
+    }
+
+    let frames = options.frames.or_else(|| cpu.playing_movie().map(|movie| movie.num_frames));
+
+    if options.record_movie_path.is_some() {
+        cpu.start_movie_recording();
+    }
+
+    if options.wav_path.is_some() {
+        cpu.enable_audio(options.sample_rate);
+        cpu.start_audio_recording();
+    }
+
+    if options.gif_path.is_some() {
+        cpu.start_gif_recording(options.scale);
+    }
+
+    if options.terminal {
+        if let Err(err) = run_terminal(&mut cpu, options.text_style, frames) {
+            eprintln!("Terminal error: {}", err);
+            process::exit(1);
+        }
+    } else {
+        for _ in 0..frames.unwrap_or(600) {
+            cpu.run_frame();
+        }
+    }
+
+    if let Some(movie_path) = &options.record_movie_path {
+        write_output(movie_path, cpu.stop_movie_recording().as_bytes());
+    }
+
+    if let Some(wav_path) = &options.wav_path {
+        write_output(wav_path, &cpu.stop_audio_recording());
+    }
+
+    if let Some(gif_path) = &options.gif_path {
+        write_output(gif_path, &cpu.stop_gif_recording());
+    }
+
+    if let Some(screenshot_path) = &options.screenshot_path {
+        write_output(screenshot_path, &cpu.screenshot_png(options.scale));
+    }
+
+    if cpu.is_playing_movie() {
+        match cpu.verify_movie() {
+            Ok(()) => println!("Movie replayed successfully, the final state matches"),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Print the title and author of an identified ROM, and warn if its platform isn't supported.
+fn print_rom_identity(metadata: &RomMetadata) {
+    match &metadata.author {
+        Some(author) => println!("Identified ROM: {} by {}", metadata.title, author),
+        None => println!("Identified ROM: {}", metadata.title),
+    }
+
+    match metadata.platform {
+        Platform::Chip8 => {}
+        platform => eprintln!("Warning: {} ROMs are not supported", platform_name(platform)),
+    }
+}
+
+/// Print the platform, quirks and findings of a ROM analysis.
+fn print_analysis(analysis: &RomAnalysis) {
+    println!("Platform: {}", platform_name(analysis.platform));
+    println!("Recommended quirks (confidence {:.0}%):", analysis.confidence * 100.0);
+    println!("    original shift:   {}", analysis.quirks.original_shift);
+    println!("    original mem acc: {}", analysis.quirks.original_mem_acc);
+    println!("    display wait:     {}", analysis.quirks.display_wait);
+    println!("    wait for release: {}", analysis.quirks.wait_for_release);
+    println!("Memory layout: {}", layout_description(&analysis.load_options));
+
+    if !analysis.findings.is_empty() {
+        println!("Findings:");
+        for finding in &analysis.findings {
+            println!("    {}", finding);
+        }
+    }
+}
+
+/// Returns a description of where a ROM is loaded and started, and its screen size.
+fn layout_description(load_options: &LoadOptions) -> String {
+    format!("loaded at {:#05X}, starting at {:#05X}, {} screen", load_options.load_address,
+        load_options.entry_point, if load_options.hires { "64x64" } else { "64x32" })
+}
+
+/// Returns the display name of `platform`.
+fn platform_name(platform: Platform) -> &'static str {
+    match platform {
+        Platform::Chip8 => "CHIP-8",
+        Platform::SuperChip => "SCHIP",
+        Platform::XoChip => "XO-CHIP",
+    }
+}
+
+/// Run the cpu interactively in the terminal in real time, until Esc is pressed or `frame_limit`
+/// frames were run.
+fn run_terminal(cpu: &mut Cpu, text_style: TextStyle, frame_limit: Option<u32>)
+    -> io::Result<()> {
+    let mut stdout = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+    // Without the keyboard enhancement protocol terminals only report key presses
+    let reports_releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if reports_releases {
+        execute!(stdout,
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+    }
+
+    let result = terminal_loop(cpu, text_style, frame_limit, reports_releases);
+
+    // Restore the terminal even if the loop failed
+    if reports_releases {
+        let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+    }
+    let _ = execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+
+    result
+}
+
+/// The frame loop of `run_terminal`.
+fn terminal_loop(cpu: &mut Cpu, text_style: TextStyle, frame_limit: Option<u32>,
+    reports_releases: bool) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let frame_duration = Duration::from_secs(1) / FRAME_RATE_HZ;
+
+    // The number of frames every key stays pressed for. Held keys stay pressed until they are
+    // released if the terminal reports releases.
+    let mut key_hold_frames = [0u32; 16];
+    let mut next_frame = Instant::now();
+    let mut frames_run = 0;
+    while frame_limit.is_none_or(|frame_limit| frames_run < frame_limit) {
+        // Handle the keyboard events until it is time for the next frame
+        while event::poll(next_frame.saturating_duration_since(Instant::now()))? {
+            let key_event = match event::read()? {
+                Event::Key(key_event) => key_event,
+                _ => continue,
+            };
+
+            let ctrl_c = key_event.code == KeyCode::Char('c')
+                && key_event.modifiers.contains(KeyModifiers::CONTROL);
+            if key_event.code == KeyCode::Esc || ctrl_c {
+                return Ok(());
+            }
+
+            let hex_key = host_key_name(key_event.code)
+                .and_then(|host_key| cpu.keymap().hex_key(&host_key));
+            if let Some(hex_key) = hex_key {
+                if key_event.kind == KeyEventKind::Release {
+                    key_hold_frames[hex_key as usize] = 0;
+                } else if reports_releases {
+                    key_hold_frames[hex_key as usize] = u32::MAX;
+                } else {
+                    key_hold_frames[hex_key as usize] = KEY_HOLD_FRAMES;
+                }
+            }
+        }
+        next_frame += frame_duration;
+
+        // Without release reports a key is released when it wasn't repeated for long enough
+        let mut key_state = [0u8; 16];
+        for (hex_key, hold_frames) in key_hold_frames.iter_mut().enumerate() {
+            *hold_frames = hold_frames.saturating_sub(if reports_releases { 0 } else { 1 });
+            key_state[hex_key] = (*hold_frames > 0) as u8;
+        }
+
+        cpu.update_key_state(&key_state).expect("The key state has a byte for every key");
+        cpu.run_frame();
+        frames_run += 1;
+
+        if cpu.handle_screen_dirty_flag() {
+            let screen_buffer = cpu.screen_buffer();
+            let screen_width = cpu.screen_width();
+            let text = render_text(text_style, screen_width, cpu.screen_height(),
+                |x, y| screen_buffer[y * screen_width + x]);
+
+            // Raw mode doesn't translate newlines, so we move the cursor to every line ourselves
+            queue!(stdout, cursor::MoveTo(0, 0))?;
+            for line in text.lines() {
+                queue!(stdout, crossterm::style::Print(line), cursor::MoveToNextLine(1))?;
+            }
+            queue!(stdout, crossterm::style::Print("Press Esc to quit"))?;
+            stdout.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the keymap name of the terminal key `code`, if it has one.
+fn host_key_name(code: KeyCode) -> Option<String> {
+    let name = match code {
+        KeyCode::Char(' ') => "Space",
+        KeyCode::Char(key) => return Some(key.to_string()),
+        KeyCode::Up => "Up",
+        KeyCode::Down => "Down",
+        KeyCode::Left => "Left",
+        KeyCode::Right => "Right",
+        KeyCode::Enter => "Enter",
+        KeyCode::Tab => "Tab",
+        KeyCode::Backspace => "Backspace",
+        _ => return None,
+    };
+
+    Some(name.to_string())
+}
+
+/// Parse the command line arguments (without the program name).
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut rom_path = None;
+    let mut load_address = None;
+    let mut entry_point = None;
+    let mut hires = false;
+    let mut options = Options {
+        rom_path: String::new(),
+        entry_name: None,
+        patch_path: None,
+        frames: None,
+        clock_rate_hz: None,
+        vip_timing: false,
+        backend: Backend::Interpreter,
+        quirks: Quirks {
+            display_wait: true,
+            ..Quirks::default()
+        },
+        custom_quirks: false,
+        sample_rate: 44100,
+        wav_path: None,
+        screenshot_path: None,
+        gif_path: None,
+        scale: 8,
+        record_movie_path: None,
+        play_movie_path: None,
+        terminal: false,
+        bindings: Vec::new(),
+        rom_db_path: None,
+        load_options: None,
+        analyze: false,
+        lint: false,
+        text_style: TextStyle::HalfBlocks,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next().ok_or_else(|| format!("Missing value for `{}`", name))
+        };
+
+        match arg.as_str() {
+            "--frames" => options.frames = Some(parse_number(&arg, &value(&arg)?)?),
+            "--clock-rate" => options.clock_rate_hz = Some(parse_number(&arg, &value(&arg)?)?),
+            "--vip-timing" => options.vip_timing = true,
+            "--backend" => options.backend = parse_backend(&value(&arg)?)?,
+            "--original-shift" => {
+                options.quirks.original_shift = true;
+                options.custom_quirks = true;
+            }
+            "--original-mem-acc" => {
+                options.quirks.original_mem_acc = true;
+                options.custom_quirks = true;
+            }
+            "--no-display-wait" => {
+                options.quirks.display_wait = false;
+                options.custom_quirks = true;
+            }
+            "--fx0a-on-press" => {
+                options.quirks.wait_for_release = false;
+                options.custom_quirks = true;
+            }
+            "--sample-rate" => options.sample_rate = parse_number(&arg, &value(&arg)?)?,
+            "--wav" => options.wav_path = Some(value(&arg)?),
+            "--screenshot" => options.screenshot_path = Some(value(&arg)?),
+            "--gif" => options.gif_path = Some(value(&arg)?),
+            "--scale" => options.scale = parse_number(&arg, &value(&arg)?)? as usize,
+            "--record-movie" => options.record_movie_path = Some(value(&arg)?),
+            "--play-movie" => options.play_movie_path = Some(value(&arg)?),
+            "--terminal" => options.terminal = true,
+            "--bind" => {
+                let binding = value(&arg)?;
+                let (host_key, hex_key) = binding.split_once('=')
+                    .ok_or_else(|| format!("Invalid key binding `{}`", binding))?;
+                let hex_key = u8::from_str_radix(hex_key, 16)
+                    .map_err(|_| format!("Invalid hex key in key binding `{}`", binding))?;
+                // Validate the binding now, it is bound once the ROM's own keymap is known
+                Keymap::new().bind(host_key, hex_key).map_err(|err| err.to_string())?;
+                options.bindings.push((host_key.to_string(), hex_key));
+            }
+            "--rom-db" => options.rom_db_path = Some(value(&arg)?),
+            "--entry" => options.entry_name = Some(value(&arg)?),
+            "--patch" => options.patch_path = Some(value(&arg)?),
+            "--load-address" => load_address = Some(parse_address(&arg, &value(&arg)?)?),
+            "--entry-point" => entry_point = Some(parse_address(&arg, &value(&arg)?)?),
+            "--eti-660" => load_address = Some(ETI_660_LOAD_ADDRESS),
+            "--hires" => hires = true,
+            "--analyze" => options.analyze = true,
+            "--lint" => options.lint = true,
+            "--braille" => options.text_style = TextStyle::Braille,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("Unknown option `{}`", arg)),
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => return Err(format!("Unexpected argument `{}`", arg)),
+        }
+    }
+
+    if options.scale == 0 {
+        return Err("Scale must not be zero".to_string());
+    }
+
+    if options.record_movie_path.is_some() && options.play_movie_path.is_some() {
+        return Err("Can't record a movie while playing one".to_string());
+    }
+
+    if load_address.is_some() || entry_point.is_some() || hires {
+        let layout = if hires { LoadOptions::hires() } else { LoadOptions::standard() };
+        let load_address = load_address.unwrap_or(layout.load_address);
+        let default_entry_point = if hires { layout.entry_point } else { load_address };
+        let load_options = LoadOptions {
+            load_address,
+            entry_point: entry_point.unwrap_or(default_entry_point),
+            hires,
+        };
+        if !load_options.is_valid() {
+            return Err("The load address must be between 200 and FFF, and the entry point \
+                between the load address and FFE".to_string());
+        }
+        options.load_options = Some(load_options);
+    }
+
+    options.rom_path = rom_path.ok_or("Missing ROM path")?;
+    Ok(options)
+}
+
+/// Parse the value of the numeric option `name`.
+fn parse_number(name: &str, value: &str) -> Result<u32, String> {
+    value.parse().map_err(|_| format!("Invalid value `{}` for `{}`", value, name))
+}
+
+/// Parse the value of the `--backend` option.
+fn parse_backend(value: &str) -> Result<Backend, String> {
+    match value {
+        "interpreter" => Ok(Backend::Interpreter),
+        "blocks" => Ok(Backend::Blocks),
+        "lockstep" => Ok(Backend::Lockstep),
+        _ => Err(format!("Unknown backend `{}`", value)),
+    }
+}
+
+/// Parse the value of the hex address option `name`.
+fn parse_address(name: &str, value: &str) -> Result<usize, String> {
+    let hex = value.strip_prefix("0x").unwrap_or(value);
+    usize::from_str_radix(hex, 16)
+        .map_err(|_| format!("Invalid address `{}` for `{}`", value, name))
+}
+
+/// Write `bytes` to the file at `path`, exiting on failure.
+fn write_output(path: &str, bytes: &[u8]) {
+    if let Err(err) = std::fs::write(path, bytes) {
+        eprintln!("Failed to write `{}`: {}", path, err);
+        process::exit(1);
+    }
+}
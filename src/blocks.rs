@@ -0,0 +1,182 @@
+//! The block backend, which translates basic blocks of CHIP-8 code into runs of decoded
+//! instructions. `run_frame` executes a whole block without decoding its instructions or checking
+//! for key events and v-blanks between them, which is much cheaper than interpreting it one
+//! instruction at a time.
+//!
+//! The translation lowers every instruction of the block to an `Instruction`, which holds its
+//! resolved operands (register indices, immediates and addresses). A block is executed by passing
+//! those operands straight to the instruction handlers, so its opcodes are never fetched or
+//! decoded again until the block is invalidated.
+//!
+//! A block ends with the first instruction that changes the control flow, or that can block the
+//! cpu, write memory or change the tone. Everything such an instruction affects is handled after
+//! the block, exactly as if it were interpreted. Writes to memory invalidate the blocks translated
+//! from it, and code that keeps being overwritten (self-modifying code) falls back to the
+//! interpreter.
+
+use std::ops::Range;
+
+use wasm_bindgen::prelude::*;
+
+use crate::instruction::Instruction;
+
+/// The maximum number of instructions in a block. Longer runs of straight-line code are split.
+const MAX_BLOCK_LEN: usize = 32;
+/// The number of times the blocks starting at an address can be invalidated, before it is treated
+/// as self-modifying code and interpreted instead.
+const SMC_THRESHOLD: u8 = 4;
+/// The number of translated instructions kept before all blocks are dropped and translated again.
+/// Invalidated blocks aren't reclaimed until then.
+const MAX_TRANSLATED_INSTRUCTIONS: usize = 1 << 16;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How `run_frame` executes instructions.
+pub enum Backend {
+    /// Decode and execute every instruction on its own.
+    Interpreter,
+    /// Execute translated basic blocks, interpreting self-modifying code.
+    Blocks,
+    /// Execute translated basic blocks, while an interpreted copy of the machine runs in lockstep.
+    /// The machine states are compared after every block, and any difference is a panic. Used to
+    /// test the block backend.
+    Lockstep,
+}
+
+/// The translated blocks of a program.
+pub struct BlockCache {
+    // The lowered instructions of all blocks, with their operands resolved
+    instructions: Vec<Instruction>,
+    // The range of `instructions` holding the block starting at every memory address
+    blocks: Vec<Option<Range<usize>>>,
+    // The number of times the blocks starting at every memory address were invalidated
+    invalidations: Vec<u8>,
+}
+
+impl BlockCache {
+    /// Create an empty cache for a memory of `mem_size` bytes.
+    pub fn new(mem_size: usize) -> Self {
+        BlockCache {
+            instructions: Vec::new(),
+            blocks: vec![None; mem_size],
+            invalidations: vec![0; mem_size],
+        }
+    }
+
+    /// Returns the range of instructions of the block starting at `address` in `memory`,
+    /// translating it if needed, where `hires` is true for hires CHIP-8 programs. Returns `None` if
+    /// the code at the address must be interpreted, because it is self-modifying or isn't a CHIP-8
+    /// instruction.
+    pub fn block(&mut self, memory: &[u8], address: usize, hires: bool)
+        -> Option<Range<usize>> {
+        if address + 1 >= memory.len() {
+            // The interpreter reports the PC being out of bounds
+            return None;
+        }
+        if let Some(block) = &self.blocks[address] {
+            return Some(block.clone());
+        }
+        if self.invalidations[address] >= SMC_THRESHOLD {
+            return None;
+        }
+
+        if self.instructions.len() >= MAX_TRANSLATED_INSTRUCTIONS {
+            self.instructions.clear();
+            for block in &mut self.blocks {
+                *block = None;
+            }
+        }
+
+        let start = self.instructions.len();
+        let mut instr_address = address;
+        while instr_address + 1 < memory.len() && self.instructions.len() - start < MAX_BLOCK_LEN
+        {
+            let instr = ((memory[instr_address] as u16) << 8) | (memory[instr_address + 1] as u16);
+            let decoded = match Instruction::decode(instr, hires) {
+                Some(decoded) => decoded,
+                None => break,
+            };
+
+            self.instructions.push(decoded);
+            if ends_block(&decoded) {
+                break;
+            }
+            instr_address += 2;
+        }
+
+        if self.instructions.len() == start {
+            // The interpreter reports the invalid instruction
+            return None;
+        }
+
+        let block = start..self.instructions.len();
+        self.blocks[address] = Some(block.clone());
+        Some(block)
+    }
+
+    /// Returns the translated instruction at `index`, whose operands are passed to its handler.
+    pub fn instruction(&self, index: usize) -> Instruction {
+        self.instructions[index]
+    }
+
+    /// Invalidate the blocks translated from any of the `len` bytes starting at `address`.
+    pub fn invalidate(&mut self, address: usize, len: usize) {
+        let end = std::cmp::min(address + len, self.blocks.len());
+        // The blocks that start before the address can still contain it
+        let first_start = address.saturating_sub(2 * MAX_BLOCK_LEN - 1);
+        for start in first_start..end {
+            let overlaps = match &self.blocks[start] {
+                Some(block) => start + 2 * block.len() > address,
+                None => false,
+            };
+            if overlaps {
+                self.blocks[start] = None;
+                self.invalidations[start] = self.invalidations[start].saturating_add(1);
+            }
+        }
+    }
+}
+
+/// Returns true if the instruction must be the last one in its block: it changes the control flow,
+/// can block the cpu (waiting for a key or a v-blank), writes memory, or changes the tone.
+fn ends_block(instruction: &Instruction) -> bool {
+    instruction.is_skip() || matches!(instruction, Instruction::Ret | Instruction::Jp { .. }
+        | Instruction::Call { .. } | Instruction::JpV0 { .. } | Instruction::Drw { .. }
+        | Instruction::LdKey { .. } | Instruction::LdSt { .. } | Instruction::LdBcd { .. }
+        | Instruction::Store { .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cpu;
+
+    /// Run `rom` for 10 seconds while pressing every key in turn, and returns the state hash
+    /// after every frame.
+    fn run_rom(rom: &[u8], backend: Backend, vip_timing: bool) -> Vec<u64> {
+        let mut cpu = Cpu::with_rom(rom).unwrap();
+        cpu.set_backend(backend);
+        cpu.set_rng_seed(1234);
+        cpu.set_clock_rate(3000);
+        cpu.set_vip_timing(vip_timing);
+
+        (0..600).map(|frame| {
+            let mut key_state = [0u8; 16];
+            key_state[frame / 20 % 16] = (frame % 20 < 10) as u8;
+            cpu.update_key_state(&key_state).unwrap();
+            cpu.run_frame();
+            cpu.state_hash()
+        }).collect()
+    }
+
+    #[test]
+    fn blocks_match_interpreter() {
+        for rom in [&include_bytes!("../static/roms/brix.rom")[..],
+            &include_bytes!("../static/roms/tetris.rom")[..]] {
+            for vip_timing in [false, true] {
+                assert_eq!(run_rom(rom, Backend::Blocks, vip_timing),
+                    run_rom(rom, Backend::Interpreter, vip_timing));
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Loading of Octo cartridges: GIF images that embed a program and its Octo options in their
+//! pixel data.
+//!
+//! Every pixel holds 2 bits of the payload in the low bits of its palette index, and every 4
+//! pixels (most significant bits first) form a byte. The pixels of all the frames are used, in
+//! order. The payload is a 4-byte big-endian length, followed by that many bytes of JSON:
+//! ```text
+//! { "options": { "tickrate": 20, "shiftQuirks": false, ... }, "program": ": main clear ..." }
+//! ```
+//! The program is Octo source code, which is compiled with [`crate::octo`]. Only CHIP-8
+//! programs are supported: cartridges using SUPER-CHIP or XO-CHIP instructions are rejected.
+
+use std::fmt;
+
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+
+use crate::load::{validate_rom, LoadError, LoadOptions};
+use crate::metadata::{Platform, RomMetadata};
+use crate::octo::{self, CompileError};
+use crate::quirks::Quirks;
+use crate::timing::FRAME_RATE_HZ;
+
+/// The GIF signature, which starts every cartridge.
+const GIF_SIGNATURE: &[u8] = b"GIF8";
+
+/// The Octo options of a cartridge. The options that don't affect this emulator are ignored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct OctoOptions {
+    /// The number of instructions executed per frame.
+    tickrate: Option<u32>,
+    /// The shift instructions shift Vx in-place.
+    shift_quirks: bool,
+    /// The load/store instructions don't increment I.
+    load_store_quirks: bool,
+    /// The draw instruction waits for the next v-blank.
+    v_blank_quirks: bool,
+    /// The maximal program size, which identifies the platform.
+    max_size: Option<u32>,
+    background_color: Option<String>,
+    fill_color: Option<String>,
+    fill_color2: Option<String>,
+    blend_color: Option<String>,
+}
+
+/// The payload of a cartridge.
+#[derive(Deserialize)]
+struct CartridgePayload {
+    #[serde(default)]
+    options: OctoOptions,
+    program: String,
+}
+
+/// Returns true if `bytes` look like a GIF image, and so might be an Octo cartridge.
+pub fn is_gif(bytes: &[u8]) -> bool {
+    bytes.starts_with(GIF_SIGNATURE)
+}
+
+#[wasm_bindgen]
+/// A decoded Octo cartridge: the ROM bytes of its program, and the settings from its options.
+pub struct OctoCartridge {
+    rom: Vec<u8>,
+    metadata: RomMetadata,
+}
+
+#[wasm_bindgen]
+impl OctoCartridge {
+    /// Decode the Octo cartridge `gif`.
+    pub fn parse(gif: &[u8]) -> Result<OctoCartridge, CartridgeError> {
+        let payload = decode_payload(gif)?;
+        let payload: CartridgePayload = serde_json::from_slice(&payload)
+            .map_err(|err| CartridgeError::InvalidPayload(err.to_string()))?;
+
+        let rom = octo::compile(&payload.program).map_err(CartridgeError::Compile)?;
+        validate_rom(&rom, &LoadOptions::standard()).map_err(CartridgeError::Load)?;
+
+        let metadata = options_metadata(&payload.options);
+        metadata.palette_colors()
+            .map_err(|_| CartridgeError::InvalidPayload("Invalid colour option".to_string()))?;
+
+        Ok(OctoCartridge {
+            rom,
+            metadata,
+        })
+    }
+
+    /// Returns the ROM bytes of the program.
+    pub fn rom(&self) -> Vec<u8> {
+        self.rom.clone()
+    }
+
+    /// Returns the settings from the options in JSON format, in the same form as the ROM
+    /// database entries.
+    pub fn metadata_json(&self) -> String {
+        serde_json::to_string(&self.metadata).expect("Failed to serialize ROM metadata")
+    }
+}
+
+impl OctoCartridge {
+    /// Returns the ROM bytes of the program.
+    pub fn rom_bytes(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// Returns the settings from the options: the platform, quirks, speed and palette.
+    pub fn metadata(&self) -> &RomMetadata {
+        &self.metadata
+    }
+}
+
+/// Decode the payload from the low bits of the pixels of `gif`.
+fn decode_payload(gif: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(gif)
+        .map_err(|err| CartridgeError::Gif(err.to_string()))?;
+
+    let mut bytes = Vec::new();
+    let mut byte = 0u8;
+    let mut num_pixels = 0usize;
+    while let Some(frame) = decoder.read_next_frame()
+        .map_err(|err| CartridgeError::Gif(err.to_string()))? {
+        for &color_index in frame.buffer.iter() {
+            byte = (byte << 2) | (color_index & 3);
+            num_pixels += 1;
+            if num_pixels.is_multiple_of(4) {
+                bytes.push(byte);
+            }
+        }
+    }
+
+    if bytes.len() < 4 {
+        return Err(CartridgeError::InvalidPayload("Missing payload length".to_string()));
+    }
+    let payload_len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    bytes.get(4..4 + payload_len).map(|payload| payload.to_vec())
+        .ok_or_else(|| CartridgeError::InvalidPayload("Truncated payload".to_string()))
+}
+
+/// Returns the settings the Octo `options` describe.
+fn options_metadata(options: &OctoOptions) -> RomMetadata {
+    let platform = match options.max_size {
+        Some(max_size) if max_size > 3584 => Platform::XoChip,
+        Some(max_size) if max_size > 3232 => Platform::SuperChip,
+        _ => Platform::Chip8,
+    };
+
+    // The palette is set up to the first missing colour
+    let palette = [&options.background_color, &options.fill_color, &options.fill_color2,
+        &options.blend_color].iter()
+        .map_while(|&color| color.clone()).collect();
+
+    RomMetadata {
+        title: "Octo cartridge".to_string(),
+        author: None,
+        platform,
+        description: None,
+        quirks: Some(Quirks {
+            original_shift: !options.shift_quirks,
+            original_mem_acc: !options.load_store_quirks,
+            display_wait: options.v_blank_quirks,
+            ..Quirks::default()
+        }),
+        speed: options.tickrate.map(|tickrate| tickrate * FRAME_RATE_HZ),
+        load_options: None,
+        keymap: Default::default(),
+        palette,
+    }
+}
+
+/// An error in loading an Octo cartridge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CartridgeError {
+    /// The file is not a valid GIF image.
+    Gif(String),
+    /// The image doesn't hold a valid cartridge payload.
+    InvalidPayload(String),
+    /// The program doesn't compile.
+    Compile(CompileError),
+    /// The program can't be loaded.
+    Load(LoadError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Gif(message) => write!(f, "Invalid cartridge image: {}", message),
+            CartridgeError::InvalidPayload(message) => {
+                write!(f, "Invalid cartridge payload: {}", message)
+            }
+            CartridgeError::Compile(err) => {
+                write!(f, "Failed to compile the cartridge program: {}", err)
+            }
+            CartridgeError::Load(err) => write!(f, "Invalid cartridge program: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<CartridgeError> for JsValue {
+    fn from(err: CartridgeError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
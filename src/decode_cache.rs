@@ -0,0 +1,47 @@
+//! A cache of decoded instructions, so a `step` doesn't have to refetch and decode an instruction
+//! it has already seen. Programs spend most of their time in small loops, so almost every step
+//! hits the cache.
+
+use crate::instruction::Instruction;
+
+/// The decoded instruction at every memory address. Instructions don't have to be aligned, so an
+/// entry is kept for both even and odd addresses. An entry must be invalidated when either of the
+/// two bytes it was decoded from is written.
+pub struct DecodeCache {
+    entries: Vec<Option<Instruction>>,
+}
+
+impl DecodeCache {
+    /// Create an empty cache for a memory of `mem_size` bytes.
+    pub fn new(mem_size: usize) -> Self {
+        DecodeCache {
+            entries: vec![None; mem_size],
+        }
+    }
+
+    /// Returns the decoded instruction at `address` in `memory`, where `hires` is true for hires
+    /// CHIP-8 programs. Returns `None` if it isn't a CHIP-8 instruction. Those aren't cached, as
+    /// executing them is an error anyway.
+    pub fn decode(&mut self, memory: &[u8], address: usize, hires: bool) -> Option<Instruction> {
+        assert!(address + 1 < memory.len(), "PC out of memory bounds");
+
+        if let Some(decoded) = self.entries[address] {
+            return Some(decoded);
+        }
+
+        let instr = ((memory[address] as u16) << 8) | (memory[address + 1] as u16);
+        let decoded = Instruction::decode(instr, hires);
+        self.entries[address] = decoded;
+        decoded
+    }
+
+    /// Invalidate the instructions decoded from any of the `len` bytes starting at `address`.
+    pub fn invalidate(&mut self, address: usize, len: usize) {
+        // The instruction starting one byte earlier contains the first written byte
+        let start = address.saturating_sub(1);
+        let end = std::cmp::min(address + len, self.entries.len());
+        for entry in &mut self.entries[start..end] {
+            *entry = None;
+        }
+    }
+}
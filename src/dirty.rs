@@ -0,0 +1,92 @@
+//! Tracking of the screen regions changed by draw/clear instructions, so a display only has to
+//! update the parts of the screen that actually changed.
+
+/// The maximum number of separate regions tracked. When more regions are changed, they are all
+/// merged into their bounding rectangle, which is cheaper for a display than many tiny updates.
+const MAX_DIRTY_RECTS: usize = 16;
+
+/// A rectangle of screen pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl DirtyRect {
+    /// Returns true if the rectangles overlap or share an edge, i.e. their union doesn't contain
+    /// any pixels that are in neither of them.
+    fn touches(&self, other: &DirtyRect) -> bool {
+        self.x <= other.x + other.width && other.x <= self.x + self.width
+            && self.y <= other.y + other.height && other.y <= self.y + self.height
+    }
+
+    /// Returns the bounding rectangle of both rectangles.
+    fn union(&self, other: &DirtyRect) -> DirtyRect {
+        let x = std::cmp::min(self.x, other.x);
+        let y = std::cmp::min(self.y, other.y);
+        DirtyRect {
+            x,
+            y,
+            width: std::cmp::max(self.x + self.width, other.x + other.width) - x,
+            height: std::cmp::max(self.y + self.height, other.y + other.height) - y,
+        }
+    }
+}
+
+/// The set of screen regions changed since the display was last updated.
+pub struct DirtyRegions {
+    rects: Vec<DirtyRect>,
+}
+
+impl DirtyRegions {
+    /// Construct an empty set of regions.
+    pub fn new() -> Self {
+        DirtyRegions {
+            rects: Vec::new(),
+        }
+    }
+
+    /// Mark `rect` as changed. Regions that touch are merged, so the tracked regions never
+    /// overlap.
+    pub fn add(&mut self, mut rect: DirtyRect) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+
+        // Merging can make the rectangle touch regions it didn't touch before, so we keep merging
+        // until nothing touches it.
+        while let Some(touching_idx) = self.rects.iter().position(|other| other.touches(&rect)) {
+            rect = rect.union(&self.rects.swap_remove(touching_idx));
+        }
+        self.rects.push(rect);
+
+        if self.rects.len() > MAX_DIRTY_RECTS {
+            let bounds = self.rects.iter().fold(self.rects[0], |bounds, rect| bounds.union(rect));
+            self.rects.clear();
+            self.rects.push(bounds);
+        }
+    }
+
+    /// Returns the changed regions.
+    pub fn rects(&self) -> &[DirtyRect] {
+        &self.rects
+    }
+
+    /// Returns true if nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    /// Forget all the changed regions, after the display was updated.
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+}
+
+impl Default for DirtyRegions {
+    fn default() -> Self {
+        DirtyRegions::new()
+    }
+}
@@ -0,0 +1,127 @@
+//! Recording of the screen to an animated GIF.
+
+use std::borrow::Cow;
+
+use crate::render::PALETTE_SIZE;
+use crate::timing::FRAME_RATE_HZ;
+
+/// A recorded frame, which was shown for `duration_frames` consecutive 60HZ frames.
+struct RecordedFrame {
+    // The palette index of every screen pixel, row by row
+    color_indices: Vec<u8>,
+    duration_frames: u32,
+}
+
+/// Captures the screen at every 60HZ frame, and encodes the captured frames as an animated GIF.
+/// Consecutive identical frames are stored once, with a longer delay.
+pub struct GifRecorder {
+    width: usize,
+    height: usize,
+    // Every screen pixel becomes a `scale` by `scale` square in the GIF
+    scale: usize,
+    // The palette colours in 0xRRGGBB format
+    palette: [u32; PALETTE_SIZE],
+    frames: Vec<RecordedFrame>,
+}
+
+impl GifRecorder {
+    /// Construct a recorder for a `width` by `height` screen, with the given palette.
+    pub fn new(width: usize, height: usize, scale: usize, palette: [u32; PALETTE_SIZE]) -> Self {
+        assert!(scale > 0, "Scale must not be zero");
+        assert!(width * scale <= u16::MAX as usize && height * scale <= u16::MAX as usize,
+            "GIF dimensions are too large");
+
+        GifRecorder {
+            width,
+            height,
+            scale,
+            palette,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Capture a frame, where `color_index(x, y)` returns the palette index of the pixel at
+    /// (x, y).
+    pub fn capture(&mut self, color_index: impl Fn(usize, usize) -> usize) {
+        let mut color_indices = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                color_indices.push(color_index(x, y) as u8);
+            }
+        }
+
+        match self.frames.last_mut() {
+            Some(last_frame) if last_frame.color_indices == color_indices => {
+                last_frame.duration_frames += 1;
+            }
+            _ => self.frames.push(RecordedFrame {
+                color_indices,
+                duration_frames: 1,
+            }),
+        }
+    }
+
+    /// Returns the number of distinct frames captured so far.
+    pub fn num_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Encode the captured frames as the bytes of an animated GIF, which loops forever.
+    pub fn to_gif_bytes(&self) -> Vec<u8> {
+        // A two colour palette is enough unless a second display plane was used
+        let uses_all_colors = self.frames.iter()
+            .any(|frame| frame.color_indices.iter().any(|&index| index >= 2));
+        let num_colors = if uses_all_colors { PALETTE_SIZE } else { 2 };
+
+        let mut gif_palette = Vec::with_capacity(num_colors * 3);
+        for &color in &self.palette[..num_colors] {
+            gif_palette.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8]);
+        }
+
+        let gif_width = self.width * self.scale;
+        let gif_height = self.height * self.scale;
+
+        // Writing to a `Vec` can't fail, and the frame sizes always match the GIF size
+        let mut gif_bytes = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif_bytes, gif_width as u16,
+                gif_height as u16, &gif_palette).expect("Failed to write GIF header");
+            encoder.set_repeat(gif::Repeat::Infinite).expect("Failed to write GIF header");
+
+            // GIF delays are in centiseconds, which don't divide a 60HZ frame. We round the time
+            // at which every frame ends, so the rounding errors don't accumulate.
+            let mut elapsed_frames = 0;
+            let mut elapsed_centisecs = 0;
+            for frame in &self.frames {
+                elapsed_frames += frame.duration_frames;
+                let end_centisecs =
+                    (elapsed_frames * 100 + FRAME_RATE_HZ / 2) / FRAME_RATE_HZ;
+                let delay = end_centisecs - elapsed_centisecs;
+                elapsed_centisecs = end_centisecs;
+
+                let mut scaled_indices = Vec::with_capacity(gif_width * gif_height);
+                for row in frame.color_indices.chunks(self.width) {
+                    let scaled_row_start = scaled_indices.len();
+                    for &index in row {
+                        scaled_indices.extend(std::iter::repeat_n(index, self.scale));
+                    }
+                    for _ in 1..self.scale {
+                        scaled_indices.extend_from_within(scaled_row_start..
+                            scaled_row_start + gif_width);
+                    }
+                }
+
+                let gif_frame = gif::Frame {
+                    width: gif_width as u16,
+                    height: gif_height as u16,
+                    delay: std::cmp::min(delay, u16::MAX as u32) as u16,
+                    buffer: Cow::Owned(scaled_indices),
+                    ..gif::Frame::default()
+                };
+                encoder.write_frame(&gif_frame).expect("Failed to write GIF frame");
+            }
+        }
+
+        gif_bytes
+    }
+}
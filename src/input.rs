@@ -0,0 +1,197 @@
+//! Queueing of key events, so they are applied at the instruction boundary within a frame at
+//! which they happened.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use wasm_bindgen::JsValue;
+
+/// The number of keys on the hex keypad.
+pub const NUM_KEYS: usize = 16;
+
+/// A key being pressed or released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// The progress of an `LD Vx, K` instruction waiting for a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyWait {
+    /// No instruction is waiting for a key.
+    Idle,
+    /// Waiting for any key to be pressed.
+    WaitingForPress,
+    /// Waiting for the pressed key to be released.
+    WaitingForRelease(u8),
+    /// The key was captured, and the instruction can complete.
+    Captured(u8),
+}
+
+impl KeyWait {
+    /// Returns true if the instruction can't complete yet.
+    pub fn is_waiting(&self) -> bool {
+        matches!(self, KeyWait::WaitingForPress | KeyWait::WaitingForRelease(_))
+    }
+
+    /// Returns the next state after `key_event`. When `wait_for_release` is true, a key is only
+    /// captured after it was pressed and then released, like the original interpreter. Otherwise
+    /// it is captured as soon as it is pressed.
+    pub fn on_key_event(self, key_event: KeyEvent, wait_for_release: bool) -> KeyWait {
+        match self {
+            KeyWait::WaitingForPress if key_event.pressed => {
+                if wait_for_release {
+                    KeyWait::WaitingForRelease(key_event.key)
+                } else {
+                    KeyWait::Captured(key_event.key)
+                }
+            }
+            KeyWait::WaitingForRelease(key) if key == key_event.key && !key_event.pressed => {
+                KeyWait::Captured(key)
+            }
+            _ => self,
+        }
+    }
+}
+
+/// An error in the input provided by the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InputError {
+    /// The key index is not a hex keypad key.
+    InvalidKey(u8),
+    /// The host key name is empty or contains whitespace.
+    InvalidHostKey(String),
+    /// The key state doesn't have a byte for every key. Holds its length.
+    InvalidKeyStateLength(usize),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::InvalidKey(key) => write!(f, "Invalid key index {}, must be 0 to F", key),
+            InputError::InvalidHostKey(name) => write!(f, "Invalid host key name `{}`", name),
+            InputError::InvalidKeyStateLength(len) => {
+                write!(f, "Invalid key state of length {}, must be of length 16", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+impl From<InputError> for JsValue {
+    fn from(err: InputError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
+
+/// When a queued key event happened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EventTime {
+    // Unknown, so the event is applied at the start of the next frame
+    Unknown,
+    // The host timestamp in milliseconds
+    Timestamp(f64),
+    // The position in the current frame, in the range [0, 1]
+    FramePosition(f32),
+}
+
+/// Key events waiting to be applied by the cpu.
+pub struct InputQueue {
+    // The events in the order they happened, with the time at which they happened
+    events: VecDeque<(KeyEvent, EventTime)>,
+    // The host timestamps of the end of the previous frame and of the current frame. The events
+    // that happened between them are spread over the current frame.
+    frame_window: Option<(f64, f64)>,
+    last_frame_timestamp: Option<f64>,
+}
+
+impl InputQueue {
+    /// Construct an empty queue.
+    pub fn new() -> Self {
+        InputQueue {
+            events: VecDeque::new(),
+            frame_window: None,
+            last_frame_timestamp: None,
+        }
+    }
+
+    /// Queue `key` being pressed or released at the host time `timestamp_ms`. Keys outside the hex
+    /// keypad are rejected.
+    pub fn push(&mut self, key: u8, pressed: bool, timestamp_ms: Option<f64>)
+        -> Result<(), InputError> {
+        if key as usize >= NUM_KEYS {
+            return Err(InputError::InvalidKey(key));
+        }
+
+        let time = match timestamp_ms {
+            Some(timestamp_ms) => EventTime::Timestamp(timestamp_ms),
+            None => EventTime::Unknown,
+        };
+        self.events.push_back((KeyEvent { key, pressed }, time));
+        Ok(())
+    }
+
+    /// Queue `key_event` at `position` in the current frame, in the range [0, 1]. Used to replay
+    /// events at exactly the position they were applied at before.
+    pub fn push_at_position(&mut self, key_event: KeyEvent, position: f32) {
+        assert!((key_event.key as usize) < NUM_KEYS, "Invalid key index");
+        self.events.push_back((key_event, EventTime::FramePosition(position)));
+    }
+
+    /// Start a new frame, which ends at the host time `timestamp_ms`. Without a timestamp, all the
+    /// queued events are applied at the start of the frame.
+    pub fn begin_frame(&mut self, timestamp_ms: Option<f64>) {
+        self.frame_window = match (self.last_frame_timestamp, timestamp_ms) {
+            (Some(start), Some(end)) if end > start => Some((start, end)),
+            // The first frame has no known start, so we only know which events happened before it
+            (_, Some(end)) => Some((end, end)),
+            (_, None) => None,
+        };
+        if timestamp_ms.is_some() {
+            self.last_frame_timestamp = timestamp_ms;
+        }
+    }
+
+    /// Returns the position in the current frame, in the range [0, 1], of the next queued event.
+    /// Returns `None` if there are no queued events that happened before the end of the frame.
+    pub fn next_event_position(&self) -> Option<f32> {
+        let &(_, time) = self.events.front()?;
+
+        match (time, self.frame_window) {
+            (EventTime::FramePosition(position), _) => Some(position),
+            (EventTime::Timestamp(timestamp), Some((_, end))) if timestamp > end => None,
+            (EventTime::Timestamp(timestamp), Some((start, end))) if timestamp > start => {
+                Some(((timestamp - start) / (end - start)) as f32)
+            }
+            _ => Some(0.0),
+        }
+    }
+
+    /// Returns the key state once all the queued events are applied to `key_state`.
+    pub fn pending_key_state(&self, key_state: [bool; NUM_KEYS]) -> [bool; NUM_KEYS] {
+        let mut pending_key_state = key_state;
+        for (key_event, _) in &self.events {
+            pending_key_state[key_event.key as usize] = key_event.pressed;
+        }
+        pending_key_state
+    }
+
+    /// Remove and return the next queued event with its position in the current frame, if it
+    /// happened at or before `position`.
+    pub fn pop_until(&mut self, position: f32) -> Option<(KeyEvent, f32)> {
+        let event_position = self.next_event_position()?;
+        if event_position <= position {
+            self.events.pop_front().map(|(event, _)| (event, event_position))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for InputQueue {
+    fn default() -> Self {
+        InputQueue::new()
+    }
+}
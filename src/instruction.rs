@@ -0,0 +1,261 @@
+//! Decoding of CHIP-8 instructions into their operation and operands. The cpu dispatches on the
+//! decoded instruction, and tools that inspect ROMs without running them (like the linter) use the
+//! same decoding.
+//! The mnemonics follow Cowgod's CHIP-8 technical reference.
+
+use std::fmt;
+
+use crate::load::HIRES_CLEAR_INSTRUCTION;
+
+/// A decoded CHIP-8 instruction. `x` and `y` are register indices, `addr` is a memory address,
+/// and `byte` and `nibble` are immediates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    /// `00E0`, or `0230` in hires programs
+    Cls,
+    /// `00EE`
+    Ret,
+    /// `1nnn`
+    Jp { addr: usize },
+    /// `2nnn`
+    Call { addr: usize },
+    /// `3xkk`
+    SeByte { x: usize, byte: u8 },
+    /// `4xkk`
+    SneByte { x: usize, byte: u8 },
+    /// `5xy0`
+    SeReg { x: usize, y: usize },
+    /// `6xkk`
+    LdByte { x: usize, byte: u8 },
+    /// `7xkk`
+    AddByte { x: usize, byte: u8 },
+    /// `8xy0`
+    LdReg { x: usize, y: usize },
+    /// `8xy1`
+    Or { x: usize, y: usize },
+    /// `8xy2`
+    And { x: usize, y: usize },
+    /// `8xy3`
+    Xor { x: usize, y: usize },
+    /// `8xy4`
+    AddReg { x: usize, y: usize },
+    /// `8xy5`
+    Sub { x: usize, y: usize },
+    /// `8xy6`
+    Shr { x: usize, y: usize },
+    /// `8xy7`
+    Subn { x: usize, y: usize },
+    /// `8xyE`
+    Shl { x: usize, y: usize },
+    /// `9xy0`
+    SneReg { x: usize, y: usize },
+    /// `Annn`
+    LdI { addr: usize },
+    /// `Bnnn`
+    JpV0 { addr: usize },
+    /// `Cxkk`
+    Rnd { x: usize, byte: u8 },
+    /// `Dxyn`
+    Drw { x: usize, y: usize, nibble: u8 },
+    /// `Ex9E`
+    Skp { x: usize },
+    /// `ExA1`
+    Sknp { x: usize },
+    /// `Fx07`
+    LdFromDt { x: usize },
+    /// `Fx0A`
+    LdKey { x: usize },
+    /// `Fx15`
+    LdDt { x: usize },
+    /// `Fx18`
+    LdSt { x: usize },
+    /// `Fx1E`
+    AddI { x: usize },
+    /// `Fx29`
+    LdFont { x: usize },
+    /// `Fx33`
+    LdBcd { x: usize },
+    /// `Fx55`
+    Store { x: usize },
+    /// `Fx65`
+    Load { x: usize },
+}
+
+impl Instruction {
+    /// Decode `instr`, where `hires` is true for hires CHIP-8 programs. Returns `None` if it isn't
+    /// a CHIP-8 instruction.
+    pub fn decode(instr: u16, hires: bool) -> Option<Instruction> {
+        let addr = decode_instr_addr(instr);
+        let x = decode_instr_x_reg(instr);
+        let y = decode_instr_y_reg(instr);
+        let byte = decode_instr_byte_imm(instr);
+        let nibble = decode_instr_nibble_imm(instr);
+
+        // The instruction type is determined by the most significant nibble
+        let instruction = match instr >> 12 {
+            0x0 => match instr {
+                0x00E0 => Instruction::Cls,
+                0x00EE => Instruction::Ret,
+                // The hires interpreter clears the screen with a machine code routine
+                HIRES_CLEAR_INSTRUCTION if hires => Instruction::Cls,
+                _ => return None,
+            },
+            0x1 => Instruction::Jp { addr },
+            0x2 => Instruction::Call { addr },
+            0x3 => Instruction::SeByte { x, byte },
+            0x4 => Instruction::SneByte { x, byte },
+            // The last nibble of 5xy0 and 9xy0 is ignored, like on the original interpreter
+            0x5 => Instruction::SeReg { x, y },
+            0x6 => Instruction::LdByte { x, byte },
+            0x7 => Instruction::AddByte { x, byte },
+            // These are arithmetic and logic operations: 8xyT, where the last nibble determines
+            // the operation type
+            0x8 => match nibble {
+                0x0 => Instruction::LdReg { x, y },
+                0x1 => Instruction::Or { x, y },
+                0x2 => Instruction::And { x, y },
+                0x3 => Instruction::Xor { x, y },
+                0x4 => Instruction::AddReg { x, y },
+                0x5 => Instruction::Sub { x, y },
+                0x6 => Instruction::Shr { x, y },
+                0x7 => Instruction::Subn { x, y },
+                0xE => Instruction::Shl { x, y },
+                _ => return None,
+            },
+            0x9 => Instruction::SneReg { x, y },
+            0xA => Instruction::LdI { addr },
+            0xB => Instruction::JpV0 { addr },
+            0xC => Instruction::Rnd { x, byte },
+            0xD => Instruction::Drw { x, y, nibble },
+            // These are keyboard flow-control instructions: ExTT, where the last byte determines
+            // the instruction type
+            0xE => match byte {
+                0x9E => Instruction::Skp { x },
+                0xA1 => Instruction::Sknp { x },
+                _ => return None,
+            },
+            // These are general peripheral devices/memory instructions: FxTT, where the last byte
+            // determines the instruction type
+            0xF => match byte {
+                0x07 => Instruction::LdFromDt { x },
+                0x0A => Instruction::LdKey { x },
+                0x15 => Instruction::LdDt { x },
+                0x18 => Instruction::LdSt { x },
+                0x1E => Instruction::AddI { x },
+                0x29 => Instruction::LdFont { x },
+                0x33 => Instruction::LdBcd { x },
+                0x55 => Instruction::Store { x },
+                0x65 => Instruction::Load { x },
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some(instruction)
+    }
+
+    /// Returns true if the instruction skips the next instruction on some condition.
+    pub fn is_skip(&self) -> bool {
+        matches!(self, Instruction::SeByte { .. } | Instruction::SneByte { .. }
+            | Instruction::SeReg { .. } | Instruction::SneReg { .. } | Instruction::Skp { .. }
+            | Instruction::Sknp { .. })
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Jp { addr } => write!(f, "JP {:#05X}", addr),
+            Instruction::Call { addr } => write!(f, "CALL {:#05X}", addr),
+            Instruction::SeByte { x, byte } => write!(f, "SE V{:X}, {:#04X}", x, byte),
+            Instruction::SneByte { x, byte } => write!(f, "SNE V{:X}, {:#04X}", x, byte),
+            Instruction::SeReg { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LdByte { x, byte } => write!(f, "LD V{:X}, {:#04X}", x, byte),
+            Instruction::AddByte { x, byte } => write!(f, "ADD V{:X}, {:#04X}", x, byte),
+            Instruction::LdReg { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI { addr } => write!(f, "LD I, {:#05X}", addr),
+            Instruction::JpV0 { addr } => write!(f, "JP V0, {:#05X}", addr),
+            Instruction::Rnd { x, byte } => write!(f, "RND V{:X}, {:#04X}", x, byte),
+            Instruction::Drw { x, y, nibble } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, nibble),
+            Instruction::Skp { x } => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp { x } => write!(f, "SKNP V{:X}", x),
+            Instruction::LdFromDt { x } => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdKey { x } => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDt { x } => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdSt { x } => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI { x } => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFont { x } => write!(f, "LD F, V{:X}", x),
+            Instruction::LdBcd { x } => write!(f, "LD B, V{:X}", x),
+            Instruction::Store { x } => write!(f, "LD [I], V{:X}", x),
+            Instruction::Load { x } => write!(f, "LD V{:X}, [I]", x),
+        }
+    }
+}
+
+/// Decodes a memory address from a CHIP-8 instruction
+fn decode_instr_addr(instr: u16) -> usize {
+    (instr & 0x0FFF) as usize
+}
+
+/// Decodes the first register from a CHIP-8 instruction
+fn decode_instr_x_reg(instr: u16) -> usize {
+    ((instr & 0x0F00) >> 8) as usize
+}
+
+/// Decodes the second register from a CHIP-8 instruction
+fn decode_instr_y_reg(instr: u16) -> usize {
+    ((instr & 0x00F0) >> 4) as usize
+}
+
+/// Decodes a byte-sized immediate from a CHIP-8 instruction
+fn decode_instr_byte_imm(instr: u16) -> u8 {
+    (instr & 0x00FF) as u8
+}
+
+/// Decodes a nibble-sized immediate from a CHIP-8 instruction
+fn decode_instr_nibble_imm(instr: u16) -> u8 {
+    (instr & 0x000F) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_hires_clear() {
+        assert_eq!(Instruction::decode(HIRES_CLEAR_INSTRUCTION, true), Some(Instruction::Cls));
+        assert_eq!(Instruction::decode(HIRES_CLEAR_INSTRUCTION, false), None);
+    }
+
+    #[test]
+    fn ignores_last_nibble_of_register_skips() {
+        for instr in [0x5AB0, 0x5AB7] {
+            assert_eq!(Instruction::decode(instr, false),
+                Some(Instruction::SeReg { x: 0xA, y: 0xB }));
+        }
+        for instr in [0x9AB0, 0x9ABF] {
+            assert_eq!(Instruction::decode(instr, false),
+                Some(Instruction::SneReg { x: 0xA, y: 0xB }));
+        }
+    }
+
+    #[test]
+    fn decodes_operands() {
+        assert_eq!(Instruction::decode(0xD12F, false),
+            Some(Instruction::Drw { x: 1, y: 2, nibble: 0xF }));
+        assert_eq!(Instruction::decode(0xB345, false), Some(Instruction::JpV0 { addr: 0x345 }));
+        assert_eq!(Instruction::decode(0x8AB8, false), None);
+        assert_eq!(Instruction::decode(0xF0FF, false), None);
+    }
+}
@@ -0,0 +1,140 @@
+//! Bindings of host keyboard keys to the hex keypad.
+//!
+//! Host keys are identified by name, case-insensitively: `0`-`9` and `A`-`Z` for the digit and
+//! letter keys, `Up`, `Down`, `Left` and `Right` for the arrow keys, and names like `Space` or
+//! `Enter` for the other keys.
+
+use wasm_bindgen::prelude::*;
+
+use crate::input::{InputError, NUM_KEYS};
+
+/// The QWERTY keyboard keys mapped to the hex keypad, in the keypad layout:
+/// ```text
+/// 1 2 3 4      1 2 3 C
+/// Q W E R  ->  4 5 6 D
+/// A S D F      7 8 9 E
+/// Z X C V      A 0 B F
+/// ```
+pub const QWERTY_KEY_LAYOUT: [(char, u8); 16] = [
+    ('1', 0x1), ('2', 0x2), ('3', 0x3), ('4', 0xC),
+    ('Q', 0x4), ('W', 0x5), ('E', 0x6), ('R', 0xD),
+    ('A', 0x7), ('S', 0x8), ('D', 0x9), ('F', 0xE),
+    ('Z', 0xA), ('X', 0x0), ('C', 0xB), ('V', 0xF),
+];
+
+/// Returns true if `name` and `other_name` name the same host key.
+fn same_key_name(name: &str, other_name: &str) -> bool {
+    name.trim().eq_ignore_ascii_case(other_name.trim())
+}
+
+#[wasm_bindgen]
+/// Maps host keys to hex keys. Several host keys can be bound to the same hex key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    // The host key names with their hex keys, in the order they were bound
+    bindings: Vec<(String, u8)>,
+}
+
+#[wasm_bindgen]
+impl Keymap {
+    /// Construct a keymap without any bindings.
+    pub fn new() -> Self {
+        Keymap {
+            bindings: Vec::new(),
+        }
+    }
+
+    /// Construct a keymap with the QWERTY layout bound.
+    pub fn qwerty() -> Self {
+        let mut keymap = Keymap::new();
+        for &(host_key, hex_key) in QWERTY_KEY_LAYOUT.iter() {
+            keymap.bindings.push((host_key.to_string(), hex_key));
+        }
+
+        keymap
+    }
+
+    /// Bind the host key named `host_key` to `hex_key`, replacing its previous binding.
+    pub fn bind(&mut self, host_key: &str, hex_key: u8) -> Result<(), InputError> {
+        if hex_key as usize >= NUM_KEYS {
+            return Err(InputError::InvalidKey(hex_key));
+        }
+
+        let host_key = host_key.trim();
+        if host_key.is_empty() || host_key.contains(char::is_whitespace) {
+            return Err(InputError::InvalidHostKey(host_key.to_string()));
+        }
+
+        match self.bindings.iter_mut().find(|(bound_key, _)| same_key_name(bound_key, host_key)) {
+            Some(binding) => binding.1 = hex_key,
+            None => self.bindings.push((host_key.to_string(), hex_key)),
+        }
+        Ok(())
+    }
+
+    /// Returns the hex key the host key named `host_key` is bound to, if any.
+    pub fn hex_key(&self, host_key: &str) -> Option<u8> {
+        self.bindings.iter().find(|(bound_key, _)| same_key_name(bound_key, host_key))
+            .map(|&(_, hex_key)| hex_key)
+    }
+
+    /// Returns the names of the host keys bound to `hex_key`, in the order they were bound.
+    pub fn host_keys(&self, hex_key: u8) -> Vec<String> {
+        self.bindings.iter().filter(|&&(_, bound_hex_key)| bound_hex_key == hex_key)
+            .map(|(host_key, _)| host_key.clone()).collect()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::new()
+    }
+}
+
+/// The host keys that are held down, with the hex keys they pressed. A hex key is held while any
+/// of the host keys bound to it is held.
+pub struct HeldHostKeys {
+    held: Vec<(String, u8)>,
+}
+
+impl HeldHostKeys {
+    /// Construct a state where no host key is held.
+    pub fn new() -> Self {
+        HeldHostKeys {
+            held: Vec::new(),
+        }
+    }
+
+    /// Record that the host key named `host_key`, bound to `hex_key`, was pressed. Returns true
+    /// if `hex_key` was pressed by it, i.e. it wasn't already held. Repeated presses of a held
+    /// host key are ignored.
+    pub fn press(&mut self, host_key: &str, hex_key: u8) -> bool {
+        if self.held.iter().any(|(held_key, _)| same_key_name(held_key, host_key)) {
+            return false;
+        }
+
+        let hex_key_held = self.held.iter().any(|&(_, held_hex_key)| held_hex_key == hex_key);
+        self.held.push((host_key.to_string(), hex_key));
+        !hex_key_held
+    }
+
+    /// Record that the host key named `host_key` was released. Returns the hex key that was
+    /// released by it, if it was the last held host key pressing that hex key.
+    pub fn release(&mut self, host_key: &str) -> Option<u8> {
+        let held_idx = self.held.iter()
+            .position(|(held_key, _)| same_key_name(held_key, host_key))?;
+        let (_, hex_key) = self.held.remove(held_idx);
+
+        if self.held.iter().any(|&(_, held_hex_key)| held_hex_key == hex_key) {
+            None
+        } else {
+            Some(hex_key)
+        }
+    }
+}
+
+impl Default for HeldHostKeys {
+    fn default() -> Self {
+        HeldHostKeys::new()
+    }
+}
@@ -1,11 +1,58 @@
 mod utils;
-
+pub mod analyzer;
+pub mod audio;
+pub mod batch;
+pub mod blocks;
+pub mod cartridge;
+pub mod decode_cache;
+pub mod dirty;
+pub mod gif_recorder;
+pub mod input;
+pub mod instruction;
+pub mod keymap;
+pub mod lint;
+pub mod load;
+pub mod metadata;
+pub mod movie;
+pub mod octo;
+pub mod patch;
+pub mod phosphor;
+pub mod quirks;
+pub mod render;
+pub mod rom_file;
+pub mod screenshot;
+pub mod terminal;
+pub mod timing;
+pub mod wav;
+
+use audio::{ToneGenerator, Waveform};
+use batch::EdgeRecorder;
+use blocks::{Backend, BlockCache};
+use cartridge::{CartridgeError, OctoCartridge};
+use decode_cache::DecodeCache;
+use dirty::{DirtyRect, DirtyRegions};
+use gif_recorder::GifRecorder;
+use input::{InputError, InputQueue, KeyWait, NUM_KEYS};
+use keymap::{HeldHostKeys, Keymap};
+use instruction::Instruction;
+use load::{LoadError, LoadOptions, LoadWarning};
+use movie::{Movie, MovieError, MovieEvent, MoviePlayer, StateHasher};
+use patch::PatchError;
+use phosphor::{PhosphorFilter, PhosphorMode};
+use quirks::Quirks;
+use render::FrameRenderer;
+use rom_file::RomFileError;
+use wav::WavRecorder;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use wasm_bindgen::prelude::*;
 
 const MEM_SIZE: usize = 4096;
 const MEM_RESERVED: usize = 512;
 const SCREEN_WIDTH: usize = 64;
 const SCREEN_HEIGHT: usize = 32;
+const HIRES_SCREEN_HEIGHT: usize = 64;
 
 #[wasm_bindgen]
 /// Represents a CHIP-8 CPU
@@ -14,6 +61,17 @@ pub struct Cpu {
     // reserved for the interpreter. We we only use them to store the font sprites needed for
     // instruction Fx29.
     memory: [u8; MEM_SIZE],
+    // The instructions decoded so far, which `step` executes without decoding them again. Entries
+    // are invalidated when the memory they were decoded from is written. Can be disabled to decode
+    // every instruction as it is executed.
+    decode_cache: Option<DecodeCache>,
+    // How `run_frame` executes instructions, and the translated blocks of the program when using
+    // the block backend.
+    backend: Backend,
+    block_cache: Option<BlockCache>,
+    // An interpreted copy of the machine, which runs in lockstep with the block backend and is
+    // compared with it after every block, when using the lockstep backend.
+    lockstep_shadow: Option<Box<Cpu>>,
 
     // Stores the call-site address, i.e. the instruction before the return address. This
     // is done so we can execute a `CALL` at the last memory address and not have to deal with
@@ -39,25 +97,72 @@ pub struct Cpu {
     st_register: u8,
 
     // Internal screen buffer which is updated by draw/clear instructions. The screen is
-    // monochromatic: a pixel is `true` if it is turned on.
-    screen_buffer: [bool; SCREEN_WIDTH * SCREEN_HEIGHT],
-    // Screen buffer dirty flag. This flag is set whenever the internal buffer is changed. The
-    // actual display must update and then clear this flag.
-    screen_dirty: bool,
+    // monochromatic: a pixel is `true` if it is turned on. Its height depends on the screen mode.
+    screen_buffer: Vec<bool>,
+    screen_height: usize,
+    // The screen buffer regions changed by draw/clear instructions. The screen buffer is dirty if
+    // there are any. The actual display must update and then clear these regions.
+    dirty_regions: DirtyRegions,
+    // Renders the screen buffer to an RGBA framebuffer on request.
+    frame_renderer: FrameRenderer,
+    // Blends the screen buffers of recent frames at the end of every `run_frame`, if flicker
+    // reduction was enabled.
+    phosphor_filter: Option<PhosphorFilter>,
+    // Captures the screen at the end of every `run_frame` while a GIF recording is in progress.
+    gif_recorder: Option<GifRecorder>,
 
     // Current keyboard state
-    key_state: [bool; 16],
-    // Waiting for key press flag. This flag is set whenever the cpu is waiting for a captured key
-    // press. The flag is reset by the instruction which set the flag in the first place, after
-    // receiving the captured key.
-    waiting_for_keypress: bool,
-    // The key that was captured.
-    captured_key: u8,
+    key_state: [bool; NUM_KEYS],
+    // Key events that were not applied to the keyboard state yet
+    input_queue: InputQueue,
+    // The bindings of host keys to hex keys, and the bound host keys that are held down
+    keymap: Keymap,
+    held_host_keys: HeldHostKeys,
+    // The progress of the `LD Vx, K` instruction waiting for a key. The state is advanced by the
+    // key events, and is reset by the instruction which started waiting, after the key was
+    // captured. While it is waiting `step` does nothing useful.
+    key_wait: KeyWait,
+    // Waiting for v-blank flag. This flag is set by the draw instruction when `display_wait` is
+    // enabled, and is reset by the next call to `tick_clock`. While it is set `step` does nothing.
+    waiting_for_vblank: bool,
 
     // Options that change how some instructions operate. Used to emulate ROMs that depend on
     // interpreter quirks from different platforms.
-    original_shift: bool, 
-    original_mem_acc: bool,
+    quirks: Quirks,
+    // Where the rom was loaded, where execution started, and the screen mode.
+    load_options: LoadOptions,
+    // The problems found with the rom when it was loaded, which didn't prevent loading it.
+    load_warnings: Vec<LoadWarning>,
+
+    // The random number generator used by the `RND` instruction, and the seed it was last seeded
+    // with. A movie records the seed so the random numbers can be replayed.
+    rng: StdRng,
+    rng_seed: u32,
+
+    // The number of instructions `run_frame` executes per second, when not using VIP timing.
+    clock_rate_hz: u32,
+    // When set, `run_frame` executes as many instructions as would fit in a frame on the COSMAC
+    // VIP, according to the cycle cost of each instruction.
+    vip_timing: bool,
+    // Machine cycles left over from the previous frame when using VIP timing. An instruction that
+    // doesn't fit in the remaining cycles of a frame is still executed, so this can be negative.
+    vip_cycle_balance: i64,
+
+    // Renders the tone to PCM samples during `run_frame`, if audio generation was enabled.
+    tone_generator: Option<ToneGenerator>,
+    // Collects the rendered samples while an audio recording is in progress.
+    audio_recorder: Option<WavRecorder>,
+
+    // The number of frames run so far
+    frame_count: u32,
+    // The movie being recorded, with the key events applied so far. The frame count and final
+    // state are filled in when the recording stops.
+    movie_recording: Option<Movie>,
+    // Replays the key events of a movie instead of the host key events, during movie playback.
+    movie_player: Option<MoviePlayer>,
+    // Records where in every `run_frame` the tone started or stopped and the cpu started waiting
+    // for a key, if enabled. Used by `CpuBatch`.
+    edge_recorder: Option<EdgeRecorder>,
 }
 
 #[wasm_bindgen]
@@ -87,8 +192,14 @@ impl Cpu {
             0xF0, 0x80, 0xF0, 0x80, 0x80, // 'F'
         ]);
 
+        let rng_seed = rand::random();
+
         Cpu {
             memory: initial_memory,
+            decode_cache: Some(DecodeCache::new(MEM_SIZE)),
+            backend: Backend::Interpreter,
+            block_cache: None,
+            lockstep_shadow: None,
             call_stack: [0; 16],
             v_registers: [0; 16],
             i_register: 0,
@@ -96,27 +207,86 @@ impl Cpu {
             sp_register: 0,
             dt_register: 0,
             st_register: 0,
-            screen_buffer: [false; SCREEN_WIDTH * SCREEN_HEIGHT],
-            screen_dirty: false,
-            key_state: [false; 16],
-            waiting_for_keypress: false,
-            captured_key: 0,
-            original_shift: false,
-            original_mem_acc: false
+            screen_buffer: vec![false; SCREEN_WIDTH * SCREEN_HEIGHT],
+            screen_height: SCREEN_HEIGHT,
+            dirty_regions: DirtyRegions::new(),
+            frame_renderer: FrameRenderer::new(),
+            phosphor_filter: None,
+            gif_recorder: None,
+            key_state: [false; NUM_KEYS],
+            input_queue: InputQueue::new(),
+            keymap: Keymap::qwerty(),
+            held_host_keys: HeldHostKeys::new(),
+            key_wait: KeyWait::Idle,
+            waiting_for_vblank: false,
+            quirks: Quirks::default(),
+            load_options: LoadOptions::standard(),
+            load_warnings: Vec::new(),
+            rng: StdRng::seed_from_u64(rng_seed as u64),
+            rng_seed,
+            clock_rate_hz: timing::DEFAULT_CLOCK_RATE_HZ,
+            vip_timing: false,
+            vip_cycle_balance: 0,
+            tone_generator: None,
+            audio_recorder: None,
+            frame_count: 0,
+            movie_recording: None,
+            movie_player: None,
+            edge_recorder: None,
         }
     }
 
     /// Construct a CHIP-8 cpu at the initial entry state, with rom bytes loaded at the entry point
     /// in memory.
-    pub fn with_rom(rom: &[u8]) -> Self {
-        assert!(rom.len() <= MEM_SIZE - MEM_RESERVED, "ROM file too large to fit in memory");
+    /// Fails if the rom is empty, doesn't fit in memory or doesn't contain any instructions.
+    pub fn with_rom(rom: &[u8]) -> Result<Cpu, LoadError> {
+        Cpu::with_rom_at(rom, &LoadOptions::standard())
+    }
+
+    /// Construct a CHIP-8 cpu at the initial entry state, with rom bytes loaded at the load
+    /// address in memory. Execution starts at the entry point, and the screen mode is set, as
+    /// `load_options` specify.
+    /// Fails if the layout is invalid, or the rom is empty, doesn't fit in memory after the load
+    /// address or doesn't contain any instructions.
+    pub fn with_rom_at(rom: &[u8], load_options: &LoadOptions) -> Result<Cpu, LoadError> {
+        let load_warnings = load::validate_rom(rom, load_options)?;
+        let load_address = load_options.load_address;
 
         let mut init_cpu = Cpu::new();
 
-        // Copy rom bytes to the memory at the entry point (right after the reserved memory)
-        init_cpu.memory[MEM_RESERVED..MEM_RESERVED + rom.len()].copy_from_slice(rom);
+        // Copy rom bytes to the memory at the load address (usually right after the reserved
+        // memory)
+        init_cpu.memory[load_address..load_address + rom.len()].copy_from_slice(rom);
+        init_cpu.pc_register = load_options.entry_point;
+
+        if load_options.hires {
+            init_cpu.screen_buffer = vec![false; SCREEN_WIDTH * HIRES_SCREEN_HEIGHT];
+            init_cpu.screen_height = HIRES_SCREEN_HEIGHT;
+        }
+        init_cpu.load_options = *load_options;
+        init_cpu.load_warnings = load_warnings;
+
+        Ok(init_cpu)
+    }
+
+    /// Construct a CHIP-8 cpu at the initial entry state, with the rom decoded from `rom_file`
+    /// loaded at the entry point in memory. The file can hold the raw rom bytes, a zip archive,
+    /// Intel HEX or hex text. For a zip archive, `entry_name` chooses the entry, otherwise the
+    /// archive must hold a single rom.
+    /// Fails if the file is malformed, or the rom can't be loaded.
+    pub fn with_rom_file(rom_file: &[u8], entry_name: Option<String>)
+        -> Result<Cpu, RomFileError> {
+        let rom = rom_file::decode_rom_file(rom_file, entry_name)?;
+        Cpu::with_rom(&rom).map_err(RomFileError::Load)
+    }
 
-        init_cpu
+    /// Construct a CHIP-8 cpu at the initial entry state, with `rom` patched by the IPS or BPS
+    /// patch `patch` loaded at the entry point in memory.
+    /// Fails if the patch is malformed or was made for a different rom, or the patched rom can't
+    /// be loaded.
+    pub fn with_patched_rom(rom: &[u8], patch: &[u8]) -> Result<Cpu, PatchError> {
+        let patched_rom = patch::apply_patch(rom, patch)?;
+        Cpu::with_rom(&patched_rom).map_err(PatchError::Load)
     }
 
     /// Construct a CHIP-8 cou at the initial entry state, with rom bytes loaded at the entry point
@@ -125,101 +295,168 @@ impl Cpu {
     /// i.e. the instruction shifts Vy instead of Vx.
     /// When `original_mem_acc` is true, the original behaviour of the load/store instructions is
     /// used, i.e. the instructions increment the I register by the number of registers used.
-    pub fn with_rom_and_options(rom: &[u8], original_shift: bool, original_mem_acc: bool) -> Self {
-        let mut init_cpu = Cpu::with_rom(rom);
-        init_cpu.original_shift = original_shift;
-        init_cpu.original_mem_acc = original_mem_acc;
+    /// When `display_wait` is true, the original behaviour of the draw instruction is used, i.e.
+    /// the instruction waits for the next v-blank (the next `tick_clock`) before execution
+    /// continues.
+    /// When `wait_for_release` is true, the original behaviour of the wait for key instruction is
+    /// used, i.e. the instruction waits for a key to be pressed and then released. Otherwise it
+    /// completes as soon as a key is pressed.
+    /// Fails if the rom can't be loaded, like `with_rom`.
+    pub fn with_rom_and_options(rom: &[u8], original_shift: bool, original_mem_acc: bool,
+        display_wait: bool, wait_for_release: bool) -> Result<Cpu, LoadError> {
+        Cpu::with_rom_and_options_at(rom, original_shift, original_mem_acc, display_wait,
+            wait_for_release, &LoadOptions::standard())
+    }
+
+    /// Like `with_rom_and_options`, where the rom is loaded, execution starts and the screen mode
+    /// is set as `load_options` specify.
+    pub fn with_rom_and_options_at(rom: &[u8], original_shift: bool, original_mem_acc: bool,
+        display_wait: bool, wait_for_release: bool, load_options: &LoadOptions)
+        -> Result<Cpu, LoadError> {
+        Cpu::with_rom_and_quirks_at(rom, Quirks {
+            original_shift,
+            original_mem_acc,
+            display_wait,
+            wait_for_release,
+        }, load_options)
+    }
+
+    /// Construct a CHIP-8 cpu that replays the movie encoded in `movie_text`, with rom bytes
+    /// loaded at the entry point in memory. The quirks, timing and random seed are set to those
+    /// the movie was recorded with, and host key events are ignored.
+    /// Fails if the movie is malformed or was recorded with a different ROM, or the rom can't be
+    /// loaded.
+    pub fn with_movie(rom: &[u8], movie_text: &str) -> Result<Cpu, MovieError> {
+        let movie = Movie::parse(movie_text)?;
+
+        let mut init_cpu = Cpu::with_rom_and_quirks_at(rom, movie.quirks, &movie.load_options)
+            .map_err(MovieError::Load)?;
+        init_cpu.set_rng_seed(movie.rng_seed);
+        init_cpu.set_clock_rate(movie.clock_rate_hz);
+        init_cpu.set_vip_timing(movie.vip_timing);
+        if init_cpu.state_hash() != movie.initial_state_hash {
+            return Err(MovieError::RomMismatch);
+        }
+
+        init_cpu.movie_player = Some(MoviePlayer::new(movie));
+        Ok(init_cpu)
+    }
+
+    /// Construct a CHIP-8 cpu from the Octo cartridge `cartridge_gif`, with the program bytes
+    /// loaded at the entry point in memory. The quirks, clock rate and palette are set from the
+    /// cartridge's options.
+    /// Fails if the image isn't a cartridge, or its program isn't made of byte literals.
+    pub fn with_octo_cartridge(cartridge_gif: &[u8]) -> Result<Cpu, CartridgeError> {
+        let cartridge = OctoCartridge::parse(cartridge_gif)?;
+        let metadata = cartridge.metadata();
+
+        let mut init_cpu = Cpu::with_rom_and_quirks(cartridge.rom_bytes(),
+            metadata.quirks.unwrap_or_default()).map_err(CartridgeError::Load)?;
+        if let Some(clock_rate_hz) = metadata.speed {
+            init_cpu.set_clock_rate(clock_rate_hz);
+        }
+        // The palette was validated when the cartridge was decoded
+        let palette = metadata.palette_colors().expect("Invalid cartridge palette");
+        for (index, &color) in palette.iter().enumerate() {
+            init_cpu.set_palette_color(index, color);
+        }
 
-        init_cpu
+        Ok(init_cpu)
     }
 
     /// Decode and execute one instruction.
-    /// It is the responsibility of the caller to check the `waiting_for_keypress` flag. If it is
-    /// set, The caller should only call `step` again after calling `set_captured_key`.
-    /// It is the responsibility of the caller to check the `screen_dirty` flag and update the
-    /// display if needed.
+    /// If the cpu is waiting for a key, the wait for key instruction is executed again, and only
+    /// completes once a key was captured from the key events.
+    /// It is the responsibility of the caller to check the screen dirty flag (or the dirty
+    /// rectangles) and update the display if needed.
+    /// If the cpu is waiting for a v-blank, this does nothing. Callers can check
+    /// `is_waiting_for_vblank` to stop stepping until the next `tick_clock`.
     pub fn step(&mut self) {
-        assert!(self.pc_register + 1 < MEM_SIZE, "PC out of memory bounds");
+        if self.waiting_for_vblank {
+            return;
+        }
 
-        // Instructions are 2 bytes, big-endian.
-        let instruction: u16 =
-            ((self.memory[self.pc_register] as u16) << 8) | (self.memory[self.pc_register + 1] as u16);
+        // Fetch and decode instruction
+        let hires = self.load_options.hires;
+        let decoded = match &mut self.decode_cache {
+            Some(decode_cache) => decode_cache.decode(&self.memory, self.pc_register, hires),
+            None => Instruction::decode(self.fetch_instruction(), hires),
+        };
 
-        // println!("Executing instruction {:x} at address {:#x}", instruction, self.pc_register);
-        
-        // Decode instruction. The instruction type is determined by the most significant nibble.
-        match (instruction & 0xF000) >> 12 {
-            0x0 => {
-                if instruction == 0x00E0 {
-                    self.instr_00e0(instruction);
-                } else if instruction == 0x00EE {
-                    self.instr_00ee(instruction);
-                } else {
-                    panic!("Unknown SYS 0nnn instruction");
-                }
-            }
-            0x1 => self.instr_1nnn(instruction),
-            0x2 => self.instr_2nnn(instruction),
-            0x3 => self.instr_3xkk(instruction),
-            0x4 => self.instr_4xkk(instruction),
-            0x5 => self.instr_5xy0(instruction),
-            0x6 => self.instr_6xkk(instruction),
-            0x7 => self.instr_7xkk(instruction),
-            0x8 => {
-                // These are arithmetic and logic operations: 8xyT, where the last nibble determines
-                // the operation type. 
-                match instruction & 0xF {
-                    0x0 => self.instr_8xy0(instruction),
-                    0x1 => self.instr_8xy1(instruction),
-                    0x2 => self.instr_8xy2(instruction),
-                    0x3 => self.instr_8xy3(instruction),
-                    0x4 => self.instr_8xy4(instruction),
-                    0x5 => self.instr_8xy5(instruction),
-                    0x6 => self.instr_8xy6(instruction),
-                    0x7 => self.instr_8xy7(instruction),
-                    0xE => self.instr_8xye(instruction),
-                    _ => panic!("Unknown 8xyT instruction type"),
-                }
-            }
-            0x9 => self.instr_9xy0(instruction),
-            0xA => self.instr_annn(instruction),
-            0xB => self.instr_bnnn(instruction),
-            0xC => self.instr_cxkk(instruction),
-            0xD => self.instr_dxyn(instruction),
-            0xE => {
-                // These are keyboard flow-control instructions: ExTT, where the last byte
-                // determines the instruction type.
-                match instruction & 0xFF {
-                    0x9E => self.instr_ex9e(instruction),
-                    0xA1 => self.instr_exa1(instruction),
-                    _ => panic!("Unknown ExTT instruction type")
-                }
-            }
-            0xF => {
-                // These are general peripheral devices/memory instructions: FxTT, where the last
-                // byte determines the instruction type.
-                match instruction & 0xFF {
-                    0x07 => self.instr_fx07(instruction),
-                    0x0A => self.instr_fx0a(instruction),
-                    0x15 => self.instr_fx15(instruction),
-                    0x18 => self.instr_fx18(instruction),
-                    0x1E => self.instr_fx1e(instruction),
-                    0x29 => self.instr_fx29(instruction),
-                    0x33 => self.instr_fx33(instruction),
-                    0x55 => self.instr_fx55(instruction),
-                    0x65 => self.instr_fx65(instruction),
-                    _ => panic!("Unknown FxTT instruction type"),
-                }
-            }
-            _ => unreachable!(),
+        let instruction = decoded.unwrap_or_else(|| panic!("Unknown instruction {:04X} at {:#05X}",
+            self.fetch_instruction(), self.pc_register));
+
+        // println!("Executing instruction {} at address {:#x}", instruction, self.pc_register);
+
+        self.execute(instruction);
+    }
+
+    /// Run a single 60HZ frame: tick the cpu timers and then execute the instructions that fit in
+    /// the frame. With the COSMAC VIP timing the timers are instead ticked partway through the
+    /// frame, between the instructions, where the display interrupt fires on the VIP.
+    /// The cpu idles while it is blocked waiting for a v-blank or a key press.
+    /// All the queued key events are applied at the start of the frame.
+    pub fn run_frame(&mut self) {
+        self.run_frame_until(None);
+    }
+
+    /// Like `run_frame`, where `timestamp_ms` is the host time at which the frame is run (e.g. the
+    /// `requestAnimationFrame` timestamp). Key events queued with timestamps between the previous
+    /// frame and this one are applied at the matching instruction boundary in this frame, so quick
+    /// taps are not lost and the order of presses and releases is kept.
+    pub fn run_frame_at(&mut self, timestamp_ms: f64) {
+        self.run_frame_until(Some(timestamp_ms));
+    }
+
+    /// Sets the number of instructions `run_frame` executes per second.
+    pub fn set_clock_rate(&mut self, clock_rate_hz: u32) {
+        self.clock_rate_hz = clock_rate_hz;
+    }
+
+    /// When `vip_timing` is true, `run_frame` ignores the clock rate and instead executes as many
+    /// instructions as would fit in a frame on the COSMAC VIP.
+    pub fn set_vip_timing(&mut self, vip_timing: bool) {
+        self.vip_timing = vip_timing;
+        self.vip_cycle_balance = 0;
+    }
+
+    /// Returns the byte at `address` in memory.
+    pub fn peek(&self, address: usize) -> u8 {
+        assert!(address < MEM_SIZE, "Peeked address is out of bounds");
+        self.memory[address]
+    }
+
+    /// Write `value` to `address` in memory, e.g. to patch a running program. The instructions at
+    /// the address are decoded again when they are executed.
+    pub fn poke(&mut self, address: usize, value: u8) {
+        assert!(address < MEM_SIZE, "Poked address is out of bounds");
+        self.memory[address] = value;
+        self.invalidate_decoded(address, 1);
+
+        if let Some(shadow) = &mut self.lockstep_shadow {
+            shadow.poke(address, value);
         }
+    }
 
-        // Increment PC
-        self.pc_register += 2;
+    /// Sets how `run_frame` executes instructions. The block backend is much faster at high clock
+    /// rates, and the lockstep backend checks it against the interpreter.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.block_cache = match backend {
+            Backend::Interpreter => None,
+            Backend::Blocks | Backend::Lockstep => Some(BlockCache::new(MEM_SIZE)),
+        };
+        self.lockstep_shadow = match backend {
+            Backend::Lockstep => Some(Box::new(self.interpreted_copy())),
+            Backend::Interpreter | Backend::Blocks => None,
+        };
     }
 
     /// Tick internal cpu timers. Must be called at 60HZ.
     pub fn tick_clock(&mut self) {
+        // A tick is a v-blank, so a draw instruction that is waiting for one can now complete
+        self.waiting_for_vblank = false;
+
         if self.dt_register > 0 {
             self.dt_register -= 1;
         }
@@ -227,6 +464,10 @@ impl Cpu {
         if self.st_register > 0 {
             self.st_register -= 1;
         }
+
+        if let Some(shadow) = &mut self.lockstep_shadow {
+            shadow.tick_clock();
+        }
     }
 
     /// Get a pointer to the screen buffer memory, used from the JS side to render the screen.
@@ -234,62 +475,841 @@ impl Cpu {
         self.screen_buffer.as_ptr()
     }
 
+    /// Returns the width of the screen in pixels.
+    pub fn screen_width(&self) -> usize {
+        SCREEN_WIDTH
+    }
+
+    /// Returns the problems found with the rom when it was loaded, which didn't prevent loading
+    /// it.
+    pub fn load_warnings(&self) -> Vec<String> {
+        self.load_warnings.iter().map(|warning| warning.to_string()).collect()
+    }
+
+    /// Returns the height of the screen in pixels.
+    pub fn screen_height(&self) -> usize {
+        self.screen_height
+    }
+
+    /// Sets the framebuffer colour at palette `index` to `rgb`, in 0xRRGGBB format. Index 0 is the
+    /// background colour and index 1 is the colour of pixels that are turned on. Indices 2 and 3
+    /// are reserved for multi-plane displays.
+    pub fn set_palette_color(&mut self, index: usize, rgb: u32) {
+        self.frame_renderer.set_palette_color(index, rgb);
+    }
+
+    /// Sets the integer scale factor of the framebuffer.
+    pub fn set_render_scale(&mut self, scale: usize) {
+        self.frame_renderer.set_scale(scale);
+    }
+
+    /// Render the screen buffer to the RGBA framebuffer. If flicker reduction is enabled, the
+    /// intensity buffer is rendered instead.
+    pub fn render_framebuffer(&mut self) {
+        if let Some(phosphor_filter) = &self.phosphor_filter {
+            let intensity = phosphor_filter.intensity();
+            self.frame_renderer.render_blended(SCREEN_WIDTH, self.screen_height,
+                |x, y| intensity[y * SCREEN_WIDTH + x]);
+        } else {
+            let screen_buffer = &self.screen_buffer;
+            self.frame_renderer.render(SCREEN_WIDTH, self.screen_height,
+                |x, y| screen_buffer[y * SCREEN_WIDTH + x] as usize);
+        }
+    }
+
+    /// Encode the screen buffer as a PNG image, using the framebuffer palette. Every pixel becomes
+    /// a `scale` by `scale` square, so the image stays sharp when it is viewed.
+    pub fn screenshot_png(&self, scale: usize) -> Vec<u8> {
+        screenshot::encode_png(&self.frame_renderer, scale, SCREEN_WIDTH, self.screen_height,
+            |x, y| self.screen_buffer[y * SCREEN_WIDTH + x])
+    }
+
+    /// Start recording the screen at the end of every `run_frame`, using the framebuffer palette.
+    /// Every pixel becomes a `scale` by `scale` square. Any recording in progress is discarded.
+    pub fn start_gif_recording(&mut self, scale: usize) {
+        self.gif_recorder = Some(GifRecorder::new(SCREEN_WIDTH, self.screen_height, scale,
+            *self.frame_renderer.palette()));
+    }
+
+    /// Returns true if a GIF recording is in progress.
+    pub fn is_recording_gif(&self) -> bool {
+        self.gif_recorder.is_some()
+    }
+
+    /// Stop the GIF recording in progress and return it encoded as an animated GIF.
+    pub fn stop_gif_recording(&mut self) -> Vec<u8> {
+        self.gif_recorder.take().expect("No GIF recording in progress").to_gif_bytes()
+    }
+
+    /// Enable flicker reduction, where a pixel keeps `decay` of its intensity every frame after it
+    /// is turned off. `decay` must be in the range [0, 1).
+    pub fn set_phosphor_decay(&mut self, decay: f32) {
+        self.set_phosphor_mode(PhosphorMode::Decay(decay));
+    }
+
+    /// Enable flicker reduction, where a pixel is lit if it was turned on in any of the last
+    /// `num_frames` frames.
+    pub fn set_phosphor_or_frames(&mut self, num_frames: usize) {
+        self.set_phosphor_mode(PhosphorMode::OrFrames(num_frames));
+    }
+
+    /// Disable flicker reduction.
+    pub fn disable_phosphor(&mut self) {
+        self.phosphor_filter = None;
+    }
+
+    /// Returns true if flicker reduction is enabled. The intensity buffer changes every frame
+    /// while it is enabled, even if the screen dirty flag is not set.
+    pub fn is_phosphor_enabled(&self) -> bool {
+        self.phosphor_filter.is_some()
+    }
+
+    /// Get a pointer to the intensity buffer memory, which holds how lit every pixel is in the
+    /// range [0, 1] after flicker reduction. Flicker reduction must be enabled.
+    pub fn get_intensity_buffer(&self) -> *const f32 {
+        self.intensity_buffer().as_ptr()
+    }
+
+    /// Get a pointer to the RGBA framebuffer memory, used from the JS side to render the screen.
+    /// The framebuffer is only updated by `render_framebuffer`.
+    pub fn get_framebuffer(&self) -> *const u8 {
+        self.frame_renderer.rgba().as_ptr()
+    }
+
+    /// Returns the width of the RGBA framebuffer in pixels.
+    pub fn get_framebuffer_width(&self) -> usize {
+        self.frame_renderer.width()
+    }
+
+    /// Returns the height of the RGBA framebuffer in pixels.
+    pub fn get_framebuffer_height(&self) -> usize {
+        self.frame_renderer.height()
+    }
+
     /// Returns whether or not the screen dirty, and if it is, sets it to false.
+    /// This also clears the dirty rectangles.
     pub fn handle_screen_dirty_flag(&mut self) -> bool {
-        let captured_flag = self.screen_dirty;
-        self.screen_dirty = false;
+        let captured_flag = !self.dirty_regions.is_empty();
+        self.dirty_regions.clear();
         captured_flag
     }
 
-    /// Update the internal key state to the provided key state.
-    /// `new_key_state` must be of length 16.
-    pub fn update_key_state(&mut self, new_key_state: &[u8]) {
-        assert!(new_key_state.len() == 16);
-        
-        assert!(std::mem::size_of::<bool>() == 1);
-        // `wasm_bindgen` doesn't support passing boolean arrays, so we must pass it as a byte array
-        // and then convert it back.
-        let new_key_state = unsafe {
-            std::slice::from_raw_parts(new_key_state.as_ptr() as *const bool, 16)
+    /// Returns the screen regions changed since the display was last updated, and clears them.
+    /// The regions are flattened to groups of 4 numbers: x, y, width and height. The screen is
+    /// not dirty anymore afterwards.
+    pub fn take_dirty_rects(&mut self) -> Vec<u32> {
+        let flattened_rects = self.dirty_regions.rects().iter()
+            .flat_map(|rect| [rect.x as u32, rect.y as u32, rect.width as u32,
+                rect.height as u32])
+            .collect();
+        self.dirty_regions.clear();
+        flattened_rects
+    }
+
+    /// Update the internal key state to the provided key state, at the start of the next frame.
+    /// `new_key_state` must be of length 16 (or an error is returned), and a key is pressed if its
+    /// byte is not zero.
+    /// `wasm_bindgen` doesn't support passing boolean arrays, so we take a byte array.
+    /// Only the keys that differ from the state requested last (including key events that are
+    /// still queued) are pressed or released.
+    pub fn update_key_state(&mut self, new_key_state: &[u8]) -> Result<(), InputError> {
+        if new_key_state.len() != NUM_KEYS {
+            return Err(InputError::InvalidKeyStateLength(new_key_state.len()));
+        }
+
+        let requested_key_state = self.requested_key_state();
+        for (key, &key_byte) in new_key_state.iter().enumerate() {
+            let pressed = key_byte != 0;
+            if pressed != requested_key_state[key] {
+                self.queue_host_key_event(key as u8, pressed, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue a press of the hex `key` that happened at the host time `timestamp_ms`. It is applied
+    /// by `run_frame_at` at the matching instruction boundary.
+    pub fn key_down(&mut self, key: u8, timestamp_ms: f64) -> Result<(), InputError> {
+        self.queue_host_key_event(key, true, Some(timestamp_ms))
+    }
+
+    /// Queue a release of the hex `key` that happened at the host time `timestamp_ms`. It is
+    /// applied by `run_frame_at` at the matching instruction boundary.
+    pub fn key_up(&mut self, key: u8, timestamp_ms: f64) -> Result<(), InputError> {
+        self.queue_host_key_event(key, false, Some(timestamp_ms))
+    }
+
+    /// Sets the bindings of host keys to hex keys used by `host_key_down` and `host_key_up`. The
+    /// QWERTY layout is bound by default.
+    pub fn set_keymap(&mut self, keymap: &Keymap) {
+        self.keymap = keymap.clone();
+    }
+
+    /// Queue a press of the host key named `host_key` that happened at the host time
+    /// `timestamp_ms`, which presses the hex key it is bound to. Returns false if the host key
+    /// isn't bound. Repeated presses of a held key are ignored.
+    pub fn host_key_down(&mut self, host_key: &str, timestamp_ms: f64)
+        -> Result<bool, InputError> {
+        let hex_key = match self.keymap.hex_key(host_key) {
+            Some(hex_key) => hex_key,
+            None => return Ok(false),
         };
 
-        &mut self.key_state[..].copy_from_slice(new_key_state.into());
+        if self.held_host_keys.press(host_key, hex_key) {
+            self.queue_host_key_event(hex_key, true, Some(timestamp_ms))?;
+        }
+        Ok(true)
     }
 
-    /// Returns true if the cpu is waiting for a captured key press.
+    /// Queue a release of the host key named `host_key` that happened at the host time
+    /// `timestamp_ms`. The hex key it pressed is released once no other held host key is bound to
+    /// it. Returns false if the host key isn't bound.
+    pub fn host_key_up(&mut self, host_key: &str, timestamp_ms: f64) -> Result<bool, InputError> {
+        if let Some(hex_key) = self.held_host_keys.release(host_key) {
+            self.queue_host_key_event(hex_key, false, Some(timestamp_ms))?;
+        }
+        Ok(self.keymap.hex_key(host_key).is_some())
+    }
+
+    /// Returns true if the cpu is blocked on a wait for key instruction, until a key is captured
+    /// from the key events.
     pub fn is_waiting_for_keypress(&self) -> bool {
-        self.waiting_for_keypress
+        self.key_wait.is_waiting()
     }
 
-    /// Sets the key that was captured in the last keypress.
-    pub fn set_captured_key(&mut self, captured_key: u8) {
-        assert!(self.waiting_for_keypress,
-            "Received a captured key even though we are not waiting for a key press");
-        
-        self.captured_key = captured_key;
+    /// Returns true if the cpu is blocked on a draw instruction waiting for the next v-blank.
+    pub fn is_waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
     }
 
     /// Returns true if the emulator should play a tone
     pub fn should_play_tone(&self) -> bool {
         self.st_register > 0
     }
+
+    /// Enable rendering the tone to mono PCM samples at `sample_rate`. After every `run_frame`,
+    /// the samples of that frame are available through `get_audio_buffer`.
+    pub fn enable_audio(&mut self, sample_rate: u32) {
+        self.tone_generator = Some(ToneGenerator::new(sample_rate));
+    }
+
+    /// Sets the waveform of the rendered tone. Audio must be enabled.
+    pub fn set_audio_waveform(&mut self, waveform: Waveform) {
+        self.tone_generator_mut().set_waveform(waveform);
+    }
+
+    /// Sets the frequency of the rendered tone. Audio must be enabled.
+    pub fn set_audio_frequency(&mut self, frequency_hz: f32) {
+        self.tone_generator_mut().set_frequency(frequency_hz);
+    }
+
+    /// Sets the volume of the rendered tone, in the range [0, 1]. Audio must be enabled.
+    pub fn set_audio_volume(&mut self, volume: f32) {
+        self.tone_generator_mut().set_volume(volume);
+    }
+
+    /// Sets the attack and release times of the rendered tone, in milliseconds. Audio must be
+    /// enabled.
+    pub fn set_audio_envelope(&mut self, attack_ms: f32, release_ms: f32) {
+        self.tone_generator_mut().set_envelope(attack_ms / 1000.0, release_ms / 1000.0);
+    }
+
+    /// Get a pointer to the samples rendered in the last frame, used from the JS side to play the
+    /// audio. Audio must be enabled.
+    pub fn get_audio_buffer(&self) -> *const f32 {
+        self.audio_samples().as_ptr()
+    }
+
+    /// Returns the number of samples rendered in the last frame. Audio must be enabled.
+    pub fn get_audio_buffer_len(&self) -> usize {
+        self.audio_samples().len()
+    }
+
+    /// Start recording the rendered samples of every frame. Any recording in progress is
+    /// discarded. Audio must be enabled.
+    pub fn start_audio_recording(&mut self) {
+        let sample_rate = self.tone_generator_mut().sample_rate();
+        self.audio_recorder = Some(WavRecorder::new(sample_rate));
+    }
+
+    /// Returns true if an audio recording is in progress.
+    pub fn is_recording_audio(&self) -> bool {
+        self.audio_recorder.is_some()
+    }
+
+    /// Stop the audio recording in progress and return it encoded as a WAV file.
+    pub fn stop_audio_recording(&mut self) -> Vec<u8> {
+        self.audio_recorder.take().expect("No audio recording in progress").to_wav_bytes()
+    }
+
+    /// Reseed the random number generator used by the `RND` instruction.
+    pub fn set_rng_seed(&mut self, seed: u32) {
+        self.rng = StdRng::seed_from_u64(seed as u64);
+        self.rng_seed = seed;
+    }
+
+    /// Returns the seed the random number generator was last seeded with.
+    pub fn rng_seed(&self) -> u32 {
+        self.rng_seed
+    }
+
+    /// Returns the number of frames run so far.
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
+    }
+
+    /// Start recording a movie of the session: every key event applied by `run_frame`, with the
+    /// quirks, timing and random seed needed to replay it. Must be called before the first frame,
+    /// and the timing must not be changed during the recording.
+    pub fn start_movie_recording(&mut self) {
+        assert!(self.frame_count == 0, "A movie must be recorded from the first frame");
+        assert!(self.movie_player.is_none(), "Can't record a movie during movie playback");
+
+        self.movie_recording = Some(Movie {
+            initial_state_hash: self.state_hash(),
+            rng_seed: self.rng_seed,
+            quirks: self.quirks,
+            load_options: self.load_options,
+            clock_rate_hz: self.clock_rate_hz,
+            vip_timing: self.vip_timing,
+            events: Vec::new(),
+            num_frames: 0,
+            final_state_hash: 0,
+        });
+    }
+
+    /// Returns true if a movie recording is in progress.
+    pub fn is_recording_movie(&self) -> bool {
+        self.movie_recording.is_some()
+    }
+
+    /// Stop the movie recording in progress and return it encoded as text.
+    pub fn stop_movie_recording(&mut self) -> String {
+        let mut movie = self.movie_recording.take().expect("No movie recording in progress");
+        movie.num_frames = self.frame_count;
+        movie.final_state_hash = self.state_hash();
+
+        movie.to_text()
+    }
+
+    /// Returns true if the cpu is replaying a movie.
+    pub fn is_playing_movie(&self) -> bool {
+        self.movie_player.is_some()
+    }
+
+    /// Returns true if all the frames of the movie being replayed were run.
+    pub fn is_movie_finished(&self) -> bool {
+        let movie_player = self.movie_player.as_ref().expect("No movie playback in progress");
+        self.frame_count >= movie_player.movie().num_frames
+    }
+
+    /// Verify that the replayed session ended in the same state as the recorded one. Should be
+    /// called once the movie is finished.
+    pub fn verify_movie(&self) -> Result<(), MovieError> {
+        let movie_player = self.movie_player.as_ref().expect("No movie playback in progress");
+        movie_player.verify(self.frame_count, self.state_hash())
+    }
 }
 
-use rand::Rng;
+impl Cpu {
+    /// Construct a CHIP-8 cpu at the initial entry state, with rom bytes loaded at the entry point
+    /// in memory, using the given quirk profile.
+    /// Fails if the rom can't be loaded, like `with_rom`.
+    pub fn with_rom_and_quirks(rom: &[u8], quirks: Quirks) -> Result<Cpu, LoadError> {
+        Cpu::with_rom_and_quirks_at(rom, quirks, &LoadOptions::standard())
+    }
+
+    /// Like `with_rom_and_quirks`, where the rom is loaded, execution starts and the screen mode
+    /// is set as `load_options` specify.
+    pub fn with_rom_and_quirks_at(rom: &[u8], quirks: Quirks, load_options: &LoadOptions)
+        -> Result<Cpu, LoadError> {
+        let mut init_cpu = Cpu::with_rom_at(rom, load_options)?;
+        init_cpu.quirks = quirks;
+
+        Ok(init_cpu)
+    }
+
+    /// Returns the quirk profile in use.
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Returns where the rom was loaded, where execution started, and the screen mode.
+    pub fn load_options(&self) -> LoadOptions {
+        self.load_options
+    }
+
+    /// Returns how `run_frame` executes instructions.
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// When `enabled` is false, `step` decodes every instruction as it is executed instead of
+    /// caching the decoded instructions. The cache is enabled by default.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = if enabled { Some(DecodeCache::new(MEM_SIZE)) } else { None };
+    }
+
+    /// Returns the bindings of host keys to hex keys.
+    pub fn keymap(&self) -> &Keymap {
+        &self.keymap
+    }
+
+    /// Returns the movie being replayed, if any.
+    pub fn playing_movie(&self) -> Option<&Movie> {
+        self.movie_player.as_ref().map(|movie_player| movie_player.movie())
+    }
+
+    /// Returns a hash of the emulated machine state: the memory, the registers, the timers, the
+    /// screen and the keyboard. Two sessions that ended in the same state have the same hash.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = StateHasher::new();
+        hasher.write(&self.memory);
+        for &return_address in &self.call_stack {
+            hasher.write(&(return_address as u16).to_le_bytes());
+        }
+        hasher.write(&self.v_registers);
+        hasher.write(&self.i_register.to_le_bytes());
+        hasher.write(&(self.pc_register as u16).to_le_bytes());
+        hasher.write(&[self.sp_register as u8, self.dt_register, self.st_register]);
+        let screen: Vec<u8> = self.screen_buffer.iter().map(|&pixel| pixel as u8).collect();
+        hasher.write(&screen);
+        hasher.write(&self.key_state.map(|pressed| pressed as u8));
+        hasher.write(&[self.waiting_for_vblank as u8, self.key_wait.is_waiting() as u8]);
+        hasher.write(&self.vip_cycle_balance.to_le_bytes());
+
+        hasher.finish()
+    }
+
+    /// Run a single 60HZ frame, which ends at the host time `timestamp_ms` if it is known.
+    fn run_frame_until(&mut self, timestamp_ms: Option<f64>) {
+        // The COSMAC VIP timing ticks the timers in the middle of the frame
+        if !self.vip_timing {
+            self.tick_clock();
+        }
+        self.input_queue.begin_frame(timestamp_ms);
+
+        if let Some(movie_player) = &mut self.movie_player {
+            for event in movie_player.take_frame_events(self.frame_count) {
+                self.input_queue.push_at_position(event.key_event, event.position);
+            }
+        }
+
+        let tone_on = self.should_play_tone();
+        if let Some(tone_generator) = &mut self.tone_generator {
+            tone_generator.begin_frame(tone_on);
+        }
+        if let Some(edge_recorder) = &mut self.edge_recorder {
+            edge_recorder.begin_frame();
+        }
+        // The tick at the start of the frame can stop the tone
+        self.record_edges(0.0);
+
+        if self.vip_timing {
+            let frame_cycles = timing::VIP_INTERPRETER_CYCLES_PER_FRAME;
+            self.vip_cycle_balance += frame_cycles;
+            let frame_start_balance = self.vip_cycle_balance;
+            let interrupt_cycles = timing::VIP_TIMER_INTERRUPT_CYCLE;
+            let mut timers_ticked = false;
+            while self.vip_cycle_balance > 0 {
+                let elapsed_cycles = frame_start_balance - self.vip_cycle_balance;
+                // The display interrupt is taken between instructions
+                if !timers_ticked && elapsed_cycles >= interrupt_cycles {
+                    self.tick_timers_at(elapsed_cycles as f32 / frame_cycles as f32);
+                    timers_ticked = true;
+                }
+                self.apply_key_events(elapsed_cycles as f32 / frame_cycles as f32);
+
+                if self.is_blocked() {
+                    // The interpreter idles while it is blocked, so we skip to the next key event
+                    // in this frame or to the display interrupt (which might unblock it).
+                    // Otherwise the cycles left in this frame are lost.
+                    let event_cycles = self.input_queue.next_event_position()
+                        .map(|event_position| (event_position * frame_cycles as f32).ceil() as i64);
+                    let pending_interrupt = Some(interrupt_cycles).filter(|_| !timers_ticked);
+                    match event_cycles.into_iter().chain(pending_interrupt).min() {
+                        Some(wake_cycles) => {
+                            self.vip_cycle_balance = frame_start_balance
+                                - std::cmp::max(wake_cycles, elapsed_cycles + 1);
+                        }
+                        None => {
+                            self.vip_cycle_balance = 0;
+                        }
+                    }
+                    continue;
+                }
+
+                let instruction = self.fetch_instruction();
+                self.vip_cycle_balance -=
+                    timing::vip_instruction_cycles(self, instruction) as i64;
+                self.execute_instructions(1);
+
+                let elapsed_cycles = frame_start_balance - self.vip_cycle_balance;
+                self.update_tone_gate(elapsed_cycles as f32 / frame_cycles as f32);
+                self.record_edges(elapsed_cycles as f32 / frame_cycles as f32);
+            }
+
+            // The cycles of the frame ran out before the interrupt was taken, because an
+            // instruction overran it, so it is taken after that instruction
+            if !timers_ticked {
+                let elapsed_cycles = frame_start_balance - self.vip_cycle_balance;
+                self.tick_timers_at(elapsed_cycles as f32 / frame_cycles as f32);
+            }
+        } else {
+            let instructions_per_frame = std::cmp::max(
+                (self.clock_rate_hz + timing::FRAME_RATE_HZ / 2) / timing::FRAME_RATE_HZ, 1);
+            let mut instr_idx = 0;
+            while instr_idx < instructions_per_frame {
+                self.apply_key_events(instr_idx as f32 / instructions_per_frame as f32);
+
+                if self.is_blocked() {
+                    instr_idx += 1;
+                    continue;
+                }
+                let max_instructions =
+                    self.instructions_before_next_event(instr_idx, instructions_per_frame);
+                instr_idx += self.execute_instructions(max_instructions);
+
+                self.update_tone_gate(instr_idx as f32 / instructions_per_frame as f32);
+                self.record_edges(instr_idx as f32 / instructions_per_frame as f32);
+            }
+        }
+
+        // Apply the events that happened after the last instruction of the frame
+        self.apply_key_events(1.0);
+
+        if let Some(phosphor_filter) = &mut self.phosphor_filter {
+            phosphor_filter.update(&self.screen_buffer);
+        }
+
+        if let Some(gif_recorder) = &mut self.gif_recorder {
+            let screen_buffer = &self.screen_buffer;
+            gif_recorder.capture(|x, y| screen_buffer[y * SCREEN_WIDTH + x] as usize);
+        }
+
+        if let Some(tone_generator) = &mut self.tone_generator {
+            tone_generator.end_frame();
+
+            if let Some(audio_recorder) = &mut self.audio_recorder {
+                audio_recorder.record(tone_generator.samples());
+            }
+        }
+
+        self.frame_count += 1;
+    }
+
+    /// Apply the queued key events that happened at or before `position` in the current frame.
+    fn apply_key_events(&mut self, position: f32) {
+        while let Some((key_event, event_position)) = self.input_queue.pop_until(position) {
+            self.key_state[key_event.key as usize] = key_event.pressed;
+            self.key_wait = self.key_wait.on_key_event(key_event, self.quirks.wait_for_release);
+            if let Some(shadow) = &mut self.lockstep_shadow {
+                shadow.key_state[key_event.key as usize] = key_event.pressed;
+                shadow.key_wait =
+                    shadow.key_wait.on_key_event(key_event, shadow.quirks.wait_for_release);
+            }
+
+            if let Some(movie) = &mut self.movie_recording {
+                movie.events.push(MovieEvent {
+                    frame: self.frame_count,
+                    position: event_position,
+                    key_event,
+                });
+            }
+        }
+    }
+
+    /// Returns the key state once all the queued key events are applied.
+    fn requested_key_state(&self) -> [bool; NUM_KEYS] {
+        self.input_queue.pending_key_state(self.key_state)
+    }
+
+    /// Queue a key event from the host. Host key events are ignored during movie playback, where
+    /// only the key events of the movie are applied.
+    fn queue_host_key_event(&mut self, key: u8, pressed: bool, timestamp_ms: Option<f64>)
+        -> Result<(), InputError> {
+        if self.movie_player.is_some() {
+            return Ok(());
+        }
+
+        self.input_queue.push(key, pressed, timestamp_ms)
+    }
+
+    /// Returns the screen buffer, row by row. A pixel is `true` if it is turned on.
+    pub fn screen_buffer(&self) -> &[bool] {
+        &self.screen_buffer
+    }
+
+    /// Enable flicker reduction with the given mode, starting from a dark screen.
+    pub fn set_phosphor_mode(&mut self, mode: PhosphorMode) {
+        self.phosphor_filter = Some(PhosphorFilter::new(mode, self.screen_buffer.len()));
+    }
+
+    /// Returns how lit every pixel is in the range [0, 1] after flicker reduction, row by row.
+    /// Flicker reduction must be enabled.
+    pub fn intensity_buffer(&self) -> &[f32] {
+        self.phosphor_filter.as_ref().expect("Flicker reduction is not enabled").intensity()
+    }
+
+    /// Returns the screen regions changed since the display was last updated. The regions are
+    /// cleared by `handle_screen_dirty_flag` and `take_dirty_rects`.
+    pub fn dirty_rects(&self) -> &[DirtyRect] {
+        self.dirty_regions.rects()
+    }
+
+    /// Returns the RGBA framebuffer, as last rendered by `render_framebuffer`.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.frame_renderer.rgba()
+    }
+
+    /// Returns the samples rendered in the last frame. Audio must be enabled.
+    pub fn audio_samples(&self) -> &[f32] {
+        self.tone_generator.as_ref().expect("Audio is not enabled").samples()
+    }
+
+    fn tone_generator_mut(&mut self) -> &mut ToneGenerator {
+        self.tone_generator.as_mut().expect("Audio is not enabled")
+    }
+
+    /// Turn the rendered tone on or off according to the sound timer, at `position` in the
+    /// current frame.
+    fn update_tone_gate(&mut self, position: f32) {
+        let tone_on = self.should_play_tone();
+        if let Some(tone_generator) = &mut self.tone_generator {
+            tone_generator.set_gate(position, tone_on);
+        }
+    }
+
+    /// Tick the cpu timers at `position` in the current frame, and update the tone to match.
+    fn tick_timers_at(&mut self, position: f32) {
+        self.tick_clock();
+        self.update_tone_gate(position);
+        self.record_edges(position);
+    }
+
+    /// Record the tone and key wait changes since the last call at `position` in the current
+    /// frame, if edge recording is enabled.
+    fn record_edges(&mut self, position: f32) {
+        let tone_on = self.should_play_tone();
+        let waiting_for_key = self.key_wait.is_waiting();
+        if let Some(edge_recorder) = &mut self.edge_recorder {
+            edge_recorder.update(position, tone_on, waiting_for_key);
+        }
+    }
+
+    /// Mark the entire screen as changed, e.g. after clearing or scrolling it.
+    fn mark_screen_dirty(&mut self) {
+        self.dirty_regions.add(DirtyRect {
+            x: 0,
+            y: 0,
+            width: SCREEN_WIDTH,
+            height: self.screen_height,
+        });
+    }
+
+    /// Fetch the instruction at the PC. Instructions are 2 bytes, big-endian.
+    fn fetch_instruction(&self) -> u16 {
+        assert!(self.pc_register + 1 < MEM_SIZE, "PC out of memory bounds");
+
+        ((self.memory[self.pc_register] as u16) << 8) | (self.memory[self.pc_register + 1] as u16)
+    }
+
+    /// Returns the number of instructions that can run, starting with instruction `instr_idx` of
+    /// the frame, before the next queued key event must be applied.
+    fn instructions_before_next_event(&self, instr_idx: u32, instructions_per_frame: u32) -> u32 {
+        let remaining = instructions_per_frame - instr_idx;
+        let event_position = match self.input_queue.next_event_position() {
+            Some(event_position) if self.block_cache.is_some() => event_position,
+            _ => return remaining,
+        };
+
+        // The event is applied before the first instruction whose position isn't before it
+        (1..remaining)
+            .find(|&count| event_position <= (instr_idx + count) as f32
+                / instructions_per_frame as f32)
+            .unwrap_or(remaining)
+    }
+
+    /// Execute at least one and at most `max_instructions` instructions with the current backend,
+    /// and returns the number of instructions executed. The block backend executes the block at
+    /// the PC (or as much of it as allowed) from its translated instructions, without reading
+    /// memory, and the interpreter executes a single instruction.
+    fn execute_instructions(&mut self, max_instructions: u32) -> u32 {
+        let block_start = self.pc_register;
+        let hires = self.load_options.hires;
+        let block = match &mut self.block_cache {
+            Some(block_cache) => block_cache.block(&self.memory, block_start, hires),
+            None => None,
+        };
+
+        let executed = match block {
+            Some(block) => {
+                let len = std::cmp::min(block.len(), max_instructions as usize);
+                for index in block.start..block.start + len {
+                    let instruction = match &self.block_cache {
+                        Some(block_cache) => block_cache.instruction(index),
+                        None => unreachable!("The block cache was dropped while executing a block"),
+                    };
+                    self.execute(instruction);
+                }
+                len as u32
+            }
+            // Interpret the instructions which can't be translated
+            None => {
+                self.step();
+                1
+            }
+        };
+
+        if let Some(shadow) = &mut self.lockstep_shadow {
+            for _ in 0..executed {
+                shadow.step();
+            }
+        }
+        if let Some(shadow) = &self.lockstep_shadow {
+            if let Some(difference) = self.state_difference(shadow) {
+                panic!("The block backend diverged from the interpreter in the block at {:#05X}: \
+                    {} differs", block_start, difference);
+            }
+        }
+
+        executed
+    }
+
+    /// Returns a copy of the emulated machine state (like `state_hash` covers, plus the random
+    /// number generator), which executes instructions with the interpreter.
+    fn interpreted_copy(&self) -> Cpu {
+        let mut copy = Cpu::new();
+        copy.memory = self.memory;
+        copy.call_stack = self.call_stack;
+        copy.v_registers = self.v_registers;
+        copy.i_register = self.i_register;
+        copy.pc_register = self.pc_register;
+        copy.sp_register = self.sp_register;
+        copy.dt_register = self.dt_register;
+        copy.st_register = self.st_register;
+        copy.screen_buffer = self.screen_buffer.clone();
+        copy.screen_height = self.screen_height;
+        copy.key_state = self.key_state;
+        copy.key_wait = self.key_wait;
+        copy.waiting_for_vblank = self.waiting_for_vblank;
+        copy.quirks = self.quirks;
+        copy.load_options = self.load_options;
+        copy.rng = self.rng.clone();
+        copy.rng_seed = self.rng_seed;
+        copy
+    }
+
+    /// Returns the name of the first part of the emulated machine state that differs between the
+    /// cpus, if any.
+    fn state_difference(&self, other: &Cpu) -> Option<&'static str> {
+        if self.pc_register != other.pc_register {
+            Some("PC")
+        } else if self.v_registers != other.v_registers {
+            Some("V registers")
+        } else if self.i_register != other.i_register {
+            Some("I")
+        } else if self.sp_register != other.sp_register || self.call_stack != other.call_stack {
+            Some("call stack")
+        } else if self.dt_register != other.dt_register || self.st_register != other.st_register {
+            Some("timers")
+        } else if self.key_wait != other.key_wait
+            || self.waiting_for_vblank != other.waiting_for_vblank {
+            Some("blocking state")
+        } else if self.memory[..] != other.memory[..] {
+            Some("memory")
+        } else if self.screen_buffer != other.screen_buffer {
+            Some("screen")
+        } else {
+            None
+        }
+    }
+
+    /// Execute the decoded instruction `instruction`, and advance the PC.
+    fn execute(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Cls => self.instr_00e0(),
+            Instruction::Ret => self.instr_00ee(),
+            Instruction::Jp { addr } => self.instr_1nnn(addr),
+            Instruction::Call { addr } => self.instr_2nnn(addr),
+            Instruction::SeByte { x, byte } => self.instr_3xkk(x, byte),
+            Instruction::SneByte { x, byte } => self.instr_4xkk(x, byte),
+            Instruction::SeReg { x, y } => self.instr_5xy0(x, y),
+            Instruction::LdByte { x, byte } => self.instr_6xkk(x, byte),
+            Instruction::AddByte { x, byte } => self.instr_7xkk(x, byte),
+            Instruction::LdReg { x, y } => self.instr_8xy0(x, y),
+            Instruction::Or { x, y } => self.instr_8xy1(x, y),
+            Instruction::And { x, y } => self.instr_8xy2(x, y),
+            Instruction::Xor { x, y } => self.instr_8xy3(x, y),
+            Instruction::AddReg { x, y } => self.instr_8xy4(x, y),
+            Instruction::Sub { x, y } => self.instr_8xy5(x, y),
+            Instruction::Shr { x, y } => self.instr_8xy6(x, y),
+            Instruction::Subn { x, y } => self.instr_8xy7(x, y),
+            Instruction::Shl { x, y } => self.instr_8xye(x, y),
+            Instruction::SneReg { x, y } => self.instr_9xy0(x, y),
+            Instruction::LdI { addr } => self.instr_annn(addr),
+            Instruction::JpV0 { addr } => self.instr_bnnn(addr),
+            Instruction::Rnd { x, byte } => self.instr_cxkk(x, byte),
+            Instruction::Drw { x, y, nibble } => self.instr_dxyn(x, y, nibble),
+            Instruction::Skp { x } => self.instr_ex9e(x),
+            Instruction::Sknp { x } => self.instr_exa1(x),
+            Instruction::LdFromDt { x } => self.instr_fx07(x),
+            Instruction::LdKey { x } => self.instr_fx0a(x),
+            Instruction::LdDt { x } => self.instr_fx15(x),
+            Instruction::LdSt { x } => self.instr_fx18(x),
+            Instruction::AddI { x } => self.instr_fx1e(x),
+            Instruction::LdFont { x } => self.instr_fx29(x),
+            Instruction::LdBcd { x } => self.instr_fx33(x),
+            Instruction::Store { x } => self.instr_fx55(x),
+            Instruction::Load { x } => self.instr_fx65(x),
+        }
+
+        // Increment PC
+        self.pc_register += 2;
+    }
+
+    /// Invalidate the decoded instructions which overlap the `len` bytes of memory starting at
+    /// `address`, after they were written.
+    fn invalidate_decoded(&mut self, address: usize, len: usize) {
+        if let Some(decode_cache) = &mut self.decode_cache {
+            decode_cache.invalidate(address, len);
+        }
+        if let Some(block_cache) = &mut self.block_cache {
+            block_cache.invalidate(address, len);
+        }
+    }
+
+    /// Complete the wait for key instruction the cpu is blocked on with `key`, as if it was
+    /// captured from the key events. Used by the analyzer, which steps the cpu without any.
+    fn capture_key(&mut self, key: u8) {
+        self.key_wait = KeyWait::Captured(key);
+    }
+
+    /// Returns true if the cpu can't make progress until the next frame or a key event.
+    fn is_blocked(&self) -> bool {
+        self.waiting_for_vblank || self.key_wait.is_waiting()
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Cpu::new()
+    }
+}
 
 // Instruction implementations
 impl Cpu {
     /// Execute `CLS` instruction
-    fn instr_00e0(&mut self, _instr: u16) {
+    fn instr_00e0(&mut self) {
         for pixel in self.screen_buffer.iter_mut() {
             *pixel = false;
         }
 
-        self.screen_dirty = true;
+        self.mark_screen_dirty();
     }
 
     /// Execute `RET` instruction
-    fn instr_00ee(&mut self, _instr: u16) {
+    fn instr_00ee(&mut self) {
         assert!(self.sp_register > 0, "RET without CALL");
 
         // Reclaim top of stack
@@ -301,8 +1321,7 @@ impl Cpu {
     }
 
     /// Execute `JP addr` instruction
-    fn instr_1nnn(&mut self, instr: u16) {
-        let jump_target = decode_instr_addr(instr);
+    fn instr_1nnn(&mut self, jump_target: usize) {
         assert!(jump_target >= MEM_RESERVED, "Jumping to the reserved memory area is not allowed");
         // assert!(jump_target%2 == 0, "Unaligned jumps are not allowed");
 
@@ -313,8 +1332,7 @@ impl Cpu {
     }
 
     /// Execute `CALL addr` instruction
-    fn instr_2nnn(&mut self, instr: u16) {
-        let call_target = decode_instr_addr(instr);
+    fn instr_2nnn(&mut self, call_target: usize) {
         assert!(call_target >= MEM_RESERVED, "Jumping to the reserved memory area is not allowed");
         // assert!(call_target%2 == 0, "Unaligned jumps are not allowed");
 
@@ -332,25 +1350,23 @@ impl Cpu {
     }
 
     /// Execute `SE Vx, byte` instruction
-    fn instr_3xkk(&mut self, instr: u16) {
+    fn instr_3xkk(&mut self, x_register: usize, byte_imm: u8) {
         // Skip the next instruction if the register value and the byte are equal
-        if self.v_registers[decode_instr_x_reg(instr)] == decode_instr_byte_imm(instr) {
+        if self.v_registers[x_register] == byte_imm {
             self.pc_register += 2;
         }
     }
 
     /// Execute `SNE Vx, byte` instruction
-    fn instr_4xkk(&mut self, instr: u16) {
+    fn instr_4xkk(&mut self, x_register: usize, byte_imm: u8) {
         // Skip the next instruction if the register value and the byte are not equal
-        if self.v_registers[decode_instr_x_reg(instr)] != decode_instr_byte_imm(instr) {
+        if self.v_registers[x_register] != byte_imm {
             self.pc_register += 2;
         }
     }
 
     /// Execute `SE Vx, Vy` instruction
-    fn instr_5xy0(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let y_register = decode_instr_y_reg(instr);
+    fn instr_5xy0(&mut self, x_register: usize, y_register: usize) {
 
         // Skip the next instruction if the values of the registers are equal
         if self.v_registers[x_register] == self.v_registers[y_register] {
@@ -359,49 +1375,37 @@ impl Cpu {
     }
 
     /// Execute `LD Vx, byte` instruction
-    fn instr_6xkk(&mut self, instr: u16) {
-        self.v_registers[decode_instr_x_reg(instr)] = decode_instr_byte_imm(instr);
+    fn instr_6xkk(&mut self, x_register: usize, byte_imm: u8) {
+        self.v_registers[x_register] = byte_imm;
     }
 
     /// Execute `ADD Vx, byte` instruction
-    fn instr_7xkk(&mut self, instr: u16) {
-        let register_idx = decode_instr_x_reg(instr);
-        let byte_imm = decode_instr_byte_imm(instr);
+    fn instr_7xkk(&mut self, register_idx: usize, byte_imm: u8) {
         self.v_registers[register_idx] = self.v_registers[register_idx].wrapping_add(byte_imm);
     }
 
     /// Execute `LD Vx, Vy` instruction
-    fn instr_8xy0(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let y_register = decode_instr_y_reg(instr);
+    fn instr_8xy0(&mut self, x_register: usize, y_register: usize) {
         self.v_registers[x_register] = self.v_registers[y_register];
     }
 
     /// Execute `OR Vx, Vy` instruction
-    fn instr_8xy1(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let y_register = decode_instr_y_reg(instr);
+    fn instr_8xy1(&mut self, x_register: usize, y_register: usize) {
         self.v_registers[x_register] |= self.v_registers[y_register];
     }
 
     /// Execute `AND Vx, Vy` instruction
-    fn instr_8xy2(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let y_register = decode_instr_y_reg(instr);
+    fn instr_8xy2(&mut self, x_register: usize, y_register: usize) {
         self.v_registers[x_register] &= self.v_registers[y_register];
     }
 
     /// Execute `XOR Vx, Vy` instruction
-    fn instr_8xy3(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let y_register = decode_instr_y_reg(instr);
+    fn instr_8xy3(&mut self, x_register: usize, y_register: usize) {
         self.v_registers[x_register] ^= self.v_registers[y_register];
     }
 
     /// Execute `ADD Vx, Vy` instruction
-    fn instr_8xy4(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let y_register = decode_instr_y_reg(instr);
+    fn instr_8xy4(&mut self, x_register: usize, y_register: usize) {
         let (sum, carry) = self.v_registers[x_register].overflowing_add(self.v_registers[y_register]);
         
         // After performing register addition, VF acts as a carry flag
@@ -410,9 +1414,7 @@ impl Cpu {
     }
 
     /// Execute `SUB Vx, Vy` instruction
-    fn instr_8xy5(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let y_register = decode_instr_y_reg(instr);
+    fn instr_8xy5(&mut self, x_register: usize, y_register: usize) {
         let (diff, borrow) = self.v_registers[x_register].overflowing_sub(self.v_registers[y_register]);
         
         // After performing register subtraction, VF acts as a NOT borrow flag
@@ -421,13 +1423,10 @@ impl Cpu {
     }
 
     /// Execute `SHR Vx, Vy` instruction
-    fn instr_8xy6(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let mut y_register = decode_instr_y_reg(instr);
-        
+    fn instr_8xy6(&mut self, x_register: usize, mut y_register: usize) {
         // In newer interpreters, probably because of a quirk in S-CHIP, the Vy register is ignored
         // and instead Vx is shifted in-place.
-        if !self.original_shift {
+        if !self.quirks.original_shift {
             y_register = x_register;
         }
 
@@ -438,9 +1437,7 @@ impl Cpu {
     }
 
     /// Execute `SUBN Vx, Vy` instruction
-    fn instr_8xy7(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let y_register = decode_instr_y_reg(instr);
+    fn instr_8xy7(&mut self, x_register: usize, y_register: usize) {
         let (diff, borrow) = self.v_registers[y_register].overflowing_sub(self.v_registers[x_register]);
         
         // After performing register subtraction, VF acts as a NOT borrow flag
@@ -449,13 +1446,10 @@ impl Cpu {
     }
 
     /// Execute `SHL Vx, Vy` instruction
-    fn instr_8xye(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let mut y_register = decode_instr_y_reg(instr);
-        
+    fn instr_8xye(&mut self, x_register: usize, mut y_register: usize) {
         // In newer interpreters, probably because of a quirk in S-CHIP, the Vy register is ignored
         // and instead Vx is shifted in-place.
-        if !self.original_shift {
+        if !self.quirks.original_shift {
             y_register = x_register;
         }
 
@@ -466,9 +1460,7 @@ impl Cpu {
     }
 
     /// Execute `SNE Vx, Vy` instruction
-    fn instr_9xy0(&mut self, instr: u16) {
-        let x_register = decode_instr_x_reg(instr);
-        let y_register = decode_instr_y_reg(instr);
+    fn instr_9xy0(&mut self, x_register: usize, y_register: usize) {
 
         // Skip the next instruction if the values of the registers are not equal
         if self.v_registers[x_register] != self.v_registers[y_register] {
@@ -477,13 +1469,13 @@ impl Cpu {
     }
 
     /// Execute `LD I, addr` instruction
-    fn instr_annn(&mut self, instr: u16) {
-        self.i_register = decode_instr_addr(instr);
+    fn instr_annn(&mut self, addr: usize) {
+        self.i_register = addr;
     }
 
     /// Execute `JP V0, addr` instruction
-    fn instr_bnnn(&mut self, instr: u16) {
-        let jump_target = decode_instr_addr(instr) + (self.v_registers[0] as usize);
+    fn instr_bnnn(&mut self, addr: usize) {
+        let jump_target = addr + (self.v_registers[0] as usize);
         assert!(jump_target >= MEM_RESERVED, "Jumping to the reserved memory area is not allowed");
         // assert!(jump_target%2 == 0, "Unaligned jumps are not allowed");        
 
@@ -494,17 +1486,15 @@ impl Cpu {
     }
 
     /// Execute `RND Vx, byte` instruction
-    fn instr_cxkk(&mut self, instr: u16) {
-        let register_idx = decode_instr_x_reg(instr);
-        let byte_imm = decode_instr_byte_imm(instr);
-        self.v_registers[register_idx] = rand::thread_rng().gen::<u8>() & byte_imm;
+    fn instr_cxkk(&mut self, register_idx: usize, byte_imm: u8) {
+        self.v_registers[register_idx] = self.rng.gen::<u8>() & byte_imm;
     }
 
     /// Execute `DRW Vx, Vy, nibble` instruction
-    fn instr_dxyn(&mut self, instr: u16) {
-        let sprite_x = (self.v_registers[decode_instr_x_reg(instr)] as usize) % SCREEN_WIDTH;
-        let sprite_y = (self.v_registers[decode_instr_y_reg(instr)] as usize) % SCREEN_HEIGHT;
-        let sprite_height = decode_instr_nibble_imm(instr) as usize;
+    fn instr_dxyn(&mut self, x_register: usize, y_register: usize, nibble: u8) {
+        let sprite_x = (self.v_registers[x_register] as usize) % SCREEN_WIDTH;
+        let sprite_y = (self.v_registers[y_register] as usize) % self.screen_height;
+        let sprite_height = nibble as usize;
 
         // Sprite positioning details are generally inconsistent across sources online. Some claim
         // that if a sprite's (x, y) are off-screen they are wrapped, and some say they dont, some
@@ -516,11 +1506,11 @@ impl Cpu {
         // We currently assume that the (x, y) of a sprite is wrapped, but that a sprite that
         // extends beyond the edge of the screen is clipped.
 
-        assert!(self.i_register + sprite_height - 1 < MEM_SIZE,
+        assert!(self.i_register + sprite_height <= MEM_SIZE,
             "Requested sprite height is out of bounds");
 
         let mut collision = false;
-        for pixel_y in sprite_y..std::cmp::min(sprite_y+sprite_height, SCREEN_HEIGHT){
+        for pixel_y in sprite_y..std::cmp::min(sprite_y+sprite_height, self.screen_height){
             for pixel_x in sprite_x..std::cmp::min(sprite_x+8, SCREEN_WIDTH) {
                 // A sprite is a bit-packed representation of a bitmap, as such its width is 8,
                 // and the number of bytes is its height.
@@ -542,12 +1532,24 @@ impl Cpu {
         // When drawing sprites, VF acts as collision flag
         self.v_registers[0xF] = collision as u8;
 
-        self.screen_dirty = true;
+        self.dirty_regions.add(DirtyRect {
+            x: sprite_x,
+            y: sprite_y,
+            width: std::cmp::min(8, SCREEN_WIDTH - sprite_x),
+            height: std::cmp::min(sprite_height, self.screen_height - sprite_y),
+        });
+
+        // On the COSMAC VIP the interpreter waited for the display interrupt before drawing, so
+        // at most one sprite is drawn per frame. Newer interpreters (S-CHIP, XO-CHIP) don't wait.
+        if self.quirks.display_wait {
+            self.waiting_for_vblank = true;
+        }
     }
 
     /// Execute `SKP Vx` instruction
-    fn instr_ex9e(&mut self, instr: u16) {
-        let key_digit = self.v_registers[decode_instr_x_reg(instr)];
+    fn instr_ex9e(&mut self, x_register: usize) {
+        // Only the low nibble of Vx selects the key, as on the COSMAC VIP
+        let key_digit = self.v_registers[x_register] & 0xF;
         // Skip the next instruction if key value of reg Vx is pressed
         if self.key_state[key_digit as usize] {
             self.pc_register += 2;
@@ -555,8 +1557,9 @@ impl Cpu {
     }
 
     /// Execute `SKNP Vx` instruction
-    fn instr_exa1(&mut self, instr: u16) {
-        let key_digit = self.v_registers[decode_instr_x_reg(instr)];
+    fn instr_exa1(&mut self, x_register: usize) {
+        // Only the low nibble of Vx selects the key, as on the COSMAC VIP
+        let key_digit = self.v_registers[x_register] & 0xF;
         // Skip the next instruction if key value of reg Vx is not pressed
         if !self.key_state[key_digit as usize] {
             self.pc_register += 2;
@@ -564,19 +1567,21 @@ impl Cpu {
     }
 
     /// Execute `LD Vx, DT` instruction
-    fn instr_fx07(&mut self, instr: u16) {
-        self.v_registers[decode_instr_x_reg(instr)] = self.dt_register;
+    fn instr_fx07(&mut self, x_register: usize) {
+        self.v_registers[x_register] = self.dt_register;
     }
 
     /// Execute `LD Vx, K` instruction
-    fn instr_fx0a(&mut self, instr: u16) {
-        if self.waiting_for_keypress {
-            // We are already waiting for a key press, because this instruction was executed again,
-            // we know we just received the capture key.
-            self.v_registers[decode_instr_x_reg(instr)] = self.captured_key;
-            self.waiting_for_keypress = false;
-        } else{
-            self.waiting_for_keypress = true;
+    fn instr_fx0a(&mut self, x_register: usize) {
+        if let KeyWait::Captured(captured_key) = self.key_wait {
+            // We were waiting for a key, and this instruction was executed again after the key
+            // events captured one.
+            self.v_registers[x_register] = captured_key;
+            self.key_wait = KeyWait::Idle;
+        } else {
+            if self.key_wait == KeyWait::Idle {
+                self.key_wait = KeyWait::WaitingForPress;
+            }
             // We are gonna block until we capture a key, after which we want this instruction to
             // execute again. We decremnt PC so the automatic PC increment after instruction
             // execution will result in this instruction being executed again after a key press.
@@ -585,42 +1590,41 @@ impl Cpu {
     }
 
     /// Execute `LD DT, Vx` instruction
-    fn instr_fx15(&mut self, instr: u16) {
-        self.dt_register = self.v_registers[decode_instr_x_reg(instr)];
+    fn instr_fx15(&mut self, x_register: usize) {
+        self.dt_register = self.v_registers[x_register];
     }
 
     /// Execute `LD ST, Vx` instruction
-    fn instr_fx18(&mut self, instr: u16) {
-        self.st_register = self.v_registers[decode_instr_x_reg(instr)];
+    fn instr_fx18(&mut self, x_register: usize) {
+        self.st_register = self.v_registers[x_register];
     }
 
     /// Execute `ADD I, Vx` instruction
-    fn instr_fx1e(&mut self, instr: u16) {
-        self.i_register += self.v_registers[decode_instr_x_reg(instr)] as usize;
+    fn instr_fx1e(&mut self, x_register: usize) {
+        self.i_register += self.v_registers[x_register] as usize;
         assert!(self.i_register < MEM_SIZE, "I register overflow, do we need to handle wrapping?");
     }
 
     /// Execute `LD F, Vx` instruction
-    fn instr_fx29(&mut self, instr: u16) {
-        let hex_digit = self.v_registers[decode_instr_x_reg(instr)];
+    fn instr_fx29(&mut self, x_register: usize) {
+        let hex_digit = self.v_registers[x_register];
         
         // We store the font sprites at address 0, and each sprite takes up 5 bytes.
         self.i_register = 5 * (hex_digit as usize);
     }
 
     /// Execute `LD B, Vx` instruction
-    fn instr_fx33(&mut self, instr: u16) {
+    fn instr_fx33(&mut self, x_register: usize) {
         assert!(self.i_register + 2 < MEM_SIZE, "Requested BCD encoding is out of bounds");
-        let reg_val = self.v_registers[decode_instr_x_reg(instr)];
+        let reg_val = self.v_registers[x_register];
         self.memory[self.i_register] = reg_val/100;
         self.memory[self.i_register+1] = (reg_val/10)%10;
         self.memory[self.i_register+2] = reg_val%10;
+        self.invalidate_decoded(self.i_register, 3);
     }
 
     /// Execute `LD [I], Vx` instruction
-    fn instr_fx55(&mut self, instr: u16) {
-        let last_reg = decode_instr_x_reg(instr);
-        
+    fn instr_fx55(&mut self, last_reg: usize) {
         // We need to check that I + Vx + 1 is still in bounds because we set I to the address after
         // the last stored register, and we assume I holds an in-bound address.
         assert!(self.i_register + last_reg + 1 < MEM_SIZE,
@@ -630,19 +1634,18 @@ impl Cpu {
         for reg in 0..last_reg+1 {
             self.memory[self.i_register + reg] = self.v_registers[reg];
         }
+        self.invalidate_decoded(self.i_register, last_reg + 1);
 
         // In the original CHIP-8 interpreter, the I register was incremented in the store loop.
         // Some newer interpreters don't change the I register.
-        if self.original_mem_acc {
+        if self.quirks.original_mem_acc {
             // Update I register to hold the address after the last stored register
             self.i_register += last_reg + 1;
         }
     }
 
     /// Execute `LD Vx, [I]` instruction
-    fn instr_fx65(&mut self, instr: u16) {
-        let last_reg = decode_instr_x_reg(instr);
-        
+    fn instr_fx65(&mut self, last_reg: usize) {
         // We need to check that I + Vx + 1 is still in bounds because we set I to the address after
         // the last loaded register, and we assume I holds an in-bound address.
         assert!(self.i_register + last_reg + 1 < MEM_SIZE,
@@ -655,34 +1658,9 @@ impl Cpu {
 
         // In the original CHIP-8 interpreter, the I register was incremented in the load loop.
         // Some newer interpreters don't change the I register.
-        if self.original_mem_acc {
+        if self.quirks.original_mem_acc {
             // Update I register to hold the address after the last stored register
             self.i_register += last_reg + 1;
         }
     }
-}
-
-/// Decodes a memory address from a CHIP-8 instruction
-fn decode_instr_addr(instr: u16) -> usize {
-    (instr & 0x0FFF) as usize
-}
-
-/// Decodes the first register from a CHIP-8 instruction
-fn decode_instr_x_reg(instr: u16) -> usize {
-    ((instr & 0x0F00) >> 8) as usize
-}
-
-/// Decodes the second register from a CHIP-8 instruction
-fn decode_instr_y_reg(instr: u16) -> usize {
-    ((instr & 0x00F0) >> 4) as usize
-}
-
-/// Decodes a byte-sized immediate from a CHIP-8 instruction
-fn decode_instr_byte_imm(instr: u16) -> u8 {
-    (instr & 0x00FF) as u8
-}
-
-/// Decodes a nibble-sized immediate from a CHIP-8 instruction
-fn decode_instr_nibble_imm(instr: u16) -> u8 {
-    (instr & 0x000F) as u8
 }
\ No newline at end of file
@@ -0,0 +1,453 @@
+//! A linter for CHIP-8 ROMs, which finds likely bugs and portability problems without running the
+//! ROM.
+//!
+//! The code reachable from the entry point is traced with the same decoding the cpu uses, keeping
+//! track of the value of I where it is known. Every subroutine is traced separately, so returns
+//! can be matched with calls. The linter reports:
+//! - Jumps and calls into the reserved memory area, and execution that leaves the ROM.
+//! - Unknown instructions in reachable code.
+//! - Unbalanced calls and returns.
+//! - Reads of memory beyond the ROM that is never written, and sprites drawn from outside sprite
+//!   data.
+//! - Bytes that are neither executed nor used as data.
+//! - Instructions whose behaviour depends on a quirk.
+//!
+//! The trace is conservative: jumps through `JP V0, addr` aren't followed, and I is only tracked
+//! while it holds a constant address.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::analyzer::{format_address, format_addresses, rom_instruction, scan_mem_acc_site};
+use crate::analyzer::shift_site;
+use crate::instruction::Instruction;
+use crate::load::{validate_rom, LoadOptions};
+use crate::{MEM_RESERVED, MEM_SIZE};
+
+/// How serious a lint issue is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The ROM will crash or misbehave when the code runs.
+    Error,
+    /// The ROM is likely to have a bug.
+    Warning,
+    /// The ROM relies on behaviour that differs between interpreters.
+    Info,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Info => write!(f, "info"),
+        }
+    }
+}
+
+/// An issue found by the linter, at the addresses of the instructions (or data) it was found at.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LintIssue {
+    pub severity: Severity,
+    pub message: String,
+    pub addresses: Vec<usize>,
+}
+
+/// The issues found in a ROM, the most serious first.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LintReport {
+    pub issues: Vec<LintIssue>,
+}
+
+impl LintReport {
+    /// Returns true if any issue is an error.
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|issue| issue.severity == Severity::Error)
+    }
+
+    /// Returns the number of issues of `severity`.
+    pub fn count(&self, severity: Severity) -> usize {
+        self.issues.iter().filter(|issue| issue.severity == severity).count()
+    }
+}
+
+impl fmt::Display for LintReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in &self.issues {
+            write!(f, "{}: {}", issue.severity, issue.message)?;
+            if !issue.addresses.is_empty() {
+                write!(f, " (at {})", format_addresses(&issue.addresses))?;
+            }
+            writeln!(f)?;
+        }
+
+        write!(f, "{} errors, {} warnings, {} infos", self.count(Severity::Error),
+            self.count(Severity::Warning), self.count(Severity::Info))
+    }
+}
+
+/// Lint `rom`, loaded as `load_options` specify.
+pub fn lint_rom(rom: &[u8], load_options: &LoadOptions) -> LintReport {
+    let mut linter = Linter::new(rom, load_options);
+    if let Err(err) = validate_rom(rom, load_options) {
+        linter.report(Severity::Error, err.to_string(), None);
+        return linter.finish();
+    }
+
+    linter.trace();
+    linter.check_subroutines();
+    linter.check_reads();
+    linter.mark_referenced_data();
+    linter.check_unreachable();
+    linter.finish()
+}
+
+#[wasm_bindgen]
+/// Lint `rom` like `lint_rom`, and return the report in JSON format.
+pub fn lint_rom_json(rom: &[u8], load_options: &LoadOptions) -> String {
+    serde_json::to_string(&lint_rom(rom, load_options)).expect("Failed to serialize lint report")
+}
+
+/// What is known about the value of I at an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IValue {
+    /// I holds this address.
+    Known(usize),
+    /// I points to a font sprite.
+    Font,
+    /// I may hold any address.
+    Unknown,
+}
+
+/// A memory read through I.
+struct Read {
+    /// The address of the reading instruction.
+    site: usize,
+    /// The address of the first byte read.
+    start: usize,
+    /// The number of bytes read.
+    len: usize,
+    /// True if the bytes are read as a sprite.
+    sprite: bool,
+}
+
+/// A point of the trace: the address of an instruction, the subroutine it runs in (or none for
+/// the main program), and the value of I.
+type TracePoint = (usize, Option<usize>, IValue);
+
+struct Linter<'a> {
+    rom: &'a [u8],
+    load_options: LoadOptions,
+    /// The address after the last byte of the ROM.
+    rom_end: usize,
+    /// The addresses of the issues found, by severity and message.
+    issues: BTreeMap<(Severity, String), BTreeSet<usize>>,
+    /// The value of I at every traced instruction, by subroutine and address.
+    traced: HashMap<(Option<usize>, usize), IValue>,
+    /// The bytes of the traced instructions.
+    code: Vec<bool>,
+    /// The bytes used as data.
+    data: Vec<bool>,
+    /// The bytes written through I.
+    written: Vec<bool>,
+    reads: Vec<Read>,
+    /// The constant addresses I is set to.
+    i_targets: BTreeSet<usize>,
+    /// The traced subroutines, and those of them that return.
+    subroutines: BTreeSet<usize>,
+    returning_subroutines: BTreeSet<usize>,
+}
+
+impl<'a> Linter<'a> {
+    fn new(rom: &'a [u8], load_options: &LoadOptions) -> Self {
+        Linter {
+            rom,
+            load_options: *load_options,
+            rom_end: load_options.load_address + rom.len(),
+            issues: BTreeMap::new(),
+            traced: HashMap::new(),
+            code: vec![false; MEM_SIZE],
+            data: vec![false; MEM_SIZE],
+            written: vec![false; MEM_SIZE],
+            reads: Vec::new(),
+            i_targets: BTreeSet::new(),
+            subroutines: BTreeSet::new(),
+            returning_subroutines: BTreeSet::new(),
+        }
+    }
+
+    /// Record an issue, at `address` if the issue has one.
+    fn report(&mut self, severity: Severity, message: String, address: Option<usize>) {
+        let addresses = self.issues.entry((severity, message)).or_default();
+        addresses.extend(address);
+    }
+
+    /// Returns true if `address` is within the ROM.
+    fn in_rom(&self, address: usize) -> bool {
+        (self.load_options.load_address..self.rom_end).contains(&address)
+    }
+
+    /// Trace the code reachable from the entry point.
+    fn trace(&mut self) {
+        let entry_point = self.load_options.entry_point;
+        let mut worklist: Vec<TracePoint> = vec![(entry_point, None, IValue::Unknown)];
+        while let Some((address, subroutine, i)) = worklist.pop() {
+            // An instruction is traced again if it is reached with a different value of I, which
+            // is then unknown
+            let i = match self.traced.get(&(subroutine, address)) {
+                Some(&traced_i) if traced_i == i || traced_i == IValue::Unknown => continue,
+                Some(_) => IValue::Unknown,
+                None => i,
+            };
+            self.traced.insert((subroutine, address), i);
+            self.trace_instruction(address, subroutine, i, &mut worklist);
+        }
+    }
+
+    /// Trace the instruction at `address`, and add the instructions that may run after it to
+    /// `worklist`.
+    fn trace_instruction(&mut self, address: usize, subroutine: Option<usize>, i: IValue,
+        worklist: &mut Vec<TracePoint>) {
+        let instr = match rom_instruction(self.rom, self.load_options.load_address, address) {
+            Some(instr) => instr,
+            None => return,
+        };
+        self.code[address] = true;
+        self.code[address + 1] = true;
+
+        let instruction = match Instruction::decode(instr, self.load_options.hires) {
+            Some(instruction) => instruction,
+            None => {
+                self.report(Severity::Error, format!("Unknown instruction `{:04X}` in reachable \
+                    code", instr), Some(address));
+                return;
+            }
+        };
+
+        let mut next_i = i;
+        match instruction {
+            Instruction::Ret => {
+                match subroutine {
+                    Some(subroutine) => {
+                        self.returning_subroutines.insert(subroutine);
+                    }
+                    None => {
+                        self.report(Severity::Error, "RET outside of a subroutine".to_string(),
+                            Some(address));
+                    }
+                }
+                return;
+            }
+            Instruction::Jp { addr } => {
+                self.follow(address, addr, subroutine, i, worklist);
+                return;
+            }
+            Instruction::Call { addr } => {
+                if addr < MEM_RESERVED {
+                    self.report(Severity::Error, "Call into the reserved memory area".to_string(),
+                        Some(address));
+                } else {
+                    self.subroutines.insert(addr);
+                    self.follow(address, addr, Some(addr), IValue::Unknown, worklist);
+                }
+                // The subroutine may change I
+                next_i = IValue::Unknown;
+            }
+            Instruction::JpV0 { addr } => {
+                if addr < MEM_RESERVED {
+                    self.report(Severity::Error, "Jump into the reserved memory area".to_string(),
+                        Some(address));
+                }
+                self.report(Severity::Info, "`JP V0, addr` jumps to `xnn + Vx` on SCHIP, and the \
+                    code it jumps to isn't checked".to_string(), Some(address));
+                return;
+            }
+            Instruction::LdI { addr } => {
+                next_i = IValue::Known(addr);
+                self.i_targets.insert(addr);
+            }
+            Instruction::AddI { .. } => next_i = IValue::Unknown,
+            Instruction::LdFont { .. } => next_i = IValue::Font,
+            Instruction::Drw { nibble, .. } => self.record_read(address, i, nibble as usize, true),
+            Instruction::Load { x } => {
+                self.record_read(address, i, x + 1, false);
+                self.check_mem_acc(address);
+                // I is incremented only with the original load/store quirk
+                next_i = IValue::Unknown;
+            }
+            Instruction::Store { x } => {
+                self.record_write(i, x + 1);
+                self.check_mem_acc(address);
+                next_i = IValue::Unknown;
+            }
+            Instruction::LdBcd { .. } => self.record_write(i, 3),
+            Instruction::Shr { .. } | Instruction::Shl { .. } => {
+                if let Some(original) = shift_site(&instruction) {
+                    let message = if original {
+                        "Shift that relies on the original shift quirk (shifting Vy into Vx)"
+                    } else {
+                        "Shift that relies on the modern shift quirk (shifting Vx in place)"
+                    };
+                    self.report(Severity::Info, message.to_string(), Some(address));
+                }
+            }
+            _ => {}
+        }
+
+        self.follow(address, address + 2, subroutine, next_i, worklist);
+        if instruction.is_skip() {
+            self.follow(address, address + 4, subroutine, next_i, worklist);
+        }
+    }
+
+    /// Continue the trace from the instruction at `site` to `target`, unless it leaves the ROM.
+    fn follow(&mut self, site: usize, target: usize, subroutine: Option<usize>, i: IValue,
+        worklist: &mut Vec<TracePoint>) {
+        if target < MEM_RESERVED {
+            self.report(Severity::Error, "Jump into the reserved memory area".to_string(),
+                Some(site));
+        } else if !self.in_rom(target) || !self.in_rom(target + 1) {
+            self.report(Severity::Warning, "Execution continues outside of the ROM".to_string(),
+                Some(site));
+        } else {
+            worklist.push((target, subroutine, i));
+        }
+    }
+
+    /// Record a read of `len` bytes through I by the instruction at `site`.
+    fn record_read(&mut self, site: usize, i: IValue, len: usize, sprite: bool) {
+        if let IValue::Known(start) = i {
+            if len > 0 {
+                self.reads.push(Read { site, start, len, sprite });
+            }
+        }
+    }
+
+    /// Record a write of `len` bytes through I.
+    fn record_write(&mut self, i: IValue, len: usize) {
+        if let IValue::Known(start) = i {
+            for address in start..(start + len).min(MEM_SIZE) {
+                self.written[address] = true;
+                self.data[address] = true;
+            }
+        }
+    }
+
+    /// Report the load/store instruction at `address` if the code after it relies on the
+    /// load/store quirk.
+    fn check_mem_acc(&mut self, address: usize) {
+        let load_address = self.load_options.load_address;
+        let message = match scan_mem_acc_site(self.rom, load_address, address) {
+            Some(true) => {
+                "Load/store that relies on the original load/store quirk (incrementing I)"
+            }
+            Some(false) => "Load/store that relies on the modern load/store quirk (I unchanged)",
+            None => return,
+        };
+        self.report(Severity::Info, message.to_string(), Some(address));
+    }
+
+    /// Report the subroutines that never return.
+    fn check_subroutines(&mut self) {
+        let never_returning: Vec<usize> = self.subroutines
+            .difference(&self.returning_subroutines).copied().collect();
+        for subroutine in never_returning {
+            self.report(Severity::Warning, "Subroutine that never returns, which grows the stack \
+                on every call".to_string(), Some(subroutine));
+        }
+    }
+
+    /// Report the reads of memory outside of the ROM's data.
+    fn check_reads(&mut self) {
+        let reads = std::mem::take(&mut self.reads);
+        for read in &reads {
+            let end = read.start + read.len;
+            let what = if read.sprite { "Sprite drawn" } else { "Load" };
+            if end > MEM_SIZE {
+                self.report(Severity::Error, format!("{} from beyond the end of memory", what),
+                    Some(read.site));
+                continue;
+            }
+
+            if read.start < MEM_RESERVED {
+                self.report(Severity::Warning, format!("{} from the reserved memory area", what),
+                    Some(read.site));
+            } else if (read.start..end).any(|address| !self.in_rom(address)
+                && !self.written[address]) {
+                self.report(Severity::Warning, format!("{} from memory beyond the ROM that is \
+                    never written", what), Some(read.site));
+            } else if read.sprite && (read.start..end).any(|address| self.code[address]) {
+                self.report(Severity::Warning, "Sprite drawn from code instead of sprite data"
+                    .to_string(), Some(read.site));
+            }
+
+            for address in read.start..end {
+                self.data[address] = true;
+            }
+        }
+    }
+
+    /// Mark the bytes I is set to point at as data, up to the next instruction. The extent of the
+    /// data isn't always known, e.g. when it is indexed with `ADD I, Vx`.
+    fn mark_referenced_data(&mut self) {
+        for &start in &self.i_targets {
+            let mut address = start;
+            while address < self.rom_end && address >= self.load_options.load_address
+                && !self.code[address] {
+                self.data[address] = true;
+                address += 1;
+            }
+        }
+    }
+
+    /// Report the bytes of the ROM that are neither executed nor used as data. Zero padding isn't
+    /// reported.
+    fn check_unreachable(&mut self) {
+        // Hires programs start with the interpreter patch, which is machine code
+        let start = if self.load_options.hires {
+            self.load_options.entry_point
+        } else {
+            self.load_options.load_address
+        };
+
+        let mut address = start;
+        while address < self.rom_end {
+            if self.code[address] || self.data[address] {
+                address += 1;
+                continue;
+            }
+
+            let range_start = address;
+            while address < self.rom_end && !self.code[address] && !self.data[address] {
+                address += 1;
+            }
+            let bytes = &self.rom[range_start - self.load_options.load_address
+                ..address - self.load_options.load_address];
+            if bytes.len() >= 2 && bytes.iter().any(|&byte| byte != 0) {
+                self.report(Severity::Warning, format!("Unreachable bytes from {} to {}, which \
+                    are neither executed nor used as data", format_address(range_start),
+                    format_address(address - 1)), Some(range_start));
+            }
+        }
+    }
+
+    /// Returns the report of the issues found, the most serious first.
+    fn finish(self) -> LintReport {
+        let mut issues: Vec<LintIssue> = self.issues.into_iter()
+            .map(|((severity, message), addresses)| LintIssue {
+                severity,
+                message,
+                addresses: addresses.into_iter().collect(),
+            })
+            .collect();
+        issues.sort_by_key(|issue| (issue.severity, issue.addresses.first().copied()));
+
+        LintReport {
+            issues,
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Options for how a ROM is loaded: where in memory it is loaded, where execution starts and the
+//! size of the screen. Most ROMs use the standard layout, but some were written for interpreters
+//! with a different one:
+//! - ETI-660 programs are loaded and start at 0x600.
+//! - Hires CHIP-8 programs start with a `1260` jump into a patched interpreter, which shows a
+//!   64x64 screen. The patch ends at 0x2C0, where the program itself starts, and its `0230`
+//!   routine clears the screen.
+//!
+//! A ROM is validated against its layout before it is loaded: it must fit in memory and contain
+//! at least one instruction.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use crate::instruction::Instruction;
+use crate::{MEM_RESERVED, MEM_SIZE};
+
+/// The address ETI-660 programs are loaded and start at.
+pub const ETI_660_LOAD_ADDRESS: usize = 0x600;
+
+/// The first instruction of hires CHIP-8 programs.
+pub const HIRES_START_INSTRUCTION: u16 = 0x1260;
+
+/// The address hires CHIP-8 programs start at, after the interpreter patch.
+pub const HIRES_ENTRY_POINT: usize = 0x2C0;
+
+/// The instruction that clears the screen in hires CHIP-8 programs.
+pub const HIRES_CLEAR_INSTRUCTION: u16 = 0x0230;
+
+#[wasm_bindgen]
+/// Where a ROM is loaded, where execution starts, and the screen mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadOptions {
+    /// The address the ROM is loaded at.
+    pub load_address: usize,
+    /// The address of the first instruction.
+    pub entry_point: usize,
+    /// Show a 64x64 screen, and execute `0230` as a screen clear.
+    pub hires: bool,
+}
+
+#[wasm_bindgen]
+impl LoadOptions {
+    /// The standard layout: loaded and started at 0x200, with a 64x32 screen.
+    pub fn standard() -> Self {
+        LoadOptions {
+            load_address: MEM_RESERVED,
+            entry_point: MEM_RESERVED,
+            hires: false,
+        }
+    }
+
+    /// The layout of ETI-660 programs.
+    pub fn eti_660() -> Self {
+        LoadOptions {
+            load_address: ETI_660_LOAD_ADDRESS,
+            entry_point: ETI_660_LOAD_ADDRESS,
+            hires: false,
+        }
+    }
+
+    /// The layout of hires CHIP-8 programs.
+    pub fn hires() -> Self {
+        LoadOptions {
+            load_address: MEM_RESERVED,
+            entry_point: HIRES_ENTRY_POINT,
+            hires: true,
+        }
+    }
+
+    /// Returns the layout `rom` most likely needs: the hires layout if it starts with the jump of
+    /// hires CHIP-8 programs, and the standard layout otherwise. ETI-660 programs can't be told
+    /// apart from standard ones.
+    pub fn detect(rom: &[u8]) -> Self {
+        if rom.starts_with(&HIRES_START_INSTRUCTION.to_be_bytes()) {
+            LoadOptions::hires()
+        } else {
+            LoadOptions::standard()
+        }
+    }
+}
+
+impl LoadOptions {
+    /// Returns true if the load address and entry point are outside the reserved memory area
+    /// and within memory, and the entry point isn't before the load address.
+    pub fn is_valid(&self) -> bool {
+        (MEM_RESERVED..MEM_SIZE).contains(&self.load_address)
+            && (self.load_address..MEM_SIZE - 1).contains(&self.entry_point)
+    }
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions::standard()
+    }
+}
+
+/// Check that `rom` can be loaded as `load_options` specify. Returns the problems that don't
+/// prevent loading it.
+pub fn validate_rom(rom: &[u8], load_options: &LoadOptions) -> Result<Vec<LoadWarning>, LoadError> {
+    if rom.is_empty() {
+        return Err(LoadError::Empty);
+    }
+    // Execution must start in the loaded ROM
+    if !load_options.is_valid()
+        || load_options.entry_point >= load_options.load_address + rom.len() {
+        return Err(LoadError::InvalidLayout {
+            load_address: load_options.load_address,
+            entry_point: load_options.entry_point,
+        });
+    }
+    let max_size = MEM_SIZE - load_options.load_address;
+    if rom.len() > max_size {
+        return Err(LoadError::TooLarge { size: rom.len(), max_size });
+    }
+    // Instructions are aligned to the entry point, but data can be anywhere in the ROM
+    let alignment = (load_options.entry_point - load_options.load_address) % 2;
+    let has_instruction = rom.get(alignment..).unwrap_or_default().chunks_exact(2)
+        .any(|bytes| {
+            let instr = u16::from_be_bytes([bytes[0], bytes[1]]);
+            Instruction::decode(instr, load_options.hires).is_some()
+        });
+    if !has_instruction {
+        return Err(LoadError::NoInstructions);
+    }
+
+    let mut warnings = Vec::new();
+    if !rom.len().is_multiple_of(2) {
+        warnings.push(LoadWarning::OddLength(rom.len()));
+    }
+
+    Ok(warnings)
+}
+
+/// An error that prevents a ROM from being loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The ROM has no bytes.
+    Empty,
+    /// The ROM doesn't fit in memory after the load address.
+    TooLarge { size: usize, max_size: usize },
+    /// No part of the ROM decodes to an instruction, so it is probably not a ROM.
+    NoInstructions,
+    /// The load address or entry point is in the reserved memory area or out of memory bounds, or
+    /// the entry point isn't in the loaded ROM.
+    InvalidLayout { load_address: usize, entry_point: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Empty => write!(f, "The ROM is empty"),
+            LoadError::TooLarge { size, max_size } => {
+                write!(f, "The ROM is too large to fit in memory ({} bytes, at most {} fit)", size,
+                    max_size)
+            }
+            LoadError::NoInstructions => {
+                write!(f, "The ROM doesn't contain any instructions, it is probably not a ROM")
+            }
+            LoadError::InvalidLayout { load_address, entry_point } => {
+                write!(f, "Invalid load address {:#05X} or entry point {:#05X}: the load address \
+                    must be between 0x200 and 0xFFF, and the entry point must be in the loaded \
+                    ROM and at most 0xFFE", load_address, entry_point)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<LoadError> for JsValue {
+    fn from(err: LoadError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
+
+/// A problem with a ROM that doesn't prevent it from being loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadWarning {
+    /// The ROM has an odd number of bytes, while instructions are 2 bytes long. Holds its size.
+    OddLength(usize),
+}
+
+impl fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadWarning::OddLength(size) => {
+                write!(f, "The ROM has an odd length ({} bytes), it might be truncated", size)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: &[u8] = &[0x00, 0xE0, 0x12, 0x00];
+
+    fn layout(load_address: usize, entry_point: usize) -> LoadOptions {
+        LoadOptions {
+            load_address,
+            entry_point,
+            hires: false,
+        }
+    }
+
+    fn invalid_layout(load_address: usize, entry_point: usize)
+        -> Result<Vec<LoadWarning>, LoadError> {
+        Err(LoadError::InvalidLayout { load_address, entry_point })
+    }
+
+    #[test]
+    fn accepts_valid_layouts() {
+        assert_eq!(validate_rom(ROM, &LoadOptions::standard()), Ok(vec![]));
+        assert_eq!(validate_rom(ROM, &LoadOptions::eti_660()), Ok(vec![]));
+        assert_eq!(validate_rom(ROM, &layout(0x200, 0x202)), Ok(vec![]));
+        assert_eq!(validate_rom(&ROM[..3], &LoadOptions::standard()),
+            Ok(vec![LoadWarning::OddLength(3)]));
+    }
+
+    #[test]
+    fn rejects_invalid_layouts() {
+        // Loaded in the reserved memory area
+        assert_eq!(validate_rom(ROM, &layout(0x100, 0x100)), invalid_layout(0x100, 0x100));
+        // Started before the loaded ROM
+        assert_eq!(validate_rom(ROM, &layout(0x300, 0x200)), invalid_layout(0x300, 0x200));
+        // Started after the loaded ROM
+        assert_eq!(validate_rom(ROM, &layout(0x200, 0x204)), invalid_layout(0x200, 0x204));
+        // Started out of memory
+        assert_eq!(validate_rom(ROM, &layout(0x200, MEM_SIZE)), invalid_layout(0x200, MEM_SIZE));
+    }
+
+    #[test]
+    fn rejects_invalid_roms() {
+        assert_eq!(validate_rom(&[], &LoadOptions::standard()), Err(LoadError::Empty));
+        assert_eq!(validate_rom(&[0xFF; 4], &LoadOptions::standard()),
+            Err(LoadError::NoInstructions));
+        let too_large = vec![0; MEM_SIZE - MEM_RESERVED + 1];
+        assert_eq!(validate_rom(&too_large, &LoadOptions::standard()),
+            Err(LoadError::TooLarge { size: too_large.len(), max_size: MEM_SIZE - MEM_RESERVED }));
+    }
+}
@@ -0,0 +1,238 @@
+//! A database of ROM metadata, which identifies ROMs by the SHA-1 hash of their bytes. It holds
+//! everything needed to run a known ROM well: its platform, quirk profile, speed, key bindings,
+//! palette and memory layout.
+//!
+//! The database is a JSON object mapping lowercase hex SHA-1 hashes to entries:
+//! ```text
+//! {
+//!     "5f518084744bf3cb8733f6e5454dfd1634320563": {
+//!         "title": "Tetris",
+//!         "author": "Fran Dachille",
+//!         "platform": "CHIP-8",
+//!         "description": "Press 5/6 to move piece left/right...",
+//!         "quirks": {
+//!             "original_shift": false,
+//!             "original_mem_acc": false,
+//!             "display_wait": true,
+//!             "wait_for_release": true
+//!         },
+//!         "speed": 600,
+//!         "keymap": { "Left": "5", "Right": "6" },
+//!         "palette": ["#000000", "#ffffff"],
+//!         "load_options": { "load_address": 512, "entry_point": 512, "hires": false }
+//!     }
+//! }
+//! ```
+//! Only the title is required. Missing quirks take their `Quirks::default()` values, which turn
+//! display wait off unlike the CHIP-8 profile, so entries list all of them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use crate::keymap::Keymap;
+use crate::load::LoadOptions;
+use crate::quirks::Quirks;
+use crate::render::PALETTE_SIZE;
+
+/// The database of the bundled ROMs.
+const BUNDLED_DATABASE: &str = include_str!("../static/roms/database.json");
+
+/// The platform a ROM was written for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Platform {
+    /// The original CHIP-8 interpreter and its compatible successors.
+    #[default]
+    #[serde(rename = "CHIP-8")]
+    Chip8,
+    /// SUPER-CHIP, which is not supported by this emulator.
+    #[serde(rename = "SCHIP")]
+    SuperChip,
+    /// XO-CHIP, which is not supported by this emulator.
+    #[serde(rename = "XO-CHIP")]
+    XoChip,
+}
+
+/// The metadata of a ROM.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RomMetadata {
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub platform: Platform,
+    /// How to play the ROM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The quirk profile the ROM needs, if it is known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quirks: Option<Quirks>,
+    /// The recommended number of instructions executed per second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<u32>,
+    /// The memory layout the ROM needs, if it isn't the standard one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_options: Option<LoadOptions>,
+    /// Host key names mapped to hex digits, bound on top of the QWERTY layout.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub keymap: BTreeMap<String, String>,
+    /// Palette colours in `#rrggbb` format, starting from the background colour.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub palette: Vec<String>,
+}
+
+impl RomMetadata {
+    /// Returns the keymap of the ROM: the QWERTY layout, with the ROM's own bindings on top.
+    pub fn keymap(&self) -> Result<Keymap, MetadataError> {
+        let mut keymap = Keymap::qwerty();
+        for (host_key, hex_key) in &self.keymap {
+            let hex_key = u8::from_str_radix(hex_key, 16)
+                .map_err(|_| self.invalid(&format!("Invalid hex key `{}`", hex_key)))?;
+            keymap.bind(host_key, hex_key).map_err(|err| self.invalid(&err.to_string()))?;
+        }
+
+        Ok(keymap)
+    }
+
+    /// Returns the palette colours of the ROM in 0xRRGGBB format.
+    pub fn palette_colors(&self) -> Result<Vec<u32>, MetadataError> {
+        if self.palette.len() > PALETTE_SIZE {
+            return Err(self.invalid("Too many palette colours"));
+        }
+
+        self.palette.iter().map(|color| {
+            color.strip_prefix('#').filter(|hex| hex.len() == 6)
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| self.invalid(&format!("Invalid colour `{}`", color)))
+        }).collect()
+    }
+
+    /// Returns an error about an invalid field of this entry.
+    fn invalid(&self, message: &str) -> MetadataError {
+        MetadataError::InvalidEntry {
+            title: self.title.clone(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Returns the lowercase hex SHA-1 hash of `rom`, which identifies it in the database.
+pub fn rom_sha1(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+#[wasm_bindgen]
+/// ROM metadata keyed by the SHA-1 hash of the ROM bytes.
+pub struct RomDatabase {
+    entries: HashMap<String, RomMetadata>,
+}
+
+#[wasm_bindgen]
+impl RomDatabase {
+    /// Construct a database without any entries.
+    pub fn new() -> Self {
+        RomDatabase {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Construct a database with the entries of the bundled ROMs.
+    pub fn bundled() -> Self {
+        let mut database = RomDatabase::new();
+        database.extend_from_json(BUNDLED_DATABASE).expect("Invalid bundled ROM database");
+
+        database
+    }
+
+    /// Add the entries of a database in JSON format, replacing existing entries of the same ROMs.
+    /// Used to extend the bundled database locally. Returns the number of entries added. If the
+    /// JSON or any of its entries is invalid, nothing is added.
+    pub fn extend_from_json(&mut self, json: &str) -> Result<usize, MetadataError> {
+        let entries: HashMap<String, RomMetadata> = serde_json::from_str(json)
+            .map_err(|err| MetadataError::Json(err.to_string()))?;
+
+        let mut new_entries = Vec::with_capacity(entries.len());
+        for (sha1, metadata) in entries {
+            if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(MetadataError::InvalidHash(sha1));
+            }
+            metadata.keymap()?;
+            metadata.palette_colors()?;
+            if metadata.load_options.is_some_and(|load_options| !load_options.is_valid()) {
+                return Err(metadata.invalid("Load address or entry point out of memory bounds"));
+            }
+
+            new_entries.push((sha1.to_ascii_lowercase(), metadata));
+        }
+
+        let num_entries = new_entries.len();
+        self.entries.extend(new_entries);
+        Ok(num_entries)
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the metadata of `rom` in JSON format, in the same form as the database entries.
+    pub fn lookup_json(&self, rom: &[u8]) -> Option<String> {
+        self.lookup(rom).map(|metadata| {
+            serde_json::to_string(metadata).expect("Failed to serialize ROM metadata")
+        })
+    }
+}
+
+impl RomDatabase {
+    /// Returns the metadata of `rom`, if it is in the database.
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomMetadata> {
+        self.entries.get(&rom_sha1(rom))
+    }
+}
+
+impl Default for RomDatabase {
+    fn default() -> Self {
+        RomDatabase::new()
+    }
+}
+
+/// An error in the ROM metadata database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataError {
+    /// The database is not valid JSON, or doesn't have the expected structure.
+    Json(String),
+    /// A key of the database is not a hex SHA-1 hash.
+    InvalidHash(String),
+    /// An entry has an invalid keymap, palette or memory layout.
+    InvalidEntry { title: String, message: String },
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::Json(message) => write!(f, "Invalid ROM database: {}", message),
+            MetadataError::InvalidHash(sha1) => {
+                write!(f, "Invalid ROM database: `{}` is not a SHA-1 hash", sha1)
+            }
+            MetadataError::InvalidEntry { title, message } => {
+                write!(f, "Invalid ROM database entry `{}`: {}", title, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+impl From<MetadataError> for JsValue {
+    fn from(err: MetadataError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
@@ -0,0 +1,361 @@
+//! Input movies: recordings of all the key events of a session, with everything else needed to
+//! replay the session deterministically and verify that it ended in the same state.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use wasm_bindgen::JsValue;
+
+use crate::input::{KeyEvent, NUM_KEYS};
+use crate::load::{LoadError, LoadOptions};
+use crate::quirks::Quirks;
+use crate::{MEM_RESERVED, MEM_SIZE};
+
+/// The first line of every movie file, which also identifies the format version.
+const MOVIE_HEADER: &str = "CHIP-8 movie v1";
+
+/// A key event, with the frame and the position in that frame at which it was applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovieEvent {
+    pub frame: u32,
+    /// The position in the frame, in the range [0, 1].
+    pub position: f32,
+    pub key_event: KeyEvent,
+}
+
+/// A recorded session: the conditions at the start of the session, the key events, and the
+/// state at its end.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    /// The hash of the cpu state before the first frame, which identifies the ROM.
+    pub initial_state_hash: u64,
+    pub rng_seed: u32,
+    pub quirks: Quirks,
+    pub load_options: LoadOptions,
+    pub clock_rate_hz: u32,
+    pub vip_timing: bool,
+    /// The key events, in the order they were applied.
+    pub events: Vec<MovieEvent>,
+    /// The number of frames that were run.
+    pub num_frames: u32,
+    /// The hash of the cpu state after the last frame.
+    pub final_state_hash: u64,
+}
+
+impl Movie {
+    /// Encode the movie as text: a header line, `name value` lines for the session conditions,
+    /// and a `key <frame> <position> <hex key> <down|up>` line for every key event.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\n", MOVIE_HEADER);
+        text += &format!("initial_state {:016x}\n", self.initial_state_hash);
+        text += &format!("rng_seed {}\n", self.rng_seed);
+        text += &format!("original_shift {}\n", self.quirks.original_shift);
+        text += &format!("original_mem_acc {}\n", self.quirks.original_mem_acc);
+        text += &format!("display_wait {}\n", self.quirks.display_wait);
+        text += &format!("wait_for_release {}\n", self.quirks.wait_for_release);
+        text += &format!("load_address {:#x}\n", self.load_options.load_address);
+        text += &format!("entry_point {:#x}\n", self.load_options.entry_point);
+        text += &format!("hires {}\n", self.load_options.hires);
+        text += &format!("clock_rate {}\n", self.clock_rate_hz);
+        text += &format!("vip_timing {}\n", self.vip_timing);
+        text += &format!("frames {}\n", self.num_frames);
+        text += &format!("final_state {:016x}\n", self.final_state_hash);
+
+        // The position is written in the shortest form that parses back to the same value, so
+        // the events are replayed at exactly the same instruction
+        for event in &self.events {
+            text += &format!("key {} {} {:X} {}\n", event.frame, event.position,
+                event.key_event.key, if event.key_event.pressed { "down" } else { "up" });
+        }
+
+        text
+    }
+
+    /// Parse a movie encoded by `to_text`.
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut lines = text.lines().enumerate()
+            .map(|(line_idx, line)| (line_idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty());
+
+        match lines.next() {
+            Some((_, MOVIE_HEADER)) => {}
+            Some((line, _)) => return Err(parse_error(line, "Not a CHIP-8 movie")),
+            None => return Err(parse_error(1, "Empty movie")),
+        }
+
+        // The session conditions by name, with the line they are on
+        let mut fields = HashMap::new();
+        let mut events: Vec<MovieEvent> = Vec::new();
+        for (line, content) in lines {
+            let words: Vec<&str> = content.split_whitespace().collect();
+            match words.as_slice() {
+                ["key", frame, position, key, direction] => {
+                    let frame = frame.parse()
+                        .map_err(|_| parse_error(line, "Invalid key event frame"))?;
+                    let position = position.parse::<f32>().ok()
+                        .filter(|position| (0.0..=1.0).contains(position))
+                        .ok_or_else(|| parse_error(line, "Invalid key event position"))?;
+                    let key = u8::from_str_radix(key, 16).ok()
+                        .filter(|&key| (key as usize) < NUM_KEYS)
+                        .ok_or_else(|| parse_error(line, "Invalid key"))?;
+                    let pressed = match *direction {
+                        "down" => true,
+                        "up" => false,
+                        _ => return Err(parse_error(line, "Key event must be `down` or `up`")),
+                    };
+
+                    let out_of_order = events.last().is_some_and(|last| (last.frame, last.position)
+                        > (frame, position));
+                    if out_of_order {
+                        return Err(parse_error(line, "Key events are out of order"));
+                    }
+
+                    events.push(MovieEvent { frame, position, key_event: KeyEvent { key, pressed } });
+                }
+                [name, value] => {
+                    if fields.insert(*name, (line, *value)).is_some() {
+                        return Err(parse_error(line, &format!("Duplicate `{}`", name)));
+                    }
+                }
+                _ => return Err(parse_error(line, "Expected `name value` or a key event")),
+            }
+        }
+
+        let field = |name: &'static str| {
+            fields.get(name).copied().ok_or(MovieError::MissingField(name))
+        };
+        let parse_field = |name: &'static str| -> Result<u32, MovieError> {
+            let (line, value) = field(name)?;
+            value.parse().map_err(|_| parse_error(line, &format!("Invalid `{}`", name)))
+        };
+        let parse_bool_field = |name: &'static str| -> Result<bool, MovieError> {
+            let (line, value) = field(name)?;
+            value.parse().map_err(|_| parse_error(line, &format!("Invalid `{}`", name)))
+        };
+        let parse_address_field = |name: &'static str| -> Result<usize, MovieError> {
+            let (line, value) = field(name)?;
+            value.strip_prefix("0x").and_then(|hex| usize::from_str_radix(hex, 16).ok())
+                .filter(|address| (MEM_RESERVED..MEM_SIZE - 1).contains(address))
+                .ok_or_else(|| parse_error(line, &format!("Invalid `{}`", name)))
+        };
+        let parse_hash_field = |name: &'static str| -> Result<u64, MovieError> {
+            let (line, value) = field(name)?;
+            u64::from_str_radix(value, 16)
+                .map_err(|_| parse_error(line, &format!("Invalid `{}`", name)))
+        };
+
+        Ok(Movie {
+            initial_state_hash: parse_hash_field("initial_state")?,
+            rng_seed: parse_field("rng_seed")?,
+            quirks: Quirks {
+                original_shift: parse_bool_field("original_shift")?,
+                original_mem_acc: parse_bool_field("original_mem_acc")?,
+                display_wait: parse_bool_field("display_wait")?,
+                wait_for_release: parse_bool_field("wait_for_release")?,
+            },
+            load_options: LoadOptions {
+                load_address: parse_address_field("load_address")?,
+                entry_point: parse_address_field("entry_point")?,
+                hires: parse_bool_field("hires")?,
+            },
+            clock_rate_hz: parse_field("clock_rate")?,
+            vip_timing: parse_bool_field("vip_timing")?,
+            events,
+            num_frames: parse_field("frames")?,
+            final_state_hash: parse_hash_field("final_state")?,
+        })
+    }
+}
+
+/// Returns a parse error at `line` of the movie text.
+fn parse_error(line: usize, message: &str) -> MovieError {
+    MovieError::Parse { line, message: message.to_string() }
+}
+
+/// Replays the key events of a movie, frame by frame.
+pub struct MoviePlayer {
+    movie: Movie,
+    // The index of the first event that wasn't replayed yet
+    next_event: usize,
+}
+
+impl MoviePlayer {
+    /// Construct a player at the start of `movie`.
+    pub fn new(movie: Movie) -> Self {
+        MoviePlayer {
+            movie,
+            next_event: 0,
+        }
+    }
+
+    /// Returns the movie being replayed.
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Returns the events of `frame` (and of any earlier frame) that weren't replayed yet.
+    pub fn take_frame_events(&mut self, frame: u32) -> &[MovieEvent] {
+        let start = self.next_event;
+        let events = &self.movie.events;
+        while self.next_event < events.len() && events[self.next_event].frame <= frame {
+            self.next_event += 1;
+        }
+
+        &events[start..self.next_event]
+    }
+
+    /// Verify the replayed session against the movie, where `frames_run` frames were run and
+    /// `final_state_hash` is the hash of the cpu state after them.
+    pub fn verify(&self, frames_run: u32, final_state_hash: u64) -> Result<(), MovieError> {
+        if frames_run != self.movie.num_frames {
+            return Err(MovieError::FrameCountMismatch {
+                expected: self.movie.num_frames,
+                actual: frames_run,
+            });
+        }
+
+        if final_state_hash != self.movie.final_state_hash {
+            return Err(MovieError::StateMismatch {
+                expected: self.movie.final_state_hash,
+                actual: final_state_hash,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A 64-bit FNV-1a hasher. State hashes must stay the same across platforms and builds, which
+/// the std hashers don't guarantee.
+pub struct StateHasher {
+    hash: u64,
+}
+
+impl StateHasher {
+    /// Construct a hasher with the FNV offset basis.
+    pub fn new() -> Self {
+        StateHasher { hash: 0xcbf2_9ce4_8422_2325 }
+    }
+
+    /// Hash `bytes` into the state.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash ^= byte as u64;
+            self.hash = self.hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    /// Returns the hash of all the bytes written so far.
+    pub fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+impl Default for StateHasher {
+    fn default() -> Self {
+        StateHasher::new()
+    }
+}
+
+/// An error in recording, parsing or replaying a movie.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MovieError {
+    /// The movie text is malformed at the given line.
+    Parse { line: usize, message: String },
+    /// The movie text doesn't have a required field.
+    MissingField(&'static str),
+    /// The state before the first frame doesn't match, so a different ROM was loaded.
+    RomMismatch,
+    /// A different number of frames was run than in the movie.
+    FrameCountMismatch { expected: u32, actual: u32 },
+    /// The playback desynced, so the final state doesn't match.
+    StateMismatch { expected: u64, actual: u64 },
+    /// The ROM can't be loaded as the movie specifies.
+    Load(LoadError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Parse { line, message } => {
+                write!(f, "Invalid movie at line {}: {}", line, message)
+            }
+            MovieError::MissingField(name) => write!(f, "Invalid movie: missing `{}`", name),
+            MovieError::RomMismatch => write!(f, "The movie was recorded with a different ROM"),
+            MovieError::FrameCountMismatch { expected, actual } => {
+                write!(f, "The movie has {} frames, but {} were run", expected, actual)
+            }
+            MovieError::StateMismatch { expected, actual } => {
+                write!(f, "Playback desynced: the final state hash is {:016x} instead of {:016x}",
+                    actual, expected)
+            }
+            MovieError::Load(err) => write!(f, "The movie's ROM can't be loaded: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<MovieError> for JsValue {
+    fn from(err: MovieError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_movie() -> Movie {
+        Movie {
+            initial_state_hash: 0x0123_4567_89AB_CDEF,
+            rng_seed: 42,
+            quirks: Quirks {
+                original_shift: true,
+                original_mem_acc: false,
+                display_wait: true,
+                wait_for_release: false,
+            },
+            load_options: LoadOptions::eti_660(),
+            clock_rate_hz: 1000,
+            vip_timing: true,
+            events: vec![
+                MovieEvent {
+                    frame: 3,
+                    position: 0.1,
+                    key_event: KeyEvent { key: 0xA, pressed: true },
+                },
+                MovieEvent {
+                    frame: 3,
+                    position: 2.0 / 3.0,
+                    key_event: KeyEvent { key: 0xA, pressed: false },
+                },
+            ],
+            num_frames: 120,
+            final_state_hash: 0xFEDC_BA98_7654_3210,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let movie = test_movie();
+        assert_eq!(Movie::parse(&movie.to_text()), Ok(movie));
+    }
+
+    #[test]
+    fn requires_load_options() {
+        let text = test_movie().to_text();
+        let without_entry_point: String = text.lines()
+            .filter(|line| !line.starts_with("entry_point"))
+            .map(|line| format!("{}\n", line)).collect();
+
+        assert_eq!(Movie::parse(&without_entry_point),
+            Err(MovieError::MissingField("entry_point")));
+    }
+
+    #[test]
+    fn rejects_out_of_order_events() {
+        let text = test_movie().to_text() + "key 2 0.5 1 down\n";
+        assert!(matches!(Movie::parse(&text), Err(MovieError::Parse { .. })));
+    }
+}
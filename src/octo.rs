@@ -0,0 +1,920 @@
+//! A compiler for the Octo assembly language, which Octo cartridges store their programs in.
+//!
+//! The CHIP-8 subset of the language is supported: every CHIP-8 instruction in Octo's syntax,
+//! labels, `:const`, `:alias`, `:macro`, `:calc`, `:byte`, `:org`, `:next`, `:unpack` and `:call`,
+//! the `if`/`then`, `if`/`begin`/`else`/`end` and `loop`/`while`/`again` control flow, and the
+//! `<`, `>`, `<=` and `>=` comparisons (which use VF). Numbers and constants in statement
+//! position are assembled as bytes, and any other name is a call of the subroutine it labels.
+//! SUPER-CHIP and XO-CHIP instructions, and string modes, are rejected.
+//!
+//! Execution starts at the `main` label: like Octo, a jump to it is placed at 0x200.
+//! `:calc` expressions are evaluated right to left without operator precedence, like Octo does.
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// The address programs are compiled for.
+const PROGRAM_START: usize = 0x200;
+/// The end of memory, which programs must fit before.
+const MEMORY_END: usize = 0x1000;
+
+/// The instructions of the SUPER-CHIP and XO-CHIP extensions, which aren't supported.
+const EXTENSION_KEYWORDS: &[&str] = &["hires", "lores", "scroll-down", "scroll-up",
+    "scroll-left", "scroll-right", "exit", "saveflags", "loadflags", "plane", "audio", "pitch",
+    "bighex", "long", ":stringmode", ":pointer"];
+
+/// An error in an Octo program, with the line it is on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Compile the Octo program `source` into the bytes of a ROM loaded at 0x200.
+pub fn compile(source: &str) -> Result<Vec<u8>, CompileError> {
+    let mut compiler = Compiler::new(tokenize(source));
+    compiler.compile()?;
+    Ok(compiler.rom)
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// Split `source` into whitespace separated tokens, dropping the comments.
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        // Comments run until the end of the line
+        let code = line.split('#').next().unwrap_or("");
+        tokens.extend(code.split_whitespace().map(|text| Token {
+            text: text.to_string(),
+            line: index + 1,
+        }));
+    }
+    tokens
+}
+
+/// A reference to a label that wasn't defined yet, which is patched at the end.
+enum Fixup {
+    /// The low 12 bits of the instruction at `address`.
+    Address { address: usize, label: String, line: usize },
+    /// The `v0 := ...` and `v1 := ...` instructions at `address` emitted by `:unpack`.
+    Unpack { address: usize, nibble: u8, label: String, line: usize },
+}
+
+/// An open control flow construct.
+enum Block {
+    // `if ... begin`, with the address of the jump over the block
+    If(usize),
+    // `else`, with the address of the jump over the else block
+    Else(usize),
+    // `loop`, with its start and the addresses of the jumps out of it of its `while`s
+    Loop { start: usize, exits: Vec<usize> },
+}
+
+/// The right operand of an instruction or comparison.
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+/// A condition, as in `if vx == 5 then`.
+enum Condition {
+    Equal(u8, Operand),
+    NotEqual(u8, Operand),
+    Key(u8),
+    NotKey(u8),
+    Less(u8, Operand),
+    Greater(u8, Operand),
+    LessEqual(u8, Operand),
+    GreaterEqual(u8, Operand),
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Compiler {
+    // The tokens left to compile, in reverse order, so macros can be expanded in place
+    tokens: Vec<Token>,
+    // The line of the last token taken, for errors
+    line: usize,
+
+    rom: Vec<u8>,
+    // The address the next byte is emitted at
+    here: usize,
+
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+}
+
+impl Compiler {
+    fn new(mut tokens: Vec<Token>) -> Self {
+        tokens.reverse();
+        Compiler {
+            tokens,
+            line: 1,
+            rom: Vec::new(),
+            here: PROGRAM_START,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    fn compile(&mut self) -> Result<(), CompileError> {
+        let has_main = self.tokens.windows(2).any(|pair| pair[1].text == ":"
+            && pair[0].text == "main");
+        if has_main {
+            self.emit_address_ref(0x1000, "main")?;
+        }
+
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if !self.blocks.is_empty() {
+            return Err(self.error("Missing `end` or `again` at the end of the program"));
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            match fixup {
+                Fixup::Address { address, label, line } => {
+                    let target = self.label_address(&label, line)?;
+                    let offset = address - PROGRAM_START;
+                    self.rom[offset] = (self.rom[offset] & 0xF0) | (target >> 8) as u8;
+                    self.rom[offset + 1] = target as u8;
+                }
+                Fixup::Unpack { address, nibble, label, line } => {
+                    let target = self.label_address(&label, line)?;
+                    let offset = address - PROGRAM_START;
+                    self.rom[offset + 1] = (nibble << 4) | (target >> 8) as u8;
+                    self.rom[offset + 3] = target as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile the next statement.
+    fn statement(&mut self) -> Result<(), CompileError> {
+        let token = self.next()?;
+        match token.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(&name, self.here)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.value()?;
+                self.constants.insert(name, value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            }
+            ":macro" => self.define_macro()?,
+            ":calc" => {
+                let name = self.next()?;
+                self.expect("{")?;
+                let value = self.expression()?;
+                self.expect("}")?;
+                self.constants.insert(name, value);
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.next()?;
+                    let value = self.expression()?;
+                    self.expect("}")?;
+                    value
+                } else {
+                    self.value()?
+                };
+                let byte = self.to_byte(value)?;
+                self.emit_byte(byte)?;
+            }
+            ":org" => {
+                let value = self.value()?;
+                let address = self.to_address(value)?;
+                if address < PROGRAM_START {
+                    return Err(self.error("`:org` before the start of the program"));
+                }
+                self.here = address;
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":unpack" => {
+                let nibble = self.value()?;
+                let nibble = self.to_byte(nibble)?;
+                if nibble > 0xF {
+                    return Err(self.error("The `:unpack` prefix must be a nibble"));
+                }
+                let name = self.next()?;
+                match self.address_of(&name)? {
+                    Some(address) => {
+                        self.emit(0x6000 | ((nibble as u16) << 4) | (address >> 8) as u16)?;
+                        self.emit(0x6100 | (address & 0xFF) as u16)?;
+                    }
+                    None => {
+                        self.fixups.push(Fixup::Unpack {
+                            address: self.here,
+                            nibble,
+                            label: name,
+                            line: self.line,
+                        });
+                        self.emit(0x6000)?;
+                        self.emit(0x6100)?;
+                    }
+                }
+            }
+            ":call" => {
+                let name = self.next()?;
+                self.emit_address_ref(0x2000, &name)?;
+            }
+            // Debugger directives don't emit anything
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ";" | "return" => self.emit(0x00EE)?,
+            "clear" => self.emit(0x00E0)?,
+            "bcd" => self.register_instruction(0xF033)?,
+            "save" => self.register_instruction(0xF055)?,
+            "load" => self.register_instruction(0xF065)?,
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let height = self.value()?;
+                let height = self.to_byte(height)?;
+                if height > 0xF {
+                    return Err(self.error("Sprites are at most 15 rows high"));
+                }
+                self.emit(0xD000 | ((x as u16) << 8) | ((y as u16) << 4) | height as u16)?;
+            }
+            "jump" => {
+                let name = self.next()?;
+                self.emit_address_ref(0x1000, &name)?;
+            }
+            "jump0" => {
+                let name = self.next()?;
+                self.emit_address_ref(0xB000, &name)?;
+            }
+            "native" => {
+                let name = self.next()?;
+                self.emit_address_ref(0x0000, &name)?;
+            }
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let opcode = if token == "delay" { 0xF015 } else { 0xF018 };
+                self.register_instruction(opcode)?;
+            }
+            "i" => self.i_statement()?,
+            "if" => {
+                let condition = self.condition()?;
+                match self.next()?.as_str() {
+                    "then" => self.emit_skip(&condition, false)?,
+                    "begin" => {
+                        self.emit_skip(&condition, true)?;
+                        let jump = self.here;
+                        self.emit(0x1000)?;
+                        self.blocks.push(Block::If(jump));
+                    }
+                    other => return Err(self.error(&format!("Expected `then` or `begin`, \
+                        found `{}`", other))),
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some(Block::If(jump)) => {
+                    let else_jump = self.here;
+                    self.emit(0x1000)?;
+                    self.patch_jump(jump, self.here);
+                    self.blocks.push(Block::Else(else_jump));
+                }
+                _ => return Err(self.error("`else` without `if ... begin`")),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If(jump)) | Some(Block::Else(jump)) => self.patch_jump(jump, self.here),
+                _ => return Err(self.error("`end` without `if ... begin`")),
+            },
+            "loop" => self.blocks.push(Block::Loop { start: self.here, exits: Vec::new() }),
+            "while" => {
+                let condition = self.condition()?;
+                self.emit_skip(&condition, true)?;
+                let jump = self.here;
+                self.emit(0x1000)?;
+                let innermost_loop = self.blocks.iter_mut().rev().find_map(|block| match block {
+                    Block::Loop { exits, .. } => Some(exits),
+                    _ => None,
+                });
+                match innermost_loop {
+                    Some(exits) => exits.push(jump),
+                    None => return Err(self.error("`while` outside of a loop")),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, exits }) => {
+                    self.emit(0x1000 | start as u16)?;
+                    for exit in exits {
+                        self.patch_jump(exit, self.here);
+                    }
+                }
+                _ => return Err(self.error("`again` without `loop`")),
+            },
+            _ if EXTENSION_KEYWORDS.contains(&token.as_str()) || token.starts_with(":assert") => {
+                return Err(self.error(&format!("`{}` is not supported, only CHIP-8 programs \
+                    can be loaded", token)));
+            }
+            _ => {
+                if let Some(register) = self.parse_register(&token) {
+                    return self.register_statement(register);
+                }
+                if let Some(value) = self.parse_value(&token) {
+                    let byte = self.to_byte(value)?;
+                    return self.emit_byte(byte);
+                }
+                if self.macros.contains_key(&token) {
+                    return self.expand_macro(&token);
+                }
+                if token.starts_with(':') || token == "{" || token == "}" {
+                    return Err(self.error(&format!("Unexpected `{}`", token)));
+                }
+                // Any other name calls the subroutine it labels
+                self.emit_address_ref(0x2000, &token)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile a statement starting with `i`.
+    fn i_statement(&mut self) -> Result<(), CompileError> {
+        match self.next()?.as_str() {
+            ":=" => {
+                if self.peek() == Some("hex") {
+                    self.next()?;
+                    return self.register_instruction(0xF029);
+                }
+                let name = self.next()?;
+                if EXTENSION_KEYWORDS.contains(&name.as_str()) {
+                    return Err(self.error(&format!("`{}` is not supported, only CHIP-8 programs \
+                        can be loaded", name)));
+                }
+                self.emit_address_ref(0xA000, &name)
+            }
+            "+=" => self.register_instruction(0xF01E),
+            other => Err(self.error(&format!("Expected `:=` or `+=` after `i`, found `{}`", other))),
+        }
+    }
+
+    /// Compile a statement starting with the register `x`.
+    fn register_statement(&mut self, x: u8) -> Result<(), CompileError> {
+        let x_bits = (x as u16) << 8;
+        let operator = self.next()?;
+        if operator == ":=" {
+            match self.peek() {
+                Some("key") => {
+                    self.next()?;
+                    return self.emit(0xF00A | x_bits);
+                }
+                Some("delay") => {
+                    self.next()?;
+                    return self.emit(0xF007 | x_bits);
+                }
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.value()?;
+                    let mask = self.to_byte(mask)?;
+                    return self.emit(0xC000 | x_bits | mask as u16);
+                }
+                _ => {}
+            }
+        }
+
+        let operand = self.operand()?;
+        let word = match (operator.as_str(), operand) {
+            (":=", Operand::Byte(byte)) => 0x6000 | byte as u16,
+            ("+=", Operand::Byte(byte)) => 0x7000 | byte as u16,
+            ("-=", Operand::Byte(byte)) => 0x7000 | byte.wrapping_neg() as u16,
+            (_, Operand::Register(y)) => {
+                let y_bits = (y as u16) << 4;
+                let operation = match operator.as_str() {
+                    ":=" => 0x0,
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    "+=" => 0x4,
+                    "-=" => 0x5,
+                    ">>=" => 0x6,
+                    "=-" => 0x7,
+                    "<<=" => 0xE,
+                    _ => return Err(self.error(&format!("Unknown operator `{}`", operator))),
+                };
+                0x8000 | y_bits | operation
+            }
+            _ => return Err(self.error(&format!("`{}` needs a register operand", operator))),
+        };
+        self.emit(word | x_bits)
+    }
+
+    /// Parse the condition of an `if` or `while`.
+    fn condition(&mut self) -> Result<Condition, CompileError> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        let condition = match operator.as_str() {
+            "key" => Condition::Key(x),
+            "-key" => Condition::NotKey(x),
+            "==" => Condition::Equal(x, self.operand()?),
+            "!=" => Condition::NotEqual(x, self.operand()?),
+            "<" => Condition::Less(x, self.operand()?),
+            ">" => Condition::Greater(x, self.operand()?),
+            "<=" => Condition::LessEqual(x, self.operand()?),
+            ">=" => Condition::GreaterEqual(x, self.operand()?),
+            _ => return Err(self.error(&format!("Unknown comparison `{}`", operator))),
+        };
+        Ok(condition)
+    }
+
+    /// Emit the instructions that skip the next instruction when `condition` is `when`.
+    fn emit_skip(&mut self, condition: &Condition, when: bool) -> Result<(), CompileError> {
+        let x_bits = |x: u8| (x as u16) << 8;
+        match *condition {
+            Condition::Equal(x, Operand::Byte(byte)) => {
+                let opcode = if when { 0x3000 } else { 0x4000 };
+                self.emit(opcode | x_bits(x) | byte as u16)
+            }
+            Condition::Equal(x, Operand::Register(y)) => {
+                let opcode = if when { 0x5000 } else { 0x9000 };
+                self.emit(opcode | x_bits(x) | (y as u16) << 4)
+            }
+            Condition::NotEqual(x, operand) => self.emit_skip(&Condition::Equal(x, operand), !when),
+            Condition::Key(x) => self.emit(if when { 0xE09E } else { 0xE0A1 } | x_bits(x)),
+            Condition::NotKey(x) => self.emit_skip(&Condition::Key(x), !when),
+            _ => {
+                let true_flag = self.emit_comparison(condition)?;
+                let flag = if when { true_flag } else { 1 - true_flag };
+                // Skip if VF holds the flag
+                self.emit(0x3F00 | flag as u16)
+            }
+        }
+    }
+
+    /// Emit the instructions that compute an ordering comparison into VF, and returns the value
+    /// VF holds when the comparison is true.
+    fn emit_comparison(&mut self, condition: &Condition) -> Result<u8, CompileError> {
+        // `VF := a; VF -= b` leaves VF = 1 exactly when a >= b
+        match *condition {
+            Condition::GreaterEqual(x, Operand::Register(y)) => {
+                self.emit_flag_difference(Operand::Register(x), y)?;
+                Ok(1)
+            }
+            Condition::GreaterEqual(_, Operand::Byte(0)) => {
+                self.emit(0x6F01)?;
+                Ok(1)
+            }
+            Condition::GreaterEqual(x, Operand::Byte(byte)) => {
+                // x >= n exactly when not n - 1 >= x
+                self.emit_flag_difference(Operand::Byte(byte - 1), x)?;
+                Ok(0)
+            }
+            Condition::LessEqual(x, operand) => {
+                self.emit_flag_difference(operand, x)?;
+                Ok(1)
+            }
+            Condition::Greater(x, operand) => {
+                self.emit_flag_difference(operand, x)?;
+                Ok(0)
+            }
+            Condition::Less(x, Operand::Register(y)) => {
+                self.emit_flag_difference(Operand::Register(x), y)?;
+                Ok(0)
+            }
+            Condition::Less(_, Operand::Byte(0)) => {
+                self.emit(0x6F00)?;
+                Ok(1)
+            }
+            Condition::Less(x, Operand::Byte(byte)) => {
+                // x < n exactly when n - 1 >= x
+                self.emit_flag_difference(Operand::Byte(byte - 1), x)?;
+                Ok(1)
+            }
+            _ => unreachable!("Not an ordering comparison"),
+        }
+    }
+
+    /// Emit `VF := a; VF -= b`.
+    fn emit_flag_difference(&mut self, a: Operand, b: u8) -> Result<(), CompileError> {
+        match a {
+            Operand::Register(register) => self.emit(0x8F00 | (register as u16) << 4)?,
+            Operand::Byte(byte) => self.emit(0x6F00 | byte as u16)?,
+        }
+        self.emit(0x8F05 | (b as u16) << 4)
+    }
+
+    /// Compile the rest of an instruction of the form `opcode vx`.
+    fn register_instruction(&mut self, opcode: u16) -> Result<(), CompileError> {
+        let x = self.register()?;
+        if self.peek() == Some("-") {
+            return Err(self.error("Register ranges are not supported, only CHIP-8 programs can be \
+                loaded"));
+        }
+        self.emit(opcode | (x as u16) << 8)
+    }
+
+    fn define_label(&mut self, name: &str, address: usize) -> Result<(), CompileError> {
+        if self.labels.insert(name.to_string(), address).is_some() {
+            return Err(self.error(&format!("The label `{}` is defined twice", name)));
+        }
+        Ok(())
+    }
+
+    /// Parse a macro definition: `:macro name args... { body }`.
+    fn define_macro(&mut self) -> Result<(), CompileError> {
+        let name = self.next()?;
+        let mut args = Vec::new();
+        loop {
+            let arg = self.next()?;
+            if arg == "{" {
+                break;
+            }
+            args.push(arg);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.take()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name, Macro { args, body });
+        Ok(())
+    }
+
+    /// Replace the call of the macro `name` with its body.
+    fn expand_macro(&mut self, name: &str) -> Result<(), CompileError> {
+        let num_args = self.macros[name].args.len();
+        let mut values = Vec::with_capacity(num_args);
+        for _ in 0..num_args {
+            values.push(self.next()?);
+        }
+
+        let line = self.line;
+        let mac = &self.macros[name];
+        let expansion: Vec<Token> = mac.body.iter().rev().map(|token| {
+            let text = match mac.args.iter().position(|arg| *arg == token.text) {
+                Some(index) => values[index].clone(),
+                None => token.text.clone(),
+            };
+            // Errors in the expansion are reported at the call
+            Token { text, line }
+        }).collect();
+        self.tokens.extend(expansion);
+        Ok(())
+    }
+
+    /// Evaluate a `:calc` expression, right to left.
+    fn expression(&mut self) -> Result<f64, CompileError> {
+        let left = self.term()?;
+        let operator = match self.peek() {
+            Some(operator) if is_binary_operator(operator) => self.next()?,
+            _ => return Ok(left),
+        };
+        let right = self.expression()?;
+        let value = match operator.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => ((left as i64) & (right as i64)) as f64,
+            "|" => ((left as i64) | (right as i64)) as f64,
+            "^" => ((left as i64) ^ (right as i64)) as f64,
+            "<<" => ((left as i64) << (right as i64)) as f64,
+            ">>" => ((left as i64) >> (right as i64)) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as u8 as f64,
+            "<=" => (left <= right) as u8 as f64,
+            "==" => (left == right) as u8 as f64,
+            "!=" => (left != right) as u8 as f64,
+            ">=" => (left >= right) as u8 as f64,
+            ">" => (left > right) as u8 as f64,
+            _ => unreachable!("Checked by is_binary_operator"),
+        };
+        Ok(value)
+    }
+
+    /// Evaluate a term of a `:calc` expression: a value, a parenthesized expression or a unary
+    /// operator applied to a term.
+    fn term(&mut self) -> Result<f64, CompileError> {
+        let token = self.next()?;
+        let value = match token.as_str() {
+            "(" => {
+                let value = self.expression()?;
+                self.expect(")")?;
+                value
+            }
+            "-" => -self.term()?,
+            "~" => !(self.term()? as i64) as f64,
+            "!" => (self.term()? == 0.0) as u8 as f64,
+            "sin" => self.term()?.sin(),
+            "cos" => self.term()?.cos(),
+            "tan" => self.term()?.tan(),
+            "exp" => self.term()?.exp(),
+            "log" => self.term()?.ln(),
+            "abs" => self.term()?.abs(),
+            "sqrt" => self.term()?.sqrt(),
+            "sign" => self.term()?.signum(),
+            "ceil" => self.term()?.ceil(),
+            "floor" => self.term()?.floor(),
+            "@" => {
+                let address = self.term()?;
+                let address = self.to_address(address)?;
+                let byte = address.checked_sub(PROGRAM_START)
+                    .and_then(|offset| self.rom.get(offset));
+                *byte.unwrap_or(&0) as f64
+            }
+            "HERE" => self.here as f64,
+            _ => match self.parse_value(&token) {
+                Some(value) => value,
+                None => match self.labels.get(&token) {
+                    Some(&address) => address as f64,
+                    None => return Err(self.error(&format!("Unknown name `{}`", token))),
+                },
+            },
+        };
+        Ok(value)
+    }
+
+    /// Emit the instruction `opcode nnn`, where the address is the label or value `name`.
+    /// Labels that aren't defined yet are patched at the end.
+    fn emit_address_ref(&mut self, opcode: u16, name: &str) -> Result<(), CompileError> {
+        match self.address_of(name)? {
+            Some(address) => self.emit(opcode | address as u16),
+            None => {
+                self.fixups.push(Fixup::Address {
+                    address: self.here,
+                    label: name.to_string(),
+                    line: self.line,
+                });
+                self.emit(opcode)
+            }
+        }
+    }
+
+    /// Returns the address `name` stands for, or `None` if it is a label that isn't defined yet.
+    fn address_of(&self, name: &str) -> Result<Option<usize>, CompileError> {
+        if let Some(value) = self.parse_value(name) {
+            return self.to_address(value).map(Some);
+        }
+        if self.parse_register(name).is_some() || name.starts_with(':') {
+            return Err(self.error(&format!("Expected an address, found `{}`", name)));
+        }
+        Ok(self.labels.get(name).copied())
+    }
+
+    fn label_address(&self, label: &str, line: usize) -> Result<usize, CompileError> {
+        self.labels.get(label).copied().ok_or_else(|| CompileError {
+            line,
+            message: format!("Undefined label `{}`", label),
+        })
+    }
+
+    fn patch_jump(&mut self, jump: usize, target: usize) {
+        let offset = jump - PROGRAM_START;
+        self.rom[offset] = 0x10 | (target >> 8) as u8;
+        self.rom[offset + 1] = target as u8;
+    }
+
+    fn emit(&mut self, word: u16) -> Result<(), CompileError> {
+        let [high, low] = word.to_be_bytes();
+        self.emit_byte(high)?;
+        self.emit_byte(low)
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), CompileError> {
+        if self.here >= MEMORY_END {
+            return Err(self.error("The program doesn't fit in memory"));
+        }
+        let offset = self.here - PROGRAM_START;
+        if self.rom.len() <= offset {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    /// Parse a register operand.
+    fn register(&mut self) -> Result<u8, CompileError> {
+        let token = self.next()?;
+        self.parse_register(&token)
+            .ok_or_else(|| self.error(&format!("Expected a register, found `{}`", token)))
+    }
+
+    /// Parse an operand that is a register or a byte.
+    fn operand(&mut self) -> Result<Operand, CompileError> {
+        let token = self.next()?;
+        if let Some(register) = self.parse_register(&token) {
+            return Ok(Operand::Register(register));
+        }
+        match self.parse_value(&token) {
+            Some(value) => Ok(Operand::Byte(self.to_byte(value)?)),
+            None => Err(self.error(&format!("Expected a register or a number, found `{}`",
+                token))),
+        }
+    }
+
+    /// Parse a number or constant.
+    fn value(&mut self) -> Result<f64, CompileError> {
+        let token = self.next()?;
+        self.parse_value(&token)
+            .ok_or_else(|| self.error(&format!("Expected a number, found `{}`", token)))
+    }
+
+    fn parse_register(&self, token: &str) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(token) {
+            return Some(register);
+        }
+        let digit = token.strip_prefix('v').or_else(|| token.strip_prefix('V'))?;
+        if digit.len() != 1 {
+            return None;
+        }
+        u8::from_str_radix(digit, 16).ok()
+    }
+
+    fn parse_value(&self, token: &str) -> Option<f64> {
+        if let Some(&value) = self.constants.get(token) {
+            return Some(value);
+        }
+        parse_number(token)
+    }
+
+    /// Returns `value` as a byte. Negative values are two's complement.
+    fn to_byte(&self, value: f64) -> Result<u8, CompileError> {
+        let value = value.floor() as i64;
+        match value {
+            0..=255 => Ok(value as u8),
+            -128..=-1 => Ok(value as i8 as u8),
+            _ => Err(self.error(&format!("{} doesn't fit in a byte", value))),
+        }
+    }
+
+    fn to_address(&self, value: f64) -> Result<usize, CompileError> {
+        let value = value.floor() as i64;
+        match value {
+            0..=0xFFF => Ok(value as usize),
+            _ => Err(self.error(&format!("{} is not a 12 bit address", value))),
+        }
+    }
+
+    fn take(&mut self) -> Result<Token, CompileError> {
+        let token = self.tokens.pop().ok_or_else(|| self.error("Unexpected end of program"))?;
+        self.line = token.line;
+        Ok(token)
+    }
+
+    fn next(&mut self) -> Result<String, CompileError> {
+        self.take().map(|token| token.text)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.last().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), CompileError> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(self.error(&format!("Expected `{}`, found `{}`", expected, token)));
+        }
+        Ok(())
+    }
+
+    fn error(&self, message: &str) -> CompileError {
+        CompileError {
+            line: self.line,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Parse an Octo number literal: decimal, `0x` hex or `0b` binary, optionally negative.
+fn parse_number(token: &str) -> Option<f64> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn is_binary_operator(token: &str) -> bool {
+    matches!(token, "+" | "-" | "*" | "/" | "%" | "&" | "|" | "^" | "<<" | ">>" | "pow" | "min"
+        | "max" | "<" | "<=" | "==" | "!=" | ">=" | ">")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_chip8_program() {
+        let source = "
+            # A bouncing ball
+            :alias px v0
+            :alias py v1
+            :const SPEED 2
+            :macro move reg amount { reg += amount }
+            : ball 0b11000000 0b11000000
+            : main
+                px := 10 py := 0x0A
+                i := ball
+                loop
+                    clear
+                    sprite px py 2
+                    move px SPEED
+                    if px >= 60 then px := 0
+                    if py < 3 begin py += 1 else py := 0 end
+                    v2 := key
+                    while v2 != 5
+                    vf := random 0xFF
+                    if v2 key then draw
+                    delay := v2
+                again
+            : draw
+                :unpack 0xA later
+                return
+            :calc twice { SPEED * 2 }
+            :byte { twice + 1 }
+            : later
+        ";
+        let words: &[u16] = &[0x1204, 0xC0C0, 0x600A, 0x610A, 0xA202, 0x00E0, 0xD012, 0x7002,
+            0x6F3B, 0x8F05, 0x3F01, 0x6000, 0x6F02, 0x8F15, 0x3F01, 0x1224, 0x7101, 0x1226,
+            0x6100, 0xF20A, 0x4205, 0x1236, 0xCFFF, 0xE2A1, 0x2236, 0xF215, 0x120A, 0x60A2,
+            0x613D, 0x00EE];
+        let mut expected: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        expected.push(0x05);
+
+        assert_eq!(compile(source), Ok(expected));
+    }
+
+    #[test]
+    fn rejects_extension_instructions() {
+        let err = compile(": main\n  hires\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.message.contains("hires"));
+    }
+
+    #[test]
+    fn rejects_undefined_labels() {
+        let err = compile(": main\n  jump nowhere\n").unwrap_err();
+        assert_eq!(err, CompileError {
+            line: 2,
+            message: "Undefined label `nowhere`".to_string(),
+        });
+    }
+}
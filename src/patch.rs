@@ -0,0 +1,431 @@
+//! Applying IPS and BPS patches to ROMs, and creating IPS patches. Fan translations and bug fixes
+//! are usually distributed as patches, which are applied to the original ROM before it is loaded.
+//!
+//! IPS patches are a list of records, each replacing the bytes at an offset, with run-length
+//! encoded records for repeated bytes and an optional truncation size after the end marker. BPS
+//! patches encode the patched ROM as copies from the original ROM, from itself and from the patch,
+//! and hold CRC32 checksums of the original ROM, the patched ROM and the patch itself.
+
+use std::fmt;
+
+use wasm_bindgen::prelude::*;
+
+use crate::load::LoadError;
+
+/// The signature of IPS patches.
+const IPS_SIGNATURE: &[u8] = b"PATCH";
+
+/// The marker after the last IPS record, which is also the offset no record can start at.
+const IPS_EOF: &[u8] = b"EOF";
+const IPS_EOF_OFFSET: usize = 0x454F46;
+
+/// The number of bytes an IPS record takes besides its data.
+const IPS_RECORD_OVERHEAD: usize = 5;
+
+/// The maximal number of data bytes in an IPS record.
+const IPS_MAX_RECORD_SIZE: usize = 0xFFFF;
+
+/// The size of the ROMs IPS offsets can address, and the maximal size of any patched ROM.
+const MAX_PATCHED_SIZE: usize = 1 << 24;
+
+/// The signature of BPS patches.
+const BPS_SIGNATURE: &[u8] = b"BPS1";
+
+/// The size of the BPS footer: the checksums of the original ROM, the patched ROM and the patch.
+const BPS_FOOTER_SIZE: usize = 12;
+
+/// The format of a patch file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+}
+
+impl PatchFormat {
+    /// Returns the format of `patch`, if it is a patch file.
+    pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+        if patch.starts_with(IPS_SIGNATURE) {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(BPS_SIGNATURE) {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns true if `bytes` look like an IPS or BPS patch.
+pub fn is_patch(bytes: &[u8]) -> bool {
+    PatchFormat::detect(bytes).is_some()
+}
+
+#[wasm_bindgen]
+/// Apply the IPS or BPS patch `patch` to `rom`, and return the patched rom. For BPS patches, the
+/// checksums of the original rom, the patched rom and the patch are validated.
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(rom, patch),
+        Some(PatchFormat::Bps) => apply_bps(rom, patch),
+        None => Err(PatchError::UnknownFormat),
+    }
+}
+
+#[wasm_bindgen]
+/// Create an IPS patch that turns `original` into `modified`.
+pub fn create_ips_patch(original: &[u8], modified: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if original.len() > MAX_PATCHED_SIZE || modified.len() > MAX_PATCHED_SIZE {
+        return Err(PatchError::TooLarge(original.len().max(modified.len())));
+    }
+
+    let differs = |offset: usize| offset >= original.len() || original[offset] != modified[offset];
+
+    let mut patch = IPS_SIGNATURE.to_vec();
+    let mut offset = 0;
+    while offset < modified.len() {
+        if !differs(offset) {
+            offset += 1;
+            continue;
+        }
+
+        // A record can't start at the offset that reads as the end marker
+        let start = if offset == IPS_EOF_OFFSET { offset - 1 } else { offset };
+
+        // Short runs of unchanged bytes are included in the record, which is smaller than
+        // starting a new one
+        let mut end = offset + 1;
+        let mut next = end;
+        while next < modified.len() && next - start < IPS_MAX_RECORD_SIZE
+            && next - end < IPS_RECORD_OVERHEAD {
+            if differs(next) {
+                end = next + 1;
+            }
+            next += 1;
+        }
+
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[start..end]);
+        offset = end;
+    }
+
+    patch.extend_from_slice(IPS_EOF);
+    if modified.len() < original.len() {
+        patch.extend_from_slice(&(modified.len() as u32).to_be_bytes()[1..]);
+    }
+
+    Ok(patch)
+}
+
+/// Apply the IPS patch `patch` to `rom`.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut reader = PatchReader::new(&patch[IPS_SIGNATURE.len()..]);
+    let mut patched = rom.to_vec();
+    loop {
+        let offset_bytes = reader.bytes(3)?;
+        if offset_bytes == IPS_EOF {
+            break;
+        }
+        let offset = u32::from_be_bytes([0, offset_bytes[0], offset_bytes[1], offset_bytes[2]])
+            as usize;
+
+        let size = reader.u16()? as usize;
+        let (size, data) = if size == 0 {
+            // A run-length encoded record
+            let count = reader.u16()? as usize;
+            (count, vec![reader.bytes(1)?[0]; count])
+        } else {
+            (size, reader.bytes(size)?.to_vec())
+        };
+
+        let end = offset + size;
+        if end > MAX_PATCHED_SIZE {
+            return Err(PatchError::TooLarge(end));
+        }
+        if end > patched.len() {
+            patched.resize(end, 0);
+        }
+        patched[offset..end].copy_from_slice(&data);
+    }
+
+    // The patched rom can be truncated by a size after the end marker
+    match reader.remaining() {
+        0 => {}
+        3 => {
+            let size_bytes = reader.bytes(3)?;
+            let size = u32::from_be_bytes([0, size_bytes[0], size_bytes[1], size_bytes[2]]);
+            patched.truncate(size as usize);
+        }
+        _ => return Err(PatchError::Malformed("Unexpected data after the end of the patch")),
+    }
+
+    Ok(patched)
+}
+
+/// Apply the BPS patch `patch` to `rom`, validating the checksums.
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < BPS_SIGNATURE.len() + BPS_FOOTER_SIZE {
+        return Err(PatchError::Malformed("Truncated patch"));
+    }
+    let footer_start = patch.len() - BPS_FOOTER_SIZE;
+    let footer_crc = |index: usize| {
+        let start = footer_start + index * 4;
+        u32::from_le_bytes([patch[start], patch[start + 1], patch[start + 2], patch[start + 3]])
+    };
+    if crc32fast::hash(&patch[..patch.len() - 4]) != footer_crc(2) {
+        return Err(PatchError::PatchChecksum);
+    }
+
+    let mut reader = PatchReader::new(&patch[BPS_SIGNATURE.len()..footer_start]);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+
+    if source_size != rom.len() || crc32fast::hash(rom) != footer_crc(0) {
+        return Err(PatchError::SourceMismatch);
+    }
+    if target_size > MAX_PATCHED_SIZE {
+        return Err(PatchError::TooLarge(target_size));
+    }
+
+    let mut patched = Vec::with_capacity(target_size);
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while reader.remaining() > 0 {
+        let action = reader.varint()?;
+        let len = (action >> 2) + 1;
+        if patched.len() + len > target_size {
+            return Err(PatchError::Malformed("Action writes beyond the end of the patched ROM"));
+        }
+
+        match action & 3 {
+            // Source read: copy from the same offset in the original rom
+            0 => {
+                let start = patched.len();
+                let bytes = rom.get(start..start + len)
+                    .ok_or(PatchError::Malformed("Source read beyond the end of the ROM"))?;
+                patched.extend_from_slice(bytes);
+            }
+            // Target read: copy from the patch
+            1 => patched.extend_from_slice(reader.bytes(len)?),
+            // Source copy: copy from a relative offset in the original rom
+            2 => {
+                source_offset = reader.relative_offset(source_offset)?;
+                let bytes = rom.get(source_offset..source_offset + len)
+                    .ok_or(PatchError::Malformed("Source copy beyond the end of the ROM"))?;
+                patched.extend_from_slice(bytes);
+                source_offset += len;
+            }
+            // Target copy: copy from a relative offset in the patched rom, which may overlap the
+            // bytes being written, so it is copied byte by byte
+            _ => {
+                target_offset = reader.relative_offset(target_offset)?;
+                if target_offset >= patched.len() {
+                    return Err(PatchError::Malformed("Target copy from unwritten bytes"));
+                }
+                for _ in 0..len {
+                    patched.push(patched[target_offset]);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if patched.len() != target_size || crc32fast::hash(&patched) != footer_crc(1) {
+        return Err(PatchError::TargetChecksum);
+    }
+
+    Ok(patched)
+}
+
+/// Reads the fields of a patch in order.
+struct PatchReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        PatchReader {
+            bytes,
+            position: 0,
+        }
+    }
+
+    /// Returns the number of bytes left to read.
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    /// Read the next `len` bytes.
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        if len > self.remaining() {
+            return Err(PatchError::Malformed("Truncated patch"));
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Read a big-endian 16-bit number.
+    fn u16(&mut self) -> Result<u16, PatchError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Read a BPS variable-length number: 7 bits per byte, least significant first, where the
+    /// high bit marks the last byte and every continuation adds one to the next group.
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let overflow = || PatchError::Malformed("Number too large");
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.bytes(1)?[0];
+            value = ((byte & 0x7F) as usize).checked_mul(shift)
+                .and_then(|group| value.checked_add(group)).ok_or_else(overflow)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or_else(overflow)?;
+            value = value.checked_add(shift).ok_or_else(overflow)?;
+        }
+    }
+
+    /// Read a BPS relative offset, and return `offset` moved by it.
+    fn relative_offset(&mut self, offset: usize) -> Result<usize, PatchError> {
+        let data = self.varint()?;
+        let distance = data >> 1;
+        let moved = if data & 1 != 0 {
+            offset.checked_sub(distance)
+        } else {
+            offset.checked_add(distance)
+        };
+        moved.ok_or(PatchError::Malformed("Copy offset out of bounds"))
+    }
+}
+
+/// An error in applying or creating a patch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    /// The file isn't an IPS or BPS patch.
+    UnknownFormat,
+    /// The patch is malformed.
+    Malformed(&'static str),
+    /// The patch was made for a different ROM: the size or checksum of the ROM doesn't match.
+    SourceMismatch,
+    /// The patched ROM doesn't match the checksum in the patch.
+    TargetChecksum,
+    /// The patch is corrupted: its checksum doesn't match.
+    PatchChecksum,
+    /// The patched ROM is too large. Holds its size in bytes.
+    TooLarge(usize),
+    /// The patched ROM can't be loaded.
+    Load(LoadError),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "The file is not an IPS or BPS patch"),
+            PatchError::Malformed(message) => write!(f, "Invalid patch: {}", message),
+            PatchError::SourceMismatch => write!(f, "The patch was made for a different ROM"),
+            PatchError::TargetChecksum => {
+                write!(f, "The patched ROM doesn't match the patch's checksum")
+            }
+            PatchError::PatchChecksum => {
+                write!(f, "The patch is corrupted, its checksum doesn't match")
+            }
+            PatchError::TooLarge(size) => {
+                write!(f, "The patched ROM is too large ({} bytes)", size)
+            }
+            PatchError::Load(err) => write!(f, "The patched ROM can't be loaded: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl From<PatchError> for JsValue {
+    fn from(err: PatchError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode `value` as a BPS variable-length number.
+    fn push_varint(patch: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let low_bits = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                patch.push(0x80 | low_bits);
+                return;
+            }
+            patch.push(low_bits);
+            value -= 1;
+        }
+    }
+
+    /// Returns a BPS patch that turns `original` into `modified` with a single target read.
+    fn bps_patch(original: &[u8], modified: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_SIGNATURE.to_vec();
+        push_varint(&mut patch, original.len());
+        push_varint(&mut patch, modified.len());
+        push_varint(&mut patch, 0);
+        push_varint(&mut patch, ((modified.len() - 1) << 2) | 1);
+        patch.extend_from_slice(modified);
+        patch.extend_from_slice(&crc32fast::hash(original).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(modified).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn ips_round_trip() {
+        let original: Vec<u8> = (0..=255).collect();
+        let mut modified = original.clone();
+        modified[3] = 0xAA;
+        modified[4] = 0xBB;
+        modified[200] = 0;
+        modified.extend_from_slice(&[1, 2, 3]);
+
+        let patch = create_ips_patch(&original, &modified).unwrap();
+        assert_eq!(PatchFormat::detect(&patch), Some(PatchFormat::Ips));
+        assert_eq!(apply_patch(&original, &patch), Ok(modified));
+    }
+
+    #[test]
+    fn ips_round_trip_truncates() {
+        let original = [0x00, 0xE0, 0x12, 0x00, 0xFF, 0xFF];
+        let modified = [0x00, 0xE0, 0x12, 0x02];
+
+        let patch = create_ips_patch(&original, &modified).unwrap();
+        assert_eq!(apply_patch(&original, &patch), Ok(modified.to_vec()));
+    }
+
+    #[test]
+    fn bps_applies() {
+        let original = [0x00, 0xE0, 0x12, 0x00];
+        let modified = [0x00, 0xE0, 0x12, 0x02];
+
+        assert_eq!(apply_patch(&original, &bps_patch(&original, &modified)),
+            Ok(modified.to_vec()));
+    }
+
+    #[test]
+    fn bps_rejects_bad_checksums() {
+        let original = [0x00, 0xE0, 0x12, 0x00];
+        let modified = [0x00, 0xE0, 0x12, 0x02];
+        let patch = bps_patch(&original, &modified);
+
+        let mut corrupted = patch.clone();
+        corrupted[BPS_SIGNATURE.len() + 4] ^= 0xFF;
+        assert_eq!(apply_patch(&original, &corrupted), Err(PatchError::PatchChecksum));
+
+        let other_rom = [0x00, 0xE0, 0x12, 0x04];
+        assert_eq!(apply_patch(&other_rom, &patch), Err(PatchError::SourceMismatch));
+    }
+}
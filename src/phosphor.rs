@@ -0,0 +1,81 @@
+//! Phosphor persistence post-processing, which reduces the flicker of sprites that are constantly
+//! erased and redrawn.
+
+use std::collections::VecDeque;
+
+/// How recent frames are combined into the intensity buffer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PhosphorMode {
+    /// A pixel lights up fully when it is turned on, and then keeps `decay` of its intensity
+    /// every frame after it is turned off, like a CRT phosphor.
+    Decay(f32),
+    /// A pixel is fully lit if it was turned on in any of the last `n` frames.
+    OrFrames(usize),
+}
+
+/// Blends the screen buffers of recent frames into an intensity buffer, without affecting the
+/// emulation itself.
+pub struct PhosphorFilter {
+    mode: PhosphorMode,
+    // The intensity of every pixel in the range [0, 1], row by row
+    intensity: Vec<f32>,
+    // The screen buffers of the last frames, oldest first. Only used by `PhosphorMode::OrFrames`.
+    history: VecDeque<Vec<bool>>,
+}
+
+impl PhosphorFilter {
+    /// Construct a filter for a screen with `num_pixels` pixels.
+    pub fn new(mode: PhosphorMode, num_pixels: usize) -> Self {
+        if let PhosphorMode::Decay(decay) = mode {
+            assert!((0.0..1.0).contains(&decay), "Decay must be in the range [0, 1)");
+        }
+        if let PhosphorMode::OrFrames(num_frames) = mode {
+            assert!(num_frames > 0, "Number of blended frames must not be zero");
+        }
+
+        PhosphorFilter {
+            mode,
+            intensity: vec![0.0; num_pixels],
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Returns how recent frames are combined.
+    pub fn mode(&self) -> PhosphorMode {
+        self.mode
+    }
+
+    /// Blend the screen buffer of a new frame into the intensity buffer.
+    pub fn update(&mut self, screen_buffer: &[bool]) {
+        assert!(screen_buffer.len() == self.intensity.len(), "Screen size changed");
+
+        match self.mode {
+            PhosphorMode::Decay(decay) => {
+                for (intensity, &pixel_on) in self.intensity.iter_mut().zip(screen_buffer) {
+                    *intensity = if pixel_on { 1.0 } else { *intensity * decay };
+                }
+            }
+            PhosphorMode::OrFrames(num_frames) => {
+                // Reuse the buffer of the oldest frame for the new one
+                let mut frame = if self.history.len() == num_frames {
+                    self.history.pop_front().unwrap()
+                } else {
+                    Vec::with_capacity(screen_buffer.len())
+                };
+                frame.clear();
+                frame.extend_from_slice(screen_buffer);
+                self.history.push_back(frame);
+
+                for (pixel_idx, intensity) in self.intensity.iter_mut().enumerate() {
+                    let lit = self.history.iter().any(|frame| frame[pixel_idx]);
+                    *intensity = if lit { 1.0 } else { 0.0 };
+                }
+            }
+        }
+    }
+
+    /// Returns the intensity of every pixel in the range [0, 1], row by row.
+    pub fn intensity(&self) -> &[f32] {
+        &self.intensity
+    }
+}
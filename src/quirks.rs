@@ -0,0 +1,32 @@
+//! Options that change how some instructions operate. Used to emulate ROMs that depend on
+//! interpreter quirks from different platforms.
+
+use serde::{Deserialize, Serialize};
+
+/// A quirk profile: which of the original interpreter behaviours are used. When deserialized,
+/// missing quirks take their default values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quirks {
+    /// The shift instructions (8xy6 and 8xyE) shift Vy instead of Vx.
+    pub original_shift: bool,
+    /// The load/store instructions (Fx55 and Fx65) increment the I register by the number of
+    /// registers used.
+    pub original_mem_acc: bool,
+    /// The draw instruction (Dxyn) waits for the next v-blank before execution continues.
+    pub display_wait: bool,
+    /// The wait for key instruction (Fx0A) waits for a key to be pressed and then released,
+    /// instead of only pressed.
+    pub wait_for_release: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            original_shift: false,
+            original_mem_acc: false,
+            display_wait: false,
+            wait_for_release: true,
+        }
+    }
+}
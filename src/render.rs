@@ -0,0 +1,133 @@
+//! Rendering of the screen buffer to an RGBA framebuffer.
+
+/// The number of colours in a palette. Every pixel selects a colour by the bits of the display
+/// planes it is set in: index 0 is the background, index 1 is the first plane, index 2 is the
+/// second plane (XO-CHIP), and index 3 is where both planes overlap.
+pub const PALETTE_SIZE: usize = 4;
+
+/// The default palette: white pixels on a black background.
+pub const DEFAULT_PALETTE: [u32; PALETTE_SIZE] = [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555];
+
+/// Renders the screen to an RGBA framebuffer, which can be handed to a host as-is.
+pub struct FrameRenderer {
+    // The palette colours in 0xRRGGBB format
+    palette: [u32; PALETTE_SIZE],
+    // Every screen pixel is rendered as a `scale` by `scale` square of framebuffer pixels
+    scale: usize,
+
+    width: usize,
+    height: usize,
+    // 4 bytes (red, green, blue, alpha) for every framebuffer pixel, row by row
+    rgba: Vec<u8>,
+}
+
+impl FrameRenderer {
+    /// Construct a renderer with the default palette and no scaling.
+    pub fn new() -> Self {
+        FrameRenderer {
+            palette: DEFAULT_PALETTE,
+            scale: 1,
+            width: 0,
+            height: 0,
+            rgba: Vec::new(),
+        }
+    }
+
+    /// Returns the palette colours in 0xRRGGBB format.
+    pub fn palette(&self) -> &[u32; PALETTE_SIZE] {
+        &self.palette
+    }
+
+    /// Sets the palette colour at `index` to `rgb`, in 0xRRGGBB format.
+    pub fn set_palette_color(&mut self, index: usize, rgb: u32) {
+        assert!(index < PALETTE_SIZE, "Palette index out of bounds");
+        self.palette[index] = rgb & 0xFFFFFF;
+    }
+
+    /// Returns the integer scale factor of the framebuffer.
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Sets the integer scale factor of the framebuffer.
+    pub fn set_scale(&mut self, scale: usize) {
+        assert!(scale > 0, "Scale must not be zero");
+        self.scale = scale;
+    }
+
+    /// Render a `width` by `height` screen, where `color_index(x, y)` returns the palette index of
+    /// the pixel at (x, y).
+    pub fn render(&mut self, width: usize, height: usize,
+        color_index: impl Fn(usize, usize) -> usize) {
+        let palette = self.palette;
+        self.render_colors(width, height, |x, y| palette[color_index(x, y)]);
+    }
+
+    /// Render a `width` by `height` single plane screen, where `intensity(x, y)` returns how lit
+    /// the pixel at (x, y) is, in the range [0, 1]. Pixels are blended between the background
+    /// colour and the first plane colour.
+    pub fn render_blended(&mut self, width: usize, height: usize,
+        intensity: impl Fn(usize, usize) -> f32) {
+        let background = self.palette[0];
+        let foreground = self.palette[1];
+        self.render_colors(width, height, |x, y| {
+            let intensity = intensity(x, y);
+            let blend_channel = |shift: u32| {
+                let background = ((background >> shift) & 0xFF) as f32;
+                let foreground = ((foreground >> shift) & 0xFF) as f32;
+                ((background + (foreground - background) * intensity).round() as u32) << shift
+            };
+            blend_channel(16) | blend_channel(8) | blend_channel(0)
+        });
+    }
+
+    /// Render a `width` by `height` screen, where `pixel_color(x, y)` returns the colour of the
+    /// pixel at (x, y) in 0xRRGGBB format.
+    fn render_colors(&mut self, width: usize, height: usize,
+        pixel_color: impl Fn(usize, usize) -> u32) {
+        self.width = width * self.scale;
+        self.height = height * self.scale;
+        self.rgba.resize(self.width * self.height * 4, 0);
+
+        let row_bytes = self.width * 4;
+        for y in 0..height {
+            // Render a single framebuffer row, and then duplicate it for the vertical scaling
+            let row_start = y * self.scale * row_bytes;
+            for x in 0..width {
+                let color = pixel_color(x, y);
+                let pixel = [(color >> 16) as u8, (color >> 8) as u8, color as u8, 0xFF];
+
+                let pixel_start = row_start + x * self.scale * 4;
+                for sub_pixel in self.rgba[pixel_start..pixel_start + self.scale * 4].chunks_mut(4) {
+                    sub_pixel.copy_from_slice(&pixel);
+                }
+            }
+
+            for row in 1..self.scale {
+                self.rgba.copy_within(row_start..row_start + row_bytes,
+                    row_start + row * row_bytes);
+            }
+        }
+    }
+
+    /// Returns the width of the last rendered framebuffer.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the height of the last rendered framebuffer.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the RGBA bytes of the last rendered framebuffer.
+    pub fn rgba(&self) -> &[u8] {
+        &self.rgba
+    }
+}
+
+impl Default for FrameRenderer {
+    fn default() -> Self {
+        FrameRenderer::new()
+    }
+}
@@ -0,0 +1,454 @@
+//! Decoding of ROM files that don't hold the raw ROM bytes: zip archives, Intel HEX files and
+//! hex text dumps.
+//!
+//! The format is detected from the file contents. Zip archives start with a local file header
+//! signature. Files made only of printable ASCII text are decoded as Intel HEX if they start with
+//! a `:` record, and as whitespace-separated hex bytes otherwise (e.g. `00 E0 A2 2A` or
+//! `00E0 A22A`). Anything else is taken as raw ROM bytes. The entries of zip archives are decoded
+//! the same way, so an archive can hold Intel HEX or hex text ROMs.
+
+use std::fmt;
+use std::io::Read;
+
+use wasm_bindgen::prelude::*;
+
+use crate::load::LoadError;
+use crate::MEM_SIZE;
+
+/// The signature of a zip local file header, which starts every non-empty archive.
+const ZIP_LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+/// The signature of a zip central directory header.
+const ZIP_CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+/// The signature of the zip end of central directory record.
+const ZIP_END_SIGNATURE: u32 = 0x0605_4b50;
+/// The size of the end of central directory record, without the archive comment.
+const ZIP_END_SIZE: usize = 22;
+
+/// The file extensions of ROMs. When an archive has entries with these extensions, only they are
+/// considered ROMs.
+const ROM_EXTENSIONS: [&str; 8] = ["ch8", "c8", "rom", "sc8", "xo8", "bin", "hex", "ihx"];
+
+/// The file extensions of documents, which archives often have next to the ROMs. They are never
+/// considered ROMs, unless they are chosen by name.
+const DOCUMENT_EXTENSIONS: [&str; 11] =
+    ["txt", "md", "nfo", "diz", "pdf", "htm", "html", "json", "png", "jpg", "gif"];
+
+/// The format of a ROM file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RomFileFormat {
+    Raw,
+    Zip,
+    IntelHex,
+    HexText,
+}
+
+/// Returns the format of `file`, detected from its contents.
+pub fn detect_format(file: &[u8]) -> RomFileFormat {
+    if file.starts_with(&ZIP_LOCAL_HEADER_SIGNATURE.to_le_bytes())
+        || file.starts_with(&ZIP_END_SIGNATURE.to_le_bytes()) {
+        return RomFileFormat::Zip;
+    }
+
+    let is_text = !file.is_empty()
+        && file.iter().all(|&byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace());
+    if !is_text {
+        return RomFileFormat::Raw;
+    }
+
+    if file.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b':') {
+        RomFileFormat::IntelHex
+    } else {
+        RomFileFormat::HexText
+    }
+}
+
+#[wasm_bindgen]
+/// Decode the ROM bytes from `file`, in any of the supported formats. For a zip archive,
+/// `entry_name` chooses the entry by its path or file name (case-insensitively). Without it, the
+/// archive must hold a single ROM.
+pub fn decode_rom_file(file: &[u8], entry_name: Option<String>) -> Result<Vec<u8>, RomFileError> {
+    match detect_format(file) {
+        RomFileFormat::Raw => Ok(file.to_vec()),
+        RomFileFormat::Zip => {
+            let archive = ZipArchive::parse(file)?;
+            let entry = archive.find_rom_entry(entry_name.as_deref())?;
+            let entry_file = archive.read(entry)?;
+
+            // The entry is decoded like a ROM file, but archives in archives aren't opened
+            match detect_format(&entry_file) {
+                RomFileFormat::Zip => Err(RomFileError::Zip(format!("`{}` is an archive",
+                    entry.name))),
+                _ => decode_rom_file(&entry_file, None),
+            }
+        }
+        RomFileFormat::IntelHex => decode_intel_hex(file),
+        RomFileFormat::HexText => decode_hex_text(file),
+    }
+}
+
+#[wasm_bindgen]
+/// Returns the paths of the entries of the zip archive `file` that may be ROMs, which can be
+/// passed to `decode_rom_file` to choose one of them.
+pub fn archive_rom_entries(file: &[u8]) -> Result<Vec<String>, RomFileError> {
+    let archive = ZipArchive::parse(file)?;
+    Ok(archive.rom_entries().iter().map(|entry| entry.name.clone()).collect())
+}
+
+/// An entry of a zip archive, from the central directory.
+struct ZipEntry {
+    name: String,
+    encrypted: bool,
+    compression_method: u16,
+    crc32: u32,
+    compressed_size: usize,
+    uncompressed_size: usize,
+    local_header_offset: usize,
+}
+
+impl ZipEntry {
+    /// Returns true if the entry is a directory.
+    fn is_directory(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    /// Returns the file name of the entry, without its directory.
+    fn file_name(&self) -> &str {
+        self.name.rsplit('/').next().unwrap_or(&self.name)
+    }
+
+    /// Returns true if the entry is metadata added by an archiver, like the `__MACOSX` directory
+    /// or `.DS_Store` files.
+    fn is_archiver_metadata(&self) -> bool {
+        self.name.starts_with("__MACOSX/") || self.file_name().starts_with('.')
+    }
+
+    /// Returns true if the entry has one of the file `extensions`.
+    fn has_extension(&self, extensions: &[&str]) -> bool {
+        match self.file_name().rsplit_once('.') {
+            Some((_, extension)) => extensions.iter()
+                .any(|known_extension| extension.eq_ignore_ascii_case(known_extension)),
+            None => false,
+        }
+    }
+}
+
+/// A zip archive, read from its central directory.
+struct ZipArchive<'a> {
+    file: &'a [u8],
+    entries: Vec<ZipEntry>,
+}
+
+impl<'a> ZipArchive<'a> {
+    /// Parse the central directory of the zip archive `file`.
+    fn parse(file: &'a [u8]) -> Result<Self, RomFileError> {
+        // The end of central directory record is followed by a comment of up to 64KB
+        let end_offset = (0..=file.len().saturating_sub(ZIP_END_SIZE)).rev()
+            .take(ZIP_END_SIZE + u16::MAX as usize)
+            .find(|&offset| read_u32(file, offset) == Some(ZIP_END_SIGNATURE))
+            .ok_or_else(|| zip_error("Missing end of central directory"))?;
+
+        let num_entries = read_u16(file, end_offset + 10).unwrap_or(0) as usize;
+        let mut offset = read_u32(file, end_offset + 16).unwrap_or(0) as usize;
+        if num_entries == u16::MAX as usize || offset == u32::MAX as usize {
+            return Err(zip_error("ZIP64 archives are not supported"));
+        }
+
+        let mut entries = Vec::with_capacity(num_entries);
+        for _ in 0..num_entries {
+            if read_u32(file, offset) != Some(ZIP_CENTRAL_HEADER_SIGNATURE) {
+                return Err(zip_error("Invalid central directory"));
+            }
+            let field = |field_offset: usize| read_u32(file, offset + field_offset)
+                .ok_or_else(|| zip_error("Truncated central directory"));
+            let short_field = |field_offset: usize| read_u16(file, offset + field_offset)
+                .ok_or_else(|| zip_error("Truncated central directory"));
+
+            let flags = short_field(8)?;
+            let name_len = short_field(28)? as usize;
+            let extra_len = short_field(30)? as usize;
+            let comment_len = short_field(32)? as usize;
+            let name = file.get(offset + 46..offset + 46 + name_len)
+                .ok_or_else(|| zip_error("Truncated central directory"))?;
+
+            entries.push(ZipEntry {
+                name: String::from_utf8_lossy(name).into_owned(),
+                encrypted: flags & 1 != 0,
+                compression_method: short_field(10)?,
+                crc32: field(16)?,
+                compressed_size: field(20)? as usize,
+                uncompressed_size: field(24)? as usize,
+                local_header_offset: field(42)? as usize,
+            });
+            offset += 46 + name_len + extra_len + comment_len;
+        }
+
+        Ok(ZipArchive {
+            file,
+            entries,
+        })
+    }
+
+    /// Returns the entries that may be ROMs: the files that aren't archiver metadata or
+    /// documents, and only those with a ROM file extension if there are any.
+    fn rom_entries(&self) -> Vec<&ZipEntry> {
+        let files: Vec<&ZipEntry> = self.entries.iter()
+            .filter(|entry| !entry.is_directory() && !entry.is_archiver_metadata()
+                && !entry.has_extension(&DOCUMENT_EXTENSIONS))
+            .collect();
+
+        if files.iter().any(|entry| entry.has_extension(&ROM_EXTENSIONS)) {
+            files.into_iter().filter(|entry| entry.has_extension(&ROM_EXTENSIONS)).collect()
+        } else {
+            files
+        }
+    }
+
+    /// Returns the entry named `entry_name`, matched by its path or else by its file name, or the
+    /// single ROM entry if no name is given.
+    fn find_rom_entry(&self, entry_name: Option<&str>) -> Result<&ZipEntry, RomFileError> {
+        let candidates: Vec<&ZipEntry> = match entry_name {
+            Some(entry_name) => {
+                let by_path: Vec<&ZipEntry> = self.entries.iter()
+                    .filter(|entry| entry.name.eq_ignore_ascii_case(entry_name)).collect();
+                if by_path.is_empty() {
+                    self.entries.iter()
+                        .filter(|entry| entry.file_name().eq_ignore_ascii_case(entry_name))
+                        .collect()
+                } else {
+                    by_path
+                }
+            }
+            None => self.rom_entries(),
+        };
+
+        match candidates.as_slice() {
+            [entry] => Ok(entry),
+            [] => match entry_name {
+                Some(entry_name) => Err(RomFileError::EntryNotFound(entry_name.to_string())),
+                None => Err(RomFileError::NoRomInArchive),
+            },
+            _ => Err(RomFileError::AmbiguousEntry(
+                candidates.iter().map(|entry| entry.name.clone()).collect())),
+        }
+    }
+
+    /// Read the contents of `entry`, checking their CRC-32.
+    fn read(&self, entry: &ZipEntry) -> Result<Vec<u8>, RomFileError> {
+        if entry.encrypted {
+            return Err(zip_error(&format!("`{}` is encrypted", entry.name)));
+        }
+
+        let offset = entry.local_header_offset;
+        if read_u32(self.file, offset) != Some(ZIP_LOCAL_HEADER_SIGNATURE) {
+            return Err(zip_error(&format!("Invalid local header of `{}`", entry.name)));
+        }
+        let name_len = read_u16(self.file, offset + 26).unwrap_or(0) as usize;
+        let extra_len = read_u16(self.file, offset + 28).unwrap_or(0) as usize;
+        let data_offset = offset + 30 + name_len + extra_len;
+        let data = self.file.get(data_offset..data_offset + entry.compressed_size)
+            .ok_or_else(|| zip_error(&format!("`{}` is truncated", entry.name)))?;
+
+        // A ROM never needs more than the memory size, which also bounds the decompressed size
+        if entry.uncompressed_size > MEM_SIZE {
+            return Err(zip_error(&format!("`{}` is too large to be a ROM", entry.name)));
+        }
+        let contents = match entry.compression_method {
+            0 => data.to_vec(),
+            8 => {
+                let mut contents = Vec::with_capacity(entry.uncompressed_size);
+                flate2::read::DeflateDecoder::new(data).take(MEM_SIZE as u64 + 1)
+                    .read_to_end(&mut contents)
+                    .map_err(|err| zip_error(&format!("`{}`: {}", entry.name, err)))?;
+                contents
+            }
+            method => {
+                return Err(zip_error(&format!("`{}` uses the unsupported compression method {}",
+                    entry.name, method)));
+            }
+        };
+
+        if contents.len() != entry.uncompressed_size
+            || crc32fast::hash(&contents) != entry.crc32 {
+            return Err(zip_error(&format!("`{}` is corrupted", entry.name)));
+        }
+
+        Ok(contents)
+    }
+}
+
+/// Read a little-endian u16 at `offset`, if it is in bounds.
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let field = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([field[0], field[1]]))
+}
+
+/// Read a little-endian u32 at `offset`, if it is in bounds.
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let field = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+/// Returns an error about a malformed zip archive.
+fn zip_error(message: &str) -> RomFileError {
+    RomFileError::Zip(message.to_string())
+}
+
+/// Decode an Intel HEX file. The ROM starts at the lowest address of the data records, and gaps
+/// between records are filled with zeros.
+fn decode_intel_hex(file: &[u8]) -> Result<Vec<u8>, RomFileError> {
+    let text = String::from_utf8_lossy(file);
+    let error = |line: usize, message: &str| RomFileError::IntelHex {
+        line,
+        message: message.to_string(),
+    };
+
+    // The data records, with their lines and absolute addresses
+    let mut records: Vec<(usize, usize, Vec<u8>)> = Vec::new();
+    let mut base_address = 0;
+    let mut reached_end = false;
+    for (line_idx, line) in text.lines().enumerate() {
+        let line_number = line_idx + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if reached_end {
+            return Err(error(line_number, "Data after the end of file record"));
+        }
+
+        let hex = line.strip_prefix(':').ok_or_else(|| error(line_number, "Expected `:`"))?;
+        if !hex.len().is_multiple_of(2) {
+            return Err(error(line_number, "Odd number of hex digits"));
+        }
+        let bytes = (0..hex.len()).step_by(2)
+            .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| error(line_number, "Invalid hex digit"))?;
+
+        if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+            return Err(error(line_number, "Record length doesn't match its byte count"));
+        }
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(error(line_number, "Checksum mismatch"));
+        }
+
+        let address = ((bytes[1] as usize) << 8) | (bytes[2] as usize);
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => records.push((line_number, base_address + address, data.to_vec())),
+            0x01 => reached_end = true,
+            // Extended segment address, in 16-byte paragraphs
+            0x02 if data.len() == 2 => {
+                base_address = (((data[0] as usize) << 8) | (data[1] as usize)) << 4;
+            }
+            // Extended linear address, the upper 16 bits of the address
+            0x04 if data.len() == 2 => {
+                base_address = (((data[0] as usize) << 8) | (data[1] as usize)) << 16;
+            }
+            // Start addresses don't matter for CHIP-8
+            0x03 | 0x05 => {}
+            record_type => {
+                return Err(error(line_number,
+                    &format!("Invalid record of type {:02X}", record_type)));
+            }
+        }
+    }
+
+    if !reached_end {
+        return Err(error(text.lines().count(), "Missing end of file record"));
+    }
+
+    let start = records.iter().map(|(_, address, _)| *address).min().unwrap_or(0);
+    let mut rom = Vec::new();
+    for (line_number, address, data) in records {
+        let offset = address - start;
+        if offset + data.len() > MEM_SIZE {
+            return Err(error(line_number, "The data doesn't fit in memory"));
+        }
+
+        if rom.len() < offset + data.len() {
+            rom.resize(offset + data.len(), 0);
+        }
+        rom[offset..offset + data.len()].copy_from_slice(&data);
+    }
+
+    Ok(rom)
+}
+
+/// Decode whitespace-separated hex bytes. Every token is one or more bytes of 2 hex digits each,
+/// optionally with a `0x` prefix.
+fn decode_hex_text(file: &[u8]) -> Result<Vec<u8>, RomFileError> {
+    let text = String::from_utf8_lossy(file);
+
+    let mut rom = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        for token in line.split_whitespace() {
+            let error = |message: &str| RomFileError::HexText {
+                line: line_idx + 1,
+                message: format!("{} in `{}`", message, token),
+            };
+
+            let hex = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X"))
+                .unwrap_or(token);
+            if hex.is_empty() || !hex.len().is_multiple_of(2) {
+                return Err(error("Odd number of hex digits"));
+            }
+            for idx in (0..hex.len()).step_by(2) {
+                let byte = u8::from_str_radix(&hex[idx..idx + 2], 16)
+                    .map_err(|_| error("Invalid hex digit"))?;
+                rom.push(byte);
+            }
+        }
+    }
+
+    Ok(rom)
+}
+
+/// An error in decoding a ROM file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RomFileError {
+    /// The zip archive is malformed or uses unsupported features.
+    Zip(String),
+    /// The zip archive doesn't have any file that may be a ROM.
+    NoRomInArchive,
+    /// The zip archive has several entries that may be the ROM. Holds their paths.
+    AmbiguousEntry(Vec<String>),
+    /// The zip archive doesn't have the requested entry.
+    EntryNotFound(String),
+    /// The Intel HEX file is malformed at the given line.
+    IntelHex { line: usize, message: String },
+    /// The hex text is malformed at the given line.
+    HexText { line: usize, message: String },
+    /// The decoded ROM can't be loaded.
+    Load(LoadError),
+}
+
+impl fmt::Display for RomFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomFileError::Zip(message) => write!(f, "Invalid zip archive: {}", message),
+            RomFileError::NoRomInArchive => write!(f, "The zip archive doesn't have any ROM"),
+            RomFileError::AmbiguousEntry(names) => {
+                write!(f, "The zip archive has several ROMs, choose one of: {}", names.join(", "))
+            }
+            RomFileError::EntryNotFound(name) => {
+                write!(f, "The zip archive doesn't have `{}`", name)
+            }
+            RomFileError::IntelHex { line, message } => {
+                write!(f, "Invalid Intel HEX at line {}: {}", line, message)
+            }
+            RomFileError::HexText { line, message } => {
+                write!(f, "Invalid hex text at line {}: {}", line, message)
+            }
+            RomFileError::Load(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for RomFileError {}
+
+impl From<RomFileError> for JsValue {
+    fn from(err: RomFileError) -> JsValue {
+        JsValue::from_str(&err.to_string())
+    }
+}
@@ -0,0 +1,44 @@
+//! Encoding of the screen as a PNG image.
+
+use crate::render::FrameRenderer;
+
+/// Encode a `width` by `height` single plane screen as a PNG image, where `pixel_on(x, y)`
+/// returns true if the pixel at (x, y) is turned on. The image is rendered with the palette of
+/// `renderer`, and every pixel becomes a `scale` by `scale` square.
+pub fn encode_png(renderer: &FrameRenderer, scale: usize, width: usize, height: usize,
+    pixel_on: impl Fn(usize, usize) -> bool) -> Vec<u8> {
+    assert!(scale > 0, "Scale must not be zero");
+
+    // The image only has two colours, so we store it as an indexed image with one bit per pixel
+    let image_width = width * scale;
+    let image_height = height * scale;
+    let row_bytes = image_width.div_ceil(8);
+    let mut pixel_data = vec![0u8; row_bytes * image_height];
+    for image_y in 0..image_height {
+        for image_x in 0..image_width {
+            if pixel_on(image_x / scale, image_y / scale) {
+                // The MSB is the leftmost pixel
+                pixel_data[image_y * row_bytes + image_x / 8] |= 0x80 >> (image_x % 8);
+            }
+        }
+    }
+
+    let palette = renderer.palette();
+    let mut png_palette = Vec::with_capacity(2 * 3);
+    for &color in &palette[..2] {
+        png_palette.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8]);
+    }
+
+    let mut png_bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_bytes, image_width as u32, image_height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::One);
+    encoder.set_palette(png_palette);
+
+    // Writing to a `Vec` can't fail, and the image data always matches the header
+    let mut writer = encoder.write_header().expect("Failed to write PNG header");
+    writer.write_image_data(&pixel_data).expect("Failed to write PNG image data");
+    writer.finish().expect("Failed to finish PNG");
+
+    png_bytes
+}
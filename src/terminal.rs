@@ -0,0 +1,58 @@
+//! Rendering of the screen as text, for terminals.
+
+/// How screen pixels are packed into text characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextStyle {
+    /// Every character holds 1x2 pixels, using the Unicode half block characters. A 64x32 screen
+    /// becomes 64x16 characters.
+    HalfBlocks,
+    /// Every character holds 2x4 pixels, using the Unicode braille patterns. A 64x32 screen
+    /// becomes 32x8 characters.
+    Braille,
+}
+
+/// Render a `width` by `height` screen as lines of text, where `pixel_on(x, y)` returns true if
+/// the pixel at (x, y) is turned on. Lines are separated by `\n`.
+pub fn render_text(style: TextStyle, width: usize, height: usize,
+    pixel_on: impl Fn(usize, usize) -> bool) -> String {
+    // Pixels outside the screen (when the size doesn't divide into characters) are off
+    let pixel_on = |x: usize, y: usize| x < width && y < height && pixel_on(x, y);
+
+    let mut text = String::new();
+    match style {
+        TextStyle::HalfBlocks => {
+            for y in (0..height).step_by(2) {
+                for x in 0..width {
+                    text.push(match (pixel_on(x, y), pixel_on(x, y + 1)) {
+                        (false, false) => ' ',
+                        (true, false) => '\u{2580}',
+                        (false, true) => '\u{2584}',
+                        (true, true) => '\u{2588}',
+                    });
+                }
+                text.push('\n');
+            }
+        }
+        TextStyle::Braille => {
+            // The braille dot bits, indexed by [row][column] in the 2x4 cell
+            const DOT_BITS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+            for y in (0..height).step_by(4) {
+                for x in (0..width).step_by(2) {
+                    let mut dots = 0;
+                    for (row, row_bits) in DOT_BITS.iter().enumerate() {
+                        for (column, bit) in row_bits.iter().enumerate() {
+                            if pixel_on(x + column, y + row) {
+                                dots |= bit;
+                            }
+                        }
+                    }
+                    text.push(std::char::from_u32(0x2800 + dots).unwrap());
+                }
+                text.push('\n');
+            }
+        }
+    }
+
+    text
+}
@@ -0,0 +1,93 @@
+//! Instruction timing models used by `Cpu::run_frame` to decide how many instructions fit in a
+//! single 60HZ frame.
+
+use crate::instruction::Instruction;
+use crate::load::HIRES_CLEAR_INSTRUCTION;
+use crate::Cpu;
+
+/// The rate at which frames are run, and at which the cpu timers tick.
+pub const FRAME_RATE_HZ: u32 = 60;
+
+/// The default number of instructions executed per second when using the flat timing model.
+pub const DEFAULT_CLOCK_RATE_HZ: u32 = 600;
+
+/// The COSMAC VIP runs its CDP1802 at 1.76064MHz, and every machine cycle takes 8 clock cycles,
+/// which gives 3668 machine cycles per frame.
+const VIP_CYCLES_PER_FRAME: i64 = 1_760_640 / 8 / FRAME_RATE_HZ as i64;
+
+/// Machine cycles of every frame which are not available to the interpreter. The CDP1861 video
+/// chip steals 8 cycles of DMA for each of the 128 displayed lines, and the display interrupt
+/// routine (which also decrements the timers) takes roughly 30 more.
+const VIP_DISPLAY_CYCLES: i64 = 128 * 8 + 30;
+
+/// The number of machine cycles available to the interpreter in every frame.
+pub const VIP_INTERPRETER_CYCLES_PER_FRAME: i64 = VIP_CYCLES_PER_FRAME - VIP_DISPLAY_CYCLES;
+
+/// The interpreter cycle of every frame at which the display interrupt fires and decrements the
+/// timers. A CDP1861 frame is 262 lines of 14 machine cycles, and the interrupt fires on line 78,
+/// 2 lines before the displayed lines, so none of the cycles before it are stolen.
+pub const VIP_TIMER_INTERRUPT_CYCLE: i64 = 78 * 14;
+
+/// Machine cycles spent by the interpreter loop fetching an instruction and dispatching on its
+/// most significant nibble. This is paid by every instruction.
+const VIP_FETCH_DECODE_CYCLES: u32 = 40;
+
+/// Extra machine cycles spent by conditional skip instructions when the skip is taken.
+const VIP_SKIP_CYCLES: u32 = 4;
+
+/// Returns the number of COSMAC VIP machine cycles the instruction `instr` would take to execute
+/// on `cpu` in its current state. The costs are approximations of the VIP interpreter routines,
+/// including the data-dependent parts: the BCD conversion loop, the number of registers copied
+/// by load/store and the number of sprite rows drawn (with unaligned rows costing more, because
+/// they have to be shifted into two bytes).
+pub fn vip_instruction_cycles(cpu: &Cpu, instr: u16) -> u32 {
+    let skip_cost = |skip: bool| if skip { VIP_SKIP_CYCLES } else { 0 };
+    let v = &cpu.v_registers;
+    // Only the low nibble of Vx selects the key, as on the COSMAC VIP
+    let key_pressed = |x: usize| cpu.key_state[(v[x] & 0xF) as usize];
+
+    let instruction = match Instruction::decode(instr, cpu.load_options.hires) {
+        Some(instruction) => instruction,
+        // Machine code subroutines can take any amount of time
+        None => return VIP_FETCH_DECODE_CYCLES,
+    };
+    let execution_cycles = match instruction {
+        // Clearing the 256 bytes of display memory, or the 512 bytes of hires display memory
+        Instruction::Cls if cpu.load_options.hires && instr == HIRES_CLEAR_INSTRUCTION => 6156,
+        Instruction::Cls => 3078,
+        Instruction::Ret => 10,
+        Instruction::Jp { .. } => 12,
+        Instruction::Call { .. } => 26,
+        Instruction::SeByte { x, byte } => 10 + skip_cost(v[x] == byte),
+        Instruction::SneByte { x, byte } => 10 + skip_cost(v[x] != byte),
+        Instruction::SeReg { x, y } => 18 + skip_cost(v[x] == v[y]),
+        Instruction::LdByte { .. } => 6,
+        Instruction::AddByte { .. } => 10,
+        // The arithmetic instructions are executed by building the 1802 instruction in memory
+        Instruction::LdReg { .. } | Instruction::Or { .. } | Instruction::And { .. }
+        | Instruction::Xor { .. } | Instruction::AddReg { .. } | Instruction::Sub { .. }
+        | Instruction::Shr { .. } | Instruction::Subn { .. } | Instruction::Shl { .. } => 44,
+        Instruction::SneReg { x, y } => 18 + skip_cost(v[x] != v[y]),
+        Instruction::LdI { .. } => 12,
+        Instruction::JpV0 { .. } => 22,
+        Instruction::Rnd { .. } => 36,
+        Instruction::Drw { x, y, nibble } => {
+            let sprite_y = (v[y] as usize) % cpu.screen_height;
+            let drawn_rows = std::cmp::min(nibble as usize, cpu.screen_height - sprite_y) as u32;
+            let row_cycles = if v[x].is_multiple_of(8) { 26 } else { 50 };
+            68 + drawn_rows * row_cycles
+        }
+        Instruction::Skp { x } => 14 + skip_cost(key_pressed(x)),
+        Instruction::Sknp { x } => 14 + skip_cost(!key_pressed(x)),
+        Instruction::LdFromDt { .. } | Instruction::LdKey { .. } | Instruction::LdDt { .. }
+        | Instruction::LdSt { .. } => 10,
+        Instruction::AddI { .. } | Instruction::LdFont { .. } => 16,
+        // The digits are computed by repeated subtraction
+        Instruction::LdBcd { x } => {
+            84 + 16 * (v[x] / 100 + (v[x] / 10) % 10 + v[x] % 10) as u32
+        }
+        Instruction::Store { x } | Instruction::Load { x } => 14 + 14 * (x as u32 + 1),
+    };
+
+    VIP_FETCH_DECODE_CYCLES + execution_cycles
+}
@@ -0,0 +1,63 @@
+//! Recording of the generated audio to a WAV file.
+
+/// Collects rendered samples across frames, and encodes them as a mono 16-bit PCM WAV file.
+pub struct WavRecorder {
+    sample_rate: u32,
+    samples: Vec<i16>,
+}
+
+impl WavRecorder {
+    /// Construct an empty recorder for samples rendered at `sample_rate`.
+    pub fn new(sample_rate: u32) -> Self {
+        WavRecorder {
+            sample_rate,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Append `samples`, in the range [-1, 1], to the recording.
+    pub fn record(&mut self, samples: &[f32]) {
+        self.samples.extend(samples.iter().map(|sample| {
+            (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+        }));
+    }
+
+    /// Returns the length of the recording in seconds.
+    pub fn duration_secs(&self) -> f32 {
+        self.samples.len() as f32 / self.sample_rate as f32
+    }
+
+    /// Encode the recording as the bytes of a WAV file.
+    pub fn to_wav_bytes(&self) -> Vec<u8> {
+        const NUM_CHANNELS: u16 = 1;
+        const BITS_PER_SAMPLE: u16 = 16;
+        const BLOCK_ALIGN: u16 = NUM_CHANNELS * BITS_PER_SAMPLE / 8;
+
+        let data_size = (self.samples.len() * BLOCK_ALIGN as usize) as u32;
+
+        let mut wav = Vec::with_capacity(44 + data_size as usize);
+        // RIFF header. The size doesn't include the "RIFF" tag and the size itself.
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+
+        // Format chunk, describing uncompressed PCM samples
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&NUM_CHANNELS.to_le_bytes());
+        wav.extend_from_slice(&self.sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(self.sample_rate * BLOCK_ALIGN as u32).to_le_bytes());
+        wav.extend_from_slice(&BLOCK_ALIGN.to_le_bytes());
+        wav.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+        // Data chunk, holding the little-endian samples
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_size.to_le_bytes());
+        for sample in &self.samples {
+            wav.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        wav
+    }
+}
@@ -1,14 +1,24 @@
-import init, { Cpu } from './pkg/chip8_emu.js';
+import init, { Cpu, Keymap, LoadOptions, OctoCartridge, RomDatabase, Waveform, analyze_rom_json,
+    apply_patch, archive_rom_entries, decode_rom_file } from './pkg/chip8_emu.js';
 
 let CLOCK_RATE_HZ = 600;
 let USE_ORIGINAL_SHIFT = false;
 let USE_ORIGINAL_MEM_ACC = false;
+let USE_DISPLAY_WAIT = true;
+let USE_KEY_WAIT_RELEASE = true;
+let USE_VIP_TIMING = false;
+let TONE_WAVEFORM = Waveform.Triangle;
+let BACKGROUND_COLOR = "#000000";
+let FOREGROUND_COLOR = "#ffffff";
+let FLICKER_REDUCTION = "off";
+// Where the ROM is loaded and started, and whether it uses the 64x64 hires screen
+let LOAD_OPTIONS = { load_address: 0x200, entry_point: 0x200, hires: false };
 
 // === Screen output ===
 const canvas = document.getElementById("game_screen");
 const ctx = canvas.getContext('2d');
 // Clear screen
-ctx.fillStyle = "black";
+ctx.fillStyle = BACKGROUND_COLOR;
 ctx.fillRect(0, 0, canvas.width, canvas.height);
 let last_animation_request_id;
 
@@ -17,32 +27,47 @@ const key_state = [];
 for (let i = 0; i < 16; i++) {
     key_state.push(false);
 }
-// True if we are waiting for a keypress
-let should_capture_key = false;
-// True if a keypress was capture and is waiting to be sent to the cpu
-let finished_capture_key = false;
-let captured_key;
 
 // === Audio output state ===
 const audio_context = new (window.AudioContext || window.webkitAudioContext)();
 const master_gain = audio_context.createGain();
 master_gain.connect(audio_context.destination);
-const oscillator = new OscillatorNode(audio_context, { type: 'triangle' });
-let tone_playing = false;
-let audio_initiated = false;
+// How far ahead of the current audio time the samples of a frame are scheduled
+const AUDIO_LATENCY_SECS = 0.05;
+// The audio context time at which the samples of the next frame should start playing
+let next_audio_time = 0;
+
+// The integer scale factor of downloaded images
+const SCREENSHOT_SCALE = 8;
+
+// The number of frames unknown ROMs are run for when they are analyzed
+const ANALYSIS_TRIAL_FRAMES = 300;
+
+// The memory layouts of the memory layout setting
+const MEMORY_LAYOUTS = {
+    standard: { load_address: 0x200, entry_point: 0x200, hires: false },
+    eti_660: { load_address: 0x600, entry_point: 0x600, hires: false },
+    hires: { load_address: 0x200, entry_point: 0x2C0, hires: true },
+};
 
-// Built-in ROMs state
-const rom_descriptions = [];
+// The local storage key of the ROM database entries imported by the user
+const LOCAL_ROM_DATABASE_KEY = "rom_database";
 
 // CHIP-8 State
 let wasm;
 let chip8_cpu;
 let loaded_rom_buffer;
+// The bindings of host keys to hex keys for the loaded ROM
+let loaded_keymap;
+// Identifies loaded ROMs and holds their settings
+let rom_database;
 
 init_wasm();
 
 async function init_wasm() {
     wasm = await init();
+    set_loaded_keymap(Keymap.qwerty());
+    load_rom_database();
     setup_event_listeners();
     populate_builtin_roms();
 }
@@ -55,8 +80,6 @@ async function populate_builtin_roms() {
         const rom_option = document.createElement('option');
         rom_option.value = rom_option.text = rom_data.file;
         rom_select.add(rom_option);
-
-        rom_descriptions.push(rom_data.desc);
     }
 
     rom_select.removeChild(document.getElementById("downloading_option"));
@@ -70,195 +93,523 @@ function render_loop(timestamp) {
         return;
     }
 
-    const delta_time = timestamp - last_frame_timestamp;
     // TODO: Handle non 60FPS animation
-    chip8_cpu.tick_clock();
+    chip8_cpu.run_frame_at(timestamp);
+
+    // Check if the executed instructions changed the screen
+    // With flicker reduction the screen fades even if the screen buffer didn't change, so it is
+    // always redrawn entirely. Otherwise only the changed regions are redrawn.
+    if (chip8_cpu.is_phosphor_enabled()) {
+        chip8_cpu.handle_screen_dirty_flag();
+        draw_screen();
+    } else {
+        const dirty_rects = chip8_cpu.take_dirty_rects();
+        if (dirty_rects.length > 0) {
+            draw_screen(dirty_rects);
+        }
+    }
+
+    queue_audio_samples();
 
-    // console.log(key_state);
-    chip8_cpu.update_key_state(key_state);
-    if (finished_capture_key) {
-        finished_capture_key = false;
-        chip8_cpu.set_captured_key(captured_key);
+    // A replayed movie stops at its last frame, where the final state is verified
+    if (chip8_cpu.is_playing_movie() && chip8_cpu.is_movie_finished()) {
+        finish_movie_playback();
+        return;
     }
 
-    let cpu_start = performance.now();
+    last_frame_timestamp = timestamp;
+    last_animation_request_id = requestAnimationFrame(render_loop);
+};
 
-    // Check we are not waiting for a key press capture
-    if (!should_capture_key) {
-        let cpu_start = performance.now();
-        const instructions_to_execute = Math.max(Math.round((delta_time / 1000) * CLOCK_RATE_HZ), 1);
-        for (let i = 0; i < instructions_to_execute; i++) {
-            chip8_cpu.step();
+// Draws the screen to the canvas. If `dirty_rects` (flattened x, y, width, height groups in
+// screen pixels) is given, only those regions of the canvas are updated.
+function draw_screen(dirty_rects) {
+    chip8_cpu.render_framebuffer();
 
-            if (chip8_cpu.is_waiting_for_keypress()) {
-                should_capture_key = true;
-                break;
-            }
+    const width = chip8_cpu.get_framebuffer_width();
+    const height = chip8_cpu.get_framebuffer_height();
+    const pixels = new Uint8ClampedArray(wasm.memory.buffer, chip8_cpu.get_framebuffer(),
+        width * height * 4);
+    const image_data = new ImageData(pixels, width, height);
 
-            // Check if the executed instruction changed the screen
-            if (chip8_cpu.handle_screen_dirty_flag()) {
-                const screen_buffer_ptr = chip8_cpu.get_screen_buffer();
-                const screen_buffer = new Uint8Array(wasm.memory.buffer, screen_buffer_ptr, 64 * 32);
+    if (dirty_rects == undefined) {
+        ctx.putImageData(image_data, 0, 0);
+        return;
+    }
 
-                const image_data = ctx.getImageData(0, 0, canvas.width, canvas.height);
-                const data = image_data.data;
+    const scale = width / chip8_cpu.screen_width();
+    for (let i = 0; i < dirty_rects.length; i += 4) {
+        ctx.putImageData(image_data, 0, 0, dirty_rects[i] * scale, dirty_rects[i + 1] * scale,
+            dirty_rects[i + 2] * scale, dirty_rects[i + 3] * scale);
+    }
+}
 
-                let buffer_idx = 0;
-                for (let i = 0; i < data.length; i += 4) {
-                    let pixelColor = (screen_buffer[buffer_idx] > 0) ? 255 : 0;
-                    data[i] = pixelColor; // red
-                    data[i + 1] = pixelColor; // green
-                    data[i + 2] = pixelColor; // blue
+function apply_palette() {
+    // Colour inputs are in "#rrggbb" format
+    chip8_cpu.set_palette_color(0, parseInt(BACKGROUND_COLOR.substr(1), 16));
+    chip8_cpu.set_palette_color(1, parseInt(FOREGROUND_COLOR.substr(1), 16));
+}
 
-                    buffer_idx++;
-                }
-                ctx.putImageData(image_data, 0, 0);
+function apply_flicker_reduction() {
+    if (FLICKER_REDUCTION == "decay") {
+        chip8_cpu.set_phosphor_decay(0.6);
+    } else if (FLICKER_REDUCTION == "or_frames") {
+        chip8_cpu.set_phosphor_or_frames(2);
+    } else {
+        chip8_cpu.disable_phosphor();
+    }
+}
 
-                // According to the reference, draw instruction waited for a v-blank.
-                break;
-            }
-        }
+function queue_audio_samples() {
+    const samples_len = chip8_cpu.get_audio_buffer_len();
+    if (samples_len == 0) {
+        return;
     }
 
-    let cpu_end = performance.now();
-    // console.log(`Took ${cpu_end - cpu_start} ms to execute instructions.`);
+    const samples = new Float32Array(wasm.memory.buffer, chip8_cpu.get_audio_buffer(), samples_len);
+    const audio_buffer = audio_context.createBuffer(1, samples_len, audio_context.sampleRate);
+    audio_buffer.copyToChannel(samples, 0);
+
+    const source = audio_context.createBufferSource();
+    source.buffer = audio_buffer;
+    source.connect(master_gain);
 
-    let should_play_tone = chip8_cpu.should_play_tone();
-    if (should_play_tone && !tone_playing) {
-        oscillator.connect(master_gain);
-        tone_playing = true;
-    } else if (!should_play_tone && tone_playing) {
-        oscillator.disconnect(master_gain);
-        tone_playing = false;
+    // Frames are played back to back, unless we fell behind the audio clock (e.g. the tab was in
+    // the background), in which case we start over with a fresh latency buffer.
+    if (next_audio_time < audio_context.currentTime) {
+        next_audio_time = audio_context.currentTime + AUDIO_LATENCY_SECS;
     }
+    source.start(next_audio_time);
+    next_audio_time += audio_buffer.duration;
+}
 
-    last_frame_timestamp = timestamp;
-    last_animation_request_id = requestAnimationFrame(render_loop);
-};
+function download_bytes(filename, bytes, mime_type) {
+    const url = URL.createObjectURL(new Blob([bytes], { type: mime_type }));
+    const link = document.createElement('a');
+    link.href = url;
+    link.download = filename;
+    link.click();
+    URL.revokeObjectURL(url);
+}
 
-function start_game() {
-    stop_game();
+function stop_gif_recording() {
+    document.getElementById("record_gif").innerText = "Record GIF";
+    if (chip8_cpu != undefined && chip8_cpu.is_recording_gif()) {
+        download_bytes("chip8_recording.gif", chip8_cpu.stop_gif_recording(), "image/gif");
+    }
+}
+
+function stop_audio_recording() {
+    document.getElementById("record_audio").innerText = "Record Audio";
+    if (chip8_cpu != undefined && chip8_cpu.is_recording_audio()) {
+        download_bytes("chip8_audio.wav", chip8_cpu.stop_audio_recording(), "audio/wav");
+    }
+}
+
+function stop_movie_recording() {
+    document.getElementById("record_movie").innerText = "Record Movie";
+    if (chip8_cpu != undefined && chip8_cpu.is_recording_movie()) {
+        download_bytes("chip8_movie.txt", chip8_cpu.stop_movie_recording(), "text/plain");
+    }
+}
 
-    if(!audio_initiated) {
-        oscillator.start();
-        audio_initiated = true;
+function finish_movie_playback() {
+    try {
+        chip8_cpu.verify_movie();
+        alert("Movie replayed successfully, the final state matches the recording.");
+    } catch (err) {
+        alert(err);
     }
+}
+
+// Starts the loaded ROM. If `movie_text` is given, the movie is replayed instead of taking
+// keyboard input.
+function start_game(movie_text) {
+    stop_game();
+
+    // The audio context can only be started after a user gesture
+    audio_context.resume();
 
     // Setup cpu
-    if (loaded_rom_buffer.byteLength == 0) {
-        console.error("Failed to load ROM");
-        ctx.font = "11px Courier New";
-        ctx.fillStyle = "white";
-        ctx.fillText("Failed to", 1, 8);
-        ctx.fillText("load ROM!", 1, 17);
-        return;
+    if (movie_text != undefined) {
+        // The movie sets the quirks and timing it was recorded with
+        try {
+            chip8_cpu = Cpu.with_movie(new Uint8Array(loaded_rom_buffer), movie_text);
+        } catch (err) {
+            chip8_cpu = undefined;
+            alert(err);
+            return;
+        }
+    } else {
+        const load_options = LoadOptions.standard();
+        load_options.load_address = LOAD_OPTIONS.load_address;
+        load_options.entry_point = LOAD_OPTIONS.entry_point;
+        load_options.hires = LOAD_OPTIONS.hires;
+        try {
+            chip8_cpu = Cpu.with_rom_and_options_at(new Uint8Array(loaded_rom_buffer),
+                USE_ORIGINAL_SHIFT, USE_ORIGINAL_MEM_ACC, USE_DISPLAY_WAIT, USE_KEY_WAIT_RELEASE,
+                load_options);
+        } catch (err) {
+            chip8_cpu = undefined;
+            alert(`Failed to load ROM. Error: ${err}`);
+            return;
+        } finally {
+            load_options.free();
+        }
+        chip8_cpu.set_clock_rate(CLOCK_RATE_HZ);
+        chip8_cpu.set_vip_timing(USE_VIP_TIMING);
     }
-    chip8_cpu = Cpu.with_rom_and_options(new Uint8Array(loaded_rom_buffer),
-        USE_ORIGINAL_SHIFT, USE_ORIGINAL_MEM_ACC);
+    for (const warning of chip8_cpu.load_warnings()) {
+        console.warn(warning);
+    }
+    resize_canvas();
+    chip8_cpu.set_keymap(loaded_keymap);
+    apply_palette();
+    apply_flicker_reduction();
+    chip8_cpu.enable_audio(audio_context.sampleRate);
+    chip8_cpu.set_audio_waveform(TONE_WAVEFORM);
 
     last_animation_request_id = requestAnimationFrame(render_loop);
 }
 
+// Sizes the canvas to the screen of the cpu, keeping its displayed width
+function resize_canvas() {
+    canvas.width = chip8_cpu.screen_width();
+    canvas.height = chip8_cpu.screen_height();
+    canvas.style.height = `${canvas.clientWidth * canvas.height / canvas.width}px`;
+}
+
 function stop_game() {
+    stop_audio_recording();
+    stop_gif_recording();
+    stop_movie_recording();
+
     // Stop animation loop
     if(last_animation_request_id != undefined){
         cancelAnimationFrame(last_animation_request_id);
     }
 
     // Clear screen
-    ctx.fillStyle = "black";
+    ctx.fillStyle = BACKGROUND_COLOR;
     ctx.fillRect(0, 0, canvas.width, canvas.height);
 
-    // Reset key state
-    should_capture_key = false;
-    finished_capture_key = false;
-
     // Reset frame timing state
     last_frame_timestamp = undefined;
 }
 
-function set_loaded_rom_buffer(rom_name, rom_buffer){
+function set_loaded_keymap(keymap) {
+    if (loaded_keymap != undefined) {
+        loaded_keymap.free();
+    }
+    loaded_keymap = keymap;
+}
+
+// Builds the keymap of a ROM: the QWERTY layout, with the ROM's own bindings on top
+function build_rom_keymap(rom_bindings) {
+    const keymap = Keymap.qwerty();
+    for (const host_key in rom_bindings) {
+        keymap.bind(host_key, parseInt(rom_bindings[host_key], 16));
+    }
+    return keymap;
+}
+
+// Describes the ROM's own key bindings, e.g. "Left: 4, Right: 6"
+function describe_rom_keymap(rom_bindings) {
+    return Object.keys(rom_bindings).map(host_key => `${host_key}: ${rom_bindings[host_key]}`)
+        .join(", ");
+}
+
+// Loads the bundled ROM database, extended with the entries the user imported
+function load_rom_database() {
+    rom_database = RomDatabase.bundled();
+    const local_entries = localStorage.getItem(LOCAL_ROM_DATABASE_KEY);
+    if (local_entries != null) {
+        try {
+            rom_database.extend_from_json(local_entries);
+        } catch (err) {
+            console.error(err);
+        }
+    }
+}
+
+// Adds the entries of a ROM database file to the local database, which is kept across visits
+function import_rom_database(json) {
+    try {
+        rom_database.extend_from_json(json);
+    } catch (err) {
+        alert(err);
+        return;
+    }
+
+    const local_entries = JSON.parse(localStorage.getItem(LOCAL_ROM_DATABASE_KEY) || "{}");
+    Object.assign(local_entries, JSON.parse(json));
+    localStorage.setItem(LOCAL_ROM_DATABASE_KEY, JSON.stringify(local_entries));
+}
+
+// Applies the settings of an identified ROM, and describes it. Settings the ROM doesn't have are
+// left as they are.
+function apply_quirks(quirks) {
+    USE_ORIGINAL_SHIFT = quirks.original_shift;
+    USE_ORIGINAL_MEM_ACC = quirks.original_mem_acc;
+    USE_DISPLAY_WAIT = quirks.display_wait;
+    USE_KEY_WAIT_RELEASE = quirks.wait_for_release;
+    document.getElementById("original_shift").checked = USE_ORIGINAL_SHIFT;
+    document.getElementById("original_mem_acc").checked = USE_ORIGINAL_MEM_ACC;
+    document.getElementById("display_wait").checked = USE_DISPLAY_WAIT;
+    document.getElementById("key_wait_release").checked = USE_KEY_WAIT_RELEASE;
+}
+
+function apply_load_options(load_options) {
+    LOAD_OPTIONS = load_options;
+    const layout = Object.keys(MEMORY_LAYOUTS).find(name =>
+        JSON.stringify(MEMORY_LAYOUTS[name]) == JSON.stringify(load_options));
+    document.getElementById("memory_layout").value = layout || "custom";
+}
+
+// Returns the memory layout `rom_buffer` most likely needs
+function detect_load_options(rom_buffer) {
+    const detected = LoadOptions.detect(new Uint8Array(rom_buffer));
+    const load_options = {
+        load_address: detected.load_address,
+        entry_point: detected.entry_point,
+        hires: detected.hires,
+    };
+    detected.free();
+    return load_options;
+}
+
+function apply_rom_metadata(metadata) {
+    const rom_bindings = metadata.keymap || {};
+    set_loaded_keymap(build_rom_keymap(rom_bindings));
+
+    if (metadata.quirks != undefined) {
+        apply_quirks(metadata.quirks);
+    }
+
+    if (metadata.load_options != undefined) {
+        apply_load_options(metadata.load_options);
+    }
+
+    if (metadata.speed != undefined) {
+        CLOCK_RATE_HZ = metadata.speed;
+        document.getElementById("clock_rate").value = CLOCK_RATE_HZ;
+    }
+
+    if (metadata.palette != undefined) {
+        if (metadata.palette.length > 0) {
+            BACKGROUND_COLOR = metadata.palette[0];
+            document.getElementById("background_color").value = BACKGROUND_COLOR;
+        }
+        if (metadata.palette.length > 1) {
+            FOREGROUND_COLOR = metadata.palette[1];
+            document.getElementById("foreground_color").value = FOREGROUND_COLOR;
+        }
+    }
+
+    let description = metadata.title;
+    if (metadata.author != undefined) {
+        description += " by " + metadata.author;
+    }
+    if (metadata.platform != "CHIP-8") {
+        description += `\nThis ROM was written for ${metadata.platform}, which is not supported.`;
+    }
+    if (metadata.description != undefined) {
+        description += "\n" + metadata.description;
+    }
+    if (Object.keys(rom_bindings).length > 0) {
+        description += "\nKeyboard: " + describe_rom_keymap(rom_bindings);
+    }
+    document.getElementById("rom_description").innerText = description;
+}
+
+function apply_rom_analysis(analysis) {
+    apply_quirks(analysis.quirks);
+    apply_load_options(analysis.load_options);
+
+    const confidence = Math.round(analysis.confidence * 100);
+    let description = `Unknown ROM, detected as ${analysis.platform}. `
+        + `The advanced settings were set to the detected quirks (confidence ${confidence}%).`;
+    if (analysis.platform != "CHIP-8") {
+        description += `\n${analysis.platform} is not supported.`;
+    }
+    for (const finding of analysis.findings) {
+        description += "\n" + finding;
+    }
+    document.getElementById("rom_description").innerText = description;
+}
+
+// `cartridge_metadata` holds the settings of an Octo cartridge, which are used instead of the
+// settings from the ROM database
+function set_loaded_rom_buffer(rom_name, rom_buffer, cartridge_metadata){
     document.getElementById("rom_filename").innerText = rom_name;
     loaded_rom_buffer = rom_buffer;
     document.getElementById("start_game").disabled = false;
+
+    // ROMs without a known memory layout use the detected one
+    apply_load_options(detect_load_options(rom_buffer));
+
+    if (cartridge_metadata != undefined) {
+        apply_rom_metadata(cartridge_metadata);
+        return;
+    }
+
+    const metadata_json = rom_database.lookup_json(new Uint8Array(rom_buffer));
+    if (metadata_json != undefined) {
+        apply_rom_metadata(JSON.parse(metadata_json));
+    } else {
+        set_loaded_keymap(Keymap.qwerty());
+        const analysis_json = analyze_rom_json(new Uint8Array(rom_buffer), ANALYSIS_TRIAL_FRAMES);
+        apply_rom_analysis(JSON.parse(analysis_json));
+    }
 }
 
 function show_loading_rom() {
     document.getElementById("rom_filename").innerText = "Loading...";
 }
 
+async function load_octo_cartridge(cartridge_file) {
+    let cartridge;
+    try {
+        cartridge = OctoCartridge.parse(new Uint8Array(await cartridge_file.arrayBuffer()));
+    } catch (err) {
+        alert(`Failed to load Octo cartridge. Error: ${err}`);
+        return;
+    }
+
+    stop_game();
+    show_loading_rom();
+    const metadata = JSON.parse(cartridge.metadata_json());
+    set_loaded_rom_buffer(cartridge_file.name, cartridge.rom().buffer, metadata);
+    cartridge.free();
+}
+
+// Applies an IPS or BPS patch to the loaded ROM, and loads the patched ROM instead
+function apply_rom_patch(patch_name, patch_bytes) {
+    let patched_rom;
+    try {
+        patched_rom = apply_patch(new Uint8Array(loaded_rom_buffer), patch_bytes);
+    } catch (err) {
+        alert(`Failed to apply patch. Error: ${err}`);
+        return;
+    }
+
+    const rom_name = document.getElementById("rom_filename").innerText;
+    stop_game();
+    show_loading_rom();
+    set_loaded_rom_buffer(`${rom_name} + ${patch_name}`, patched_rom.buffer);
+}
+
 function handle_rom_upload(files){
     if(!files || files.length == 0)
         return;
     
     let rom_file = files[0];
-    if(rom_file.size < 4096-512) { // Memory size - reserved memory
-        const file_reader = new FileReader();
-        file_reader.onload = () => {
-            stop_game();
-            show_loading_rom();
-            set_loaded_rom_buffer(rom_file.name, file_reader.result);
-        };
-        file_reader.onerror = (err) => {
-            alert(`Failed to read ROM file. Error: ${err.message}`);
-        } 
-        file_reader.readAsArrayBuffer(rom_file);
-    }else{
-        alert("ROM file too large!");
+    if (rom_file.name.toLowerCase().endsWith(".gif")) {
+        load_octo_cartridge(rom_file);
+        return;
     }
-}
 
-function setup_event_listeners() {
-    const key_map = {
-        '1': 0x1, '2': 0x2, '3': 0x3, '4': 0xC,
-        'Q': 0x4, 'W': 0x5, 'E': 0x6, 'R': 0xD,
-        'A': 0x7, 'S': 0x8, 'D': 0x9, 'F': 0xE,
-        'Z': 0xA, 'X': 0x0, 'C': 0xB, 'V': 0xF,
+    const file_reader = new FileReader();
+    file_reader.onload = () => {
+        let rom;
+        try {
+            rom = decode_uploaded_rom(new Uint8Array(file_reader.result));
+        } catch (err) {
+            alert(`Failed to load ROM file. Error: ${err}`);
+            return;
+        }
+        if (rom == undefined) {
+            return;
+        }
+
+        stop_game();
+        show_loading_rom();
+        set_loaded_rom_buffer(rom_file.name, rom.buffer);
     };
+    file_reader.onerror = (err) => {
+        alert(`Failed to read ROM file. Error: ${err.message}`);
+    } 
+    file_reader.readAsArrayBuffer(rom_file);
+}
+
+// Decodes the ROM bytes of an uploaded file, which can also be a zip archive, Intel HEX or hex
+// text. If a zip archive holds several ROMs, the user chooses one of them. Returns undefined if
+// the user cancelled.
+function decode_uploaded_rom(file_bytes) {
+    let entry_name;
+    const is_zip = file_bytes[0] == 0x50 && file_bytes[1] == 0x4B; // "PK"
+    if (is_zip) {
+        const entries = archive_rom_entries(file_bytes);
+        if (entries.length > 1) {
+            entry_name = prompt("The archive has several ROMs, enter the one to load:\n"
+                + entries.join("\n"), entries[0]);
+            if (entry_name == null) {
+                return undefined;
+            }
+        }
+    }
+
+    return decode_rom_file(file_bytes, entry_name);
+}
 
-    const handle_keydown = (key_digit) => {
+function setup_event_listeners() {
+    // Key events are sent to the cpu with their timestamps, so they are applied at the right time
+    // within the next frame.
+    const handle_keydown = (key_digit, timestamp) => {
+        if (!key_state[key_digit] && chip8_cpu != undefined) {
+            chip8_cpu.key_down(key_digit, timestamp);
+        }
         key_state[key_digit] = true;
         document.getElementById("key_"+key_digit.toString(16).toUpperCase()).classList.add("key_button_pressed");
     };
-    const handle_keyup = (key_digit) => {
+    const handle_keyup = (key_digit, timestamp) => {
+        if (key_state[key_digit] && chip8_cpu != undefined) {
+            chip8_cpu.key_up(key_digit, timestamp);
+        }
         key_state[key_digit] = false;
         document.getElementById("key_"+key_digit.toString(16).toUpperCase()).classList.remove("key_button_pressed");
-
-        if (should_capture_key) {
-            should_capture_key = false;
-            finished_capture_key = true;
-            captured_key = key_digit;
-        }
     };
 
+    // Keyboard keys are mapped to hex keys by the cpu, using the keymap of the loaded ROM. A hex
+    // key bound to several keyboard keys stays pressed while any of them is held.
     document.addEventListener("keydown", ev => {
-        let key = get_key_from_event(ev);
+        const key = get_key_from_event(ev);
+        const key_digit = loaded_keymap.hex_key(key);
+        if (key_digit == undefined) {
+            return;
+        }
 
-        if (key in key_map) {
-            let key_digit = key_map[key];
-            handle_keydown(key_digit);
+        // Bound keys (e.g. the arrow keys) shouldn't scroll the page
+        ev.preventDefault();
+        if (chip8_cpu != undefined) {
+            chip8_cpu.host_key_down(key, ev.timeStamp);
         }
+        document.getElementById("key_"+key_digit.toString(16).toUpperCase()).classList.add("key_button_pressed");
     });
 
     document.addEventListener("keyup", ev => {
-        let key = get_key_from_event(ev);
+        const key = get_key_from_event(ev);
+        const key_digit = loaded_keymap.hex_key(key);
+        if (key_digit == undefined) {
+            return;
+        }
 
-        if (key in key_map) {
-            let key_digit = key_map[key];
-            handle_keyup(key_digit)
+        if (chip8_cpu != undefined) {
+            chip8_cpu.host_key_up(key, ev.timeStamp);
         }
+        document.getElementById("key_"+key_digit.toString(16).toUpperCase()).classList.remove("key_button_pressed");
     });
 
     for(let i = 0; i < 16; i++){
         document.getElementById("key_"+i.toString(16).toUpperCase()).addEventListener("mousedown", ev => {
-            handle_keydown(i);
+            handle_keydown(i, ev.timeStamp);
         });
         document.getElementById("key_"+i.toString(16).toUpperCase()).addEventListener("mouseup", ev => {
-            handle_keyup(i);
+            handle_keyup(i, ev.timeStamp);
         });
         document.getElementById("key_"+i.toString(16).toUpperCase()).addEventListener("mouseleave", ev => {
             if(key_state[i])
-                handle_keyup(i);
+                handle_keyup(i, ev.timeStamp);
         });
     }
 
@@ -276,14 +627,104 @@ function setup_event_listeners() {
         }
     });
 
+    document.getElementById("screenshot").addEventListener("click", () => {
+        if (chip8_cpu != undefined) {
+            download_bytes("chip8_screenshot.png", chip8_cpu.screenshot_png(SCREENSHOT_SCALE),
+                "image/png");
+        }
+    });
+
+    document.getElementById("record_gif").addEventListener("click", ev => {
+        if (chip8_cpu == undefined) {
+            return;
+        }
+
+        if (chip8_cpu.is_recording_gif()) {
+            stop_gif_recording();
+        } else {
+            chip8_cpu.start_gif_recording(SCREENSHOT_SCALE);
+            ev.target.innerText = "Save GIF";
+        }
+    });
+
+    document.getElementById("record_audio").addEventListener("click", ev => {
+        if (chip8_cpu == undefined) {
+            return;
+        }
+
+        if (chip8_cpu.is_recording_audio()) {
+            stop_audio_recording();
+        } else {
+            chip8_cpu.start_audio_recording();
+            ev.target.innerText = "Save Recording";
+        }
+    });
+
+    // A movie is recorded from the start of the game, so recording restarts the game
+    document.getElementById("record_movie").addEventListener("click", ev => {
+        if (loaded_rom_buffer == undefined) {
+            return;
+        }
+
+        if (chip8_cpu != undefined && chip8_cpu.is_recording_movie()) {
+            stop_movie_recording();
+        } else {
+            start_game();
+            if (chip8_cpu != undefined) {
+                chip8_cpu.start_movie_recording();
+                ev.target.innerText = "Save Movie";
+            }
+        }
+    });
+
+    const movie_picker = document.getElementById("movie_picker");
+    document.getElementById("play_movie").addEventListener("click", () => {
+        if (loaded_rom_buffer == undefined) {
+            alert("Load the ROM the movie was recorded with first.");
+            return;
+        }
+        movie_picker.click();
+    });
+    movie_picker.addEventListener("change", async ev => {
+        if (ev.target.files.length > 0) {
+            start_game(await ev.target.files[0].text());
+        }
+        movie_picker.value = "";
+    });
+
+    const patch_picker = document.getElementById("patch_picker");
+    document.getElementById("apply_patch").addEventListener("click", () => {
+        if (loaded_rom_buffer == undefined) {
+            alert("Load the ROM the patch was made for first.");
+            return;
+        }
+        patch_picker.click();
+    });
+    patch_picker.addEventListener("change", async ev => {
+        if (ev.target.files.length > 0) {
+            apply_rom_patch(ev.target.files[0].name,
+                new Uint8Array(await ev.target.files[0].arrayBuffer()));
+        }
+        patch_picker.value = "";
+    });
+
     document.getElementById("load_select_rom").addEventListener("click", async () => {
         const rom_select = document.getElementById("rom_select");
         if(rom_select.value.endsWith(".rom")){
             show_loading_rom();
             set_loaded_rom_buffer(rom_select.value, await fetch('./static/roms/'+rom_select.value).then(resp => resp.arrayBuffer()));
+        }
+    });
 
-            document.getElementById("rom_description").innerText = rom_descriptions[rom_select.selectedIndex];
+    const rom_db_picker = document.getElementById("rom_db_picker");
+    document.getElementById("import_rom_db").addEventListener("click", () => {
+        rom_db_picker.click();
+    });
+    rom_db_picker.addEventListener("change", async ev => {
+        if (ev.target.files.length > 0) {
+            import_rom_database(await ev.target.files[0].text());
         }
+        rom_db_picker.value = "";
     });
 
     const file_picker = document.getElementById("file_picker");
@@ -324,7 +765,7 @@ function setup_event_listeners() {
         for(let i = 0; i < 16; i++){
             let key_name = i.toString(16).toUpperCase();
             document.getElementById("key_"+key_name).innerText =
-                Object.keys(key_map).find(key => key_map[key] == i);
+                loaded_keymap.host_keys(i)[0] || key_name;
         }
     });
     keyboard_label.addEventListener("mouseleave", () => {
@@ -336,6 +777,11 @@ function setup_event_listeners() {
 
     document.getElementById("clock_rate").addEventListener("input", ev => {
         CLOCK_RATE_HZ = parseInt(ev.target.value);
+        // Movies are replayed with the clock rate they were recorded with
+        if (chip8_cpu != undefined && !chip8_cpu.is_recording_movie()
+            && !chip8_cpu.is_playing_movie()) {
+            chip8_cpu.set_clock_rate(CLOCK_RATE_HZ);
+        }
     });
 
     document.getElementById("advanced_settings_title").addEventListener("click", () => {
@@ -358,6 +804,55 @@ function setup_event_listeners() {
         stop_game();
         USE_ORIGINAL_MEM_ACC = ev.target.checked;
     });
+    document.getElementById("display_wait").addEventListener("change", ev => {
+        stop_game();
+        USE_DISPLAY_WAIT = ev.target.checked;
+    });
+    document.getElementById("key_wait_release").addEventListener("change", ev => {
+        stop_game();
+        USE_KEY_WAIT_RELEASE = ev.target.checked;
+    });
+    document.getElementById("background_color").addEventListener("input", ev => {
+        BACKGROUND_COLOR = ev.target.value;
+        if (chip8_cpu != undefined) {
+            apply_palette();
+            draw_screen();
+        }
+    });
+    document.getElementById("foreground_color").addEventListener("input", ev => {
+        FOREGROUND_COLOR = ev.target.value;
+        if (chip8_cpu != undefined) {
+            apply_palette();
+            draw_screen();
+        }
+    });
+
+    document.getElementById("flicker_reduction").addEventListener("change", ev => {
+        FLICKER_REDUCTION = ev.target.value;
+        if (chip8_cpu != undefined) {
+            apply_flicker_reduction();
+        }
+    });
+
+    document.getElementById("memory_layout").addEventListener("change", ev => {
+        LOAD_OPTIONS = MEMORY_LAYOUTS[ev.target.value];
+    });
+
+    document.getElementById("tone_waveform").addEventListener("change", ev => {
+        TONE_WAVEFORM = Waveform[ev.target.value];
+        if (chip8_cpu != undefined) {
+            chip8_cpu.set_audio_waveform(TONE_WAVEFORM);
+        }
+    });
+    document.getElementById("vip_timing").addEventListener("change", ev => {
+        USE_VIP_TIMING = ev.target.checked;
+        document.getElementById("clock_rate").disabled = USE_VIP_TIMING;
+        // Movies are replayed with the timing they were recorded with
+        if (chip8_cpu != undefined && !chip8_cpu.is_recording_movie()
+            && !chip8_cpu.is_playing_movie()) {
+            chip8_cpu.set_vip_timing(USE_VIP_TIMING);
+        }
+    });
 }
 
 function get_key_from_event(ev) {
@@ -366,8 +861,11 @@ function get_key_from_event(ev) {
         key = ev.code.substr(5);
     }else if(ev.code.startsWith("Key")){
         key = ev.code.substr(3);
+    }else if(ev.code.startsWith("Arrow")){
+        key = ev.code.substr(5);
     }else{
-        key = ev.key;
+        key = ev.code;
     }
-    return key.toUpperCase();
+    // Keymaps use the same key names, e.g. "Q", "Up" or "Space"
+    return key;
 }
\ No newline at end of file